@@ -0,0 +1,97 @@
+//! The [`Hasher`] and [`HashAlgorithm`] traits and the registry of custom algorithms.
+
+use crate::hash::{algo_name, BUILTIN_ALGOS};
+use anyhow::{anyhow, Result};
+use std::sync::RwLock;
+
+/// Incremental digest of one file. Besides the built-in algorithms, custom ones implement it
+/// (see [`HashAlgorithm`]).
+pub trait Hasher: Send {
+    fn update(&mut self, data: &[u8]);
+    /// The digest as lowercase hex.
+    fn finalize_hex(self: Box<Self>) -> String;
+}
+
+/// A custom hash algorithm, selectable with `--algo <name>` once registered with
+/// [`register_algorithm`].
+pub trait HashAlgorithm: Send + Sync {
+    /// Name used with `--algo` and recorded in state files: lowercase ASCII letters, digits
+    /// and `-`, different from the built-in names.
+    fn name(&self) -> &str;
+    /// Length of the hex digests of [`Hasher::finalize_hex`].
+    fn digest_hex_len(&self) -> usize;
+    /// Multihash code of the digests, needed for `--digest-encoding multihash`.
+    fn multihash_code(&self) -> Option<u64> {
+        None
+    }
+    fn new_hasher(&self) -> Box<dyn Hasher>;
+}
+
+static CUSTOM_ALGORITHMS: RwLock<Vec<&'static dyn HashAlgorithm>> = RwLock::new(Vec::new());
+
+/// Makes `algorithm` available to [`run`](crate::run). Fails if its name is invalid or
+/// already taken.
+pub fn register_algorithm(algorithm: impl HashAlgorithm + 'static) -> Result<()> {
+    let name = algorithm.name();
+    let valid = !name.is_empty()
+        && name.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-');
+    if !valid {
+        return Err(anyhow!("Invalid algorithm name {name:?}: use lowercase letters, digits and `-`"));
+    }
+    // Checked under the write lock, so that two threads cannot register the same name.
+    let mut custom = CUSTOM_ALGORITHMS.write().unwrap_or_else(|e| e.into_inner());
+    let taken = BUILTIN_ALGOS.into_iter().any(|a| algo_name(a).eq_ignore_ascii_case(name))
+        || custom.iter().any(|a| a.name().eq_ignore_ascii_case(name));
+    if taken {
+        return Err(anyhow!("An algorithm called {name:?} is already registered"));
+    }
+    custom.push(Box::leak(Box::new(algorithm)));
+    Ok(())
+}
+
+/// The algorithms registered so far, in registration order.
+pub(crate) fn registered() -> Vec<&'static dyn HashAlgorithm> {
+    CUSTOM_ALGORITHMS.read().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Digest of the number of bytes hashed, as 16 hex digits.
+    #[derive(Default)]
+    struct ByteCount(u64);
+
+    impl Hasher for ByteCount {
+        fn update(&mut self, data: &[u8]) {
+            self.0 += data.len() as u64;
+        }
+        fn finalize_hex(self: Box<Self>) -> String {
+            format!("{:016x}", self.0)
+        }
+    }
+
+    struct Named(&'static str);
+
+    impl HashAlgorithm for Named {
+        fn name(&self) -> &str {
+            self.0
+        }
+        fn digest_hex_len(&self) -> usize {
+            16
+        }
+        fn new_hasher(&self) -> Box<dyn Hasher> {
+            Box::<ByteCount>::default()
+        }
+    }
+
+    #[test]
+    fn register_rejects_invalid_and_taken_names() {
+        assert!(register_algorithm(Named("")).is_err());
+        assert!(register_algorithm(Named("Upper")).is_err());
+        assert!(register_algorithm(Named("blake3")).is_err());
+        register_algorithm(Named("test-hash")).unwrap();
+        assert!(register_algorithm(Named("test-hash")).is_err());
+        assert_eq!(registered().iter().filter(|a| a.name() == "test-hash").count(), 1);
+    }
+}
@@ -0,0 +1,255 @@
+//! Writing the synced changes into a tar (`--target x.tar[.zst]`) or zip (`--archive-zip`) archive.
+
+use crate::copy::temp_path;
+use crate::diff::Change;
+use crate::scan::{file_mode, file_mtime_ns};
+use crate::state::{Entry, EntryKind};
+use crate::sync::{create_parent, OpSource, SyncOp};
+use crate::timestamps::civil_from_days;
+use anyhow::{anyhow, Context, Result};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::Path;
+
+/// Packs the files added or updated by `changes` into a new zip archive at `path` (deflated,
+/// with their mode and modification time). With `dry_run`, they are only listed.
+pub(crate) fn write_zip(
+    path: &Path,
+    changes: &[Change],
+    new: &HashMap<String, Entry>,
+    source: &OpSource,
+    dry_run: bool,
+) -> Result<()> {
+    let files: BTreeSet<&str> = changes
+        .iter()
+        .filter(|ch| matches!(ch, Change::Added(_) | Change::Updated(_) | Change::SizeAnomaly(_)))
+        .map(|ch| ch.path())
+        .filter(|rel| new.get(*rel).is_some_and(|e| e.kind == EntryKind::File))
+        .collect();
+    if dry_run {
+        for rel in &files {
+            eprintln!("Would add {rel} to {path:?}");
+        }
+        return Ok(());
+    }
+    let file = File::create(path).with_context(|| format!("Failed to create zip archive: {path:?}"))?;
+    let mut zip = zip::ZipWriter::new(BufWriter::new(file));
+    for rel in &files {
+        let src = source.path(rel)?;
+        let mut input = File::open(&src).with_context(|| format!("Failed to open {src:?}"))?;
+        let meta = input.metadata().with_context(|| format!("Failed to read metadata for {src:?}"))?;
+        let mut options = zip::write::SimpleFileOptions::default()
+            .compression_method(zip::CompressionMethod::Deflated)
+            .large_file(meta.len() >= u32::MAX as u64);
+        if let Some(mode) = file_mode(&meta) {
+            options = options.unix_permissions(mode);
+        }
+        if let Some(time) = file_mtime_ns(&meta).and_then(|ns| zip_time(ns / 1_000_000_000)) {
+            options = options.last_modified_time(time);
+        }
+        zip.start_file(*rel, options)?;
+        io::copy(&mut input, &mut zip).with_context(|| format!("Failed to add {src:?} to {path:?}"))?;
+    }
+    zip.finish()?
+        .into_inner()
+        .map_err(|e| e.into_error())
+        .with_context(|| format!("Failed to write zip archive: {path:?}"))?;
+    eprintln!("Packed {} changed files into {path:?}", files.len());
+    Ok(())
+}
+
+/// Seconds since the Unix epoch as a zip timestamp (UTC), if it is in the range zip allows.
+fn zip_time(secs: u64) -> Option<zip::DateTime> {
+    let (year, month, day) = civil_from_days(secs / 86_400);
+    let rem = secs % 86_400;
+    let narrow = |v: u64| u8::try_from(v).ok();
+    zip::DateTime::from_date_and_time(
+        u16::try_from(year).ok()?,
+        narrow(month)?,
+        narrow(day)?,
+        narrow(rem / 3600)?,
+        narrow(rem % 3600 / 60)?,
+        narrow(rem % 60)?,
+    )
+    .ok()
+}
+
+/// Compression of a tar archive given as `--target`, told by its name.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum TarFormat {
+    Plain,
+    Zstd,
+}
+
+impl TarFormat {
+    pub(crate) fn of(path: &Path) -> Option<TarFormat> {
+        let name = path.file_name()?.to_string_lossy().to_ascii_lowercase();
+        if name.ends_with(".tar") {
+            Some(TarFormat::Plain)
+        } else if name.ends_with(".tar.zst") || name.ends_with(".tzst") {
+            Some(TarFormat::Zstd)
+        } else {
+            None
+        }
+    }
+}
+
+/// Writes the files, links and directories that `ops` add or change into the tar archive
+/// `archive`, with their paths relative to the source root. An existing plain archive is
+/// appended to; an existing compressed one is only replaced with `replace`. Either way the
+/// archive is written next to it and renamed over it once complete. Files whose mode, owner
+/// or attributes changed are stored whole; deletions cannot be stored, and are only counted.
+pub(crate) fn write_tar(
+    archive: &Path,
+    format: TarFormat,
+    ops: &[SyncOp],
+    source: &OpSource,
+    dry_run: bool,
+    replace: bool,
+) -> Result<()> {
+    let exists = archive.exists();
+    if exists && format == TarFormat::Zstd && !replace {
+        return Err(anyhow!(
+            "{archive:?} exists and a compressed archive cannot be appended to (use --force to replace it)"
+        ));
+    }
+    let mut members: BTreeMap<&str, &SyncOp> = BTreeMap::new();
+    for op in ops {
+        if !op.is_deletion() {
+            // A copy comes first, so it is what stands for the other operations on its path.
+            members.entry(op.path()).or_insert(op);
+        }
+    }
+    let deleted = ops.iter().filter(|op| op.is_deletion()).count();
+    if deleted > 0 {
+        eprintln!(
+            "Notice: {deleted} paths deleted in the source are not in {archive:?} (a tar archive cannot record deletions; see --emit-patch)"
+        );
+    }
+    let kept = match format {
+        TarFormat::Plain if exists => Some(tar_data_len(archive)?),
+        _ => None,
+    };
+    if dry_run {
+        for path in members.keys() {
+            eprintln!("Would add {path} to {archive:?}");
+        }
+        return Ok(());
+    }
+    create_parent(archive)?;
+    let tmp = temp_path(archive);
+    let written = File::create(&tmp)
+        .with_context(|| format!("Failed to create archive: {tmp:?}"))
+        .and_then(|mut file| {
+            // The members already there, without the end-of-archive blocks that follow them.
+            if let Some(len) = kept {
+                let mut existing = File::open(archive)?.take(len);
+                io::copy(&mut existing, &mut file).with_context(|| format!("Failed to copy {archive:?}"))?;
+            }
+            Ok(file)
+        })
+        .and_then(|file| match format {
+            TarFormat::Plain => {
+                append_tar_members(BufWriter::new(file), &members, source)?.into_inner().map_err(|e| e.into_error().into())
+            }
+            TarFormat::Zstd => {
+                let encoder = zstd::Encoder::new(BufWriter::new(file), 0)?;
+                append_tar_members(encoder, &members, source)?.finish()?.into_inner().map_err(|e| e.into_error().into())
+            }
+        })
+        .and_then(|file| file.sync_all().context("Failed to flush the archive"));
+    if let Err(err) = written {
+        let _ = fs::remove_file(&tmp);
+        return Err(err.context(format!("Failed to write archive: {archive:?}")));
+    }
+    fs::rename(&tmp, archive).with_context(|| format!("Failed to move {tmp:?} to {archive:?}"))?;
+    eprintln!("Added {} entries to {archive:?}", members.len());
+    Ok(())
+}
+
+/// The length of the members of the tar archive at `path`, i.e. where the blocks of zeros
+/// that end it start.
+fn tar_data_len(path: &Path) -> Result<u64> {
+    let file = File::open(path).with_context(|| format!("Failed to open archive: {path:?}"))?;
+    let mut archive = tar::Archive::new(file);
+    let mut len = 0;
+    for entry in archive.entries().with_context(|| format!("Failed to read archive: {path:?}"))? {
+        let entry = entry.with_context(|| format!("Failed to read archive: {path:?}"))?;
+        len = entry.raw_file_position() + entry.size().div_ceil(512) * 512;
+    }
+    Ok(len)
+}
+
+fn append_tar_members<W: Write>(out: W, members: &BTreeMap<&str, &SyncOp>, source: &OpSource) -> Result<W> {
+    let mut tar = tar::Builder::new(out);
+    tar.follow_symlinks(false);
+    // Hard links last, after the files they are extracted as links to.
+    let (links, others): (Vec<_>, Vec<_>) = members.iter().partition(|(_, op)| matches!(op, SyncOp::Link { .. }));
+    for (&path, op) in others.into_iter().chain(links) {
+        let src = source.path(path)?;
+        match op {
+            SyncOp::Mkdir { .. } => tar.append_dir(path, &src)?,
+            // Hard links to a file that is not in the archive get their own copy of it.
+            SyncOp::Link { to, .. } if members.contains_key(to.as_str()) => {
+                let mut header = tar::Header::new_gnu();
+                header.set_metadata(&fs::metadata(&src).with_context(|| format!("Failed to read metadata for {src:?}"))?);
+                header.set_entry_type(tar::EntryType::Link);
+                header.set_size(0);
+                tar.append_link(&mut header, path, to)?;
+            }
+            _ => tar.append_path_with_name(&src, path)?,
+        }
+    }
+    Ok(tar.into_inner()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    fn copy_op(path: &str) -> SyncOp {
+        SyncOp::Copy { path: path.to_string(), size: 0, hash: String::new(), partial: None }
+    }
+
+    fn member_names(archive: &Path) -> Vec<String> {
+        let mut tar = tar::Archive::new(File::open(archive).unwrap());
+        let entries = tar.entries().unwrap();
+        entries.map(|e| e.unwrap().path().unwrap().to_string_lossy().into_owned()).collect()
+    }
+
+    #[test]
+    fn tar_target_is_appended_to() {
+        let dir = TestDir::new();
+        dir.write("src/a", "first\n");
+        let root = dir.path().join("src");
+        let source = OpSource { root: &root, disk_paths: &HashMap::new(), verify: None };
+        let archive = dir.path().join("delta.tar");
+
+        write_tar(&archive, TarFormat::Plain, &[copy_op("a")], &source, false, false).unwrap();
+        dir.write("src/a", "second\n");
+        dir.write("src/b", "new\n");
+        write_tar(&archive, TarFormat::Plain, &[copy_op("a"), copy_op("b")], &source, false, false).unwrap();
+
+        assert_eq!(member_names(&archive), ["a", "a", "b"]);
+        let mut tar = tar::Archive::new(File::open(&archive).unwrap());
+        let mut first = String::new();
+        tar.entries().unwrap().next().unwrap().unwrap().read_to_string(&mut first).unwrap();
+        assert_eq!(first, "first\n", "the earlier members are kept as they were");
+    }
+
+    #[test]
+    fn compressed_tar_target_is_only_replaced_with_force() {
+        let dir = TestDir::new();
+        dir.write("src/a", "a\n");
+        let root = dir.path().join("src");
+        let source = OpSource { root: &root, disk_paths: &HashMap::new(), verify: None };
+        let archive = dir.write("delta.tar.zst", "previous");
+
+        assert!(write_tar(&archive, TarFormat::Zstd, &[copy_op("a")], &source, false, false).is_err());
+        assert_eq!(fs::read_to_string(&archive).unwrap(), "previous");
+        write_tar(&archive, TarFormat::Zstd, &[copy_op("a")], &source, false, true).unwrap();
+        assert_ne!(fs::read(&archive).unwrap(), b"previous");
+    }
+}
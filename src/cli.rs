@@ -0,0 +1,1025 @@
+//! Command line options, [`run`] and the default mode: scan directories and diff them against the previous state.
+
+use crate::archive::{write_tar, write_zip, TarFormat};
+use crate::commands::apply::{run_apply, ApplyArgs};
+use crate::commands::dedupe::{run_dedupe, DedupeArgs};
+use crate::commands::diff::{run_diff, DiffArgs};
+use crate::commands::diff_dirs::{run_diff_dirs, DiffDirsArgs};
+use crate::commands::diff_states::{run_diff_states, DiffStatesArgs};
+use crate::commands::dupes::{run_dupes, DupesArgs};
+use crate::commands::import::{run_import, ImportArgs};
+use crate::commands::inspect::{run_inspect, InspectArgs};
+use crate::commands::merge::{run_merge, MergeArgs};
+use crate::commands::migrate::{run_migrate, MigrateArgs};
+use crate::commands::prune::{run_prune, PruneArgs};
+use crate::diff::{
+    add_similarity_digests, check_change_limits, check_conflicts, diff_maps, filter_changes,
+    fold_case_renames, fold_v1_backslashes, report_case_renames, ChangeKind,
+};
+use crate::hash::{
+    algo_name, default_buffer_size, hash_key_id, load_hash_key, Algo, IoEngine, RateLimit,
+    ReadOptions, Sampling,
+};
+use crate::manifest::{write_manifest, write_mtree};
+use crate::output::{
+    notify_changes, print_changes, print_chunk_changes, print_grouped_changes, print_junit,
+    print_porcelain, print_similar_files, print_top_changes, OutputFormat,
+};
+use crate::patch::{write_patch, PatchHeader, PATCH_VERSION};
+use crate::scan::{
+    hash_entries, hostname, reconcile_algorithms, report_file_errors, HashCache, ScanOptions,
+};
+use crate::snapshot::{validate_snapshot_label, write_snapshot};
+use crate::state::{
+    acquire_run_lock, is_stdio, load_signing_key, load_verifying_key, read_state_file, sign_state,
+    tree_hashes, verify_state, write_state_file, DigestEncoding, State, StateFormat, StateHeader,
+    STATE_VERSION,
+};
+use crate::sync::{
+    apply_ops, backup_options, link_unchanged, mirror_ops, quarantine_dir, rename_in_target,
+    sync_ops, Journal, OpSource, Preserve, SyncOp, SyncOptions, TRASH_DIR,
+};
+use crate::timestamps::{parse_duration, parse_time_spec, unix_now};
+use crate::walk::{
+    build_globset, build_prune_set, build_regex_set, collect_files, common_root, empty_dir_entries,
+    git_tracked_files, link_entries, listed_files, normalize_entries, path_to_rel_unix,
+    read_file_list, read_pattern_file, special_entries, PathForm, SpecialFiles, TimeWindow,
+    WalkOptions,
+};
+use anyhow::{anyhow, Context, Result};
+use clap::builder::{PossibleValue, PossibleValuesParser, TypedValueParser};
+use clap::{ArgAction, Parser, Subcommand};
+use std::collections::HashSet;
+use std::fs::{self};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+/// Value parser of `--algo`: the built-in and registered algorithms.
+pub(crate) fn algo_parser() -> impl TypedValueParser<Value = Algo> {
+    let values = Algo::all().into_iter().map(|a| PossibleValue::new(algo_name(a)).help(a.help()));
+    PossibleValuesParser::new(values)
+        .map(|name| Algo::from_name(&name).expect("--algo only accepts known algorithms"))
+}
+
+#[derive(Parser, Debug)]
+#[command(
+    about = "Indexes a directory with file hashes and prints diff against a previous state file",
+    version,
+    disable_help_subcommand = true,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[arg(required = true)]
+    state_file: Option<PathBuf>,
+    /// Directories to index; several are indexed into one state, below their common parent
+    #[arg(required = true, value_name = "DIR")]
+    dirs: Vec<PathBuf>,
+
+    #[arg(short = 'x', long = "exclude")]
+    excludes: Vec<String>,
+
+    /// Exclude files and directories whose relative path (with `/` separators) matches a regex
+    #[arg(long = "exclude-regex", value_name = "REGEX")]
+    exclude_regex: Vec<String>,
+
+    /// Only index files whose relative path matches one of these regexes (like `--include`)
+    #[arg(long = "include-regex", value_name = "REGEX")]
+    include_regex: Vec<String>,
+
+    /// Match exclude and include patterns and regexes case-insensitively
+    #[arg(long = "ignore-case", action = ArgAction::SetTrue)]
+    ignore_case: bool,
+
+    /// Read exclude patterns from a file, one per line (`#` starts a comment line)
+    #[arg(long = "exclude-from", value_name = "FILE")]
+    exclude_from: Vec<PathBuf>,
+
+    /// Skip files and directories ignored by `.gitignore` files and `.git/info/exclude`
+    #[arg(long = "respect-gitignore", action = ArgAction::SetTrue)]
+    respect_gitignore: bool,
+
+    /// Do not descend more than this many directory levels below DIR (1: only its own files)
+    #[arg(long = "max-depth", value_name = "N")]
+    max_depth: Option<usize>,
+
+    /// Do not enter directories on other file systems (mount points below DIR)
+    #[arg(long = "one-file-system", action = ArgAction::SetTrue)]
+    one_file_system: bool,
+
+    /// Skip hidden files and directories (names starting with `.`)
+    #[arg(long = "no-hidden", action = ArgAction::SetTrue)]
+    no_hidden: bool,
+
+    /// Only index files tracked by git (listed by `git ls-files` in DIR)
+    #[arg(long = "git-tracked", action = ArgAction::SetTrue)]
+    git_tracked: bool,
+
+    /// Do not read `.fhignore` files
+    #[arg(long = "no-fhignore", action = ArgAction::SetTrue)]
+    no_fhignore: bool,
+
+    /// Unicode normalization of the recorded paths, so names from macOS (NFD) and other systems
+    /// (NFC) compare equal
+    #[arg(long = "normalize-paths", value_enum, default_value_t = PathForm::Nfc)]
+    normalize_paths: PathForm,
+
+    /// Compare paths case-insensitively, so a file renamed by case only is the same entry
+    #[arg(long = "case-insensitive-paths", action = ArgAction::SetTrue)]
+    case_insensitive_paths: bool,
+
+    /// What to do with sockets, FIFOs and device nodes
+    #[arg(long = "special-files", value_enum, default_value_t = SpecialFiles::Warn)]
+    special_files: SpecialFiles,
+
+    /// Also index directories tagged as caches with a `CACHEDIR.TAG` file
+    #[arg(long = "no-cachedir-tag", action = ArgAction::SetTrue)]
+    no_cachedir_tag: bool,
+
+    /// Only hash files modified after this time: a duration ago (`7d`, `12h`) or an RFC 3339
+    /// timestamp. Other files keep their previous entry
+    #[arg(long = "newer-than", value_name = "TIME", value_parser = parse_time_spec)]
+    newer_than: Option<SystemTime>,
+
+    /// Only hash files modified before this time (same forms as `--newer-than`)
+    #[arg(long = "older-than", value_name = "TIME", value_parser = parse_time_spec)]
+    older_than: Option<SystemTime>,
+
+    /// Only hash the files listed in this file (`-` for stdin), one path per line relative to
+    /// DIR, instead of walking it. Other files keep their previous entry
+    #[arg(long = "files-from", value_name = "FILE")]
+    files_from: Option<PathBuf>,
+
+    /// Only index files matching one of these glob patterns (even if also excluded, unless
+    /// they are below an excluded directory, which is never entered)
+    #[arg(short = 'i', long = "include", value_name = "PATTERN")]
+    includes: Vec<String>,
+
+    /// Hash algorithm. Several comma-separated ones (e.g. `blake3,sha256`) are computed in one
+    /// read and all stored; the first one is used to detect changes
+    #[arg(long = "algo", value_parser = algo_parser(), value_delimiter = ',', default_value = "blake3")]
+    algo: Vec<Algo>,
+
+    /// State file format to write (default: keep the format of the existing state, else text)
+    #[arg(long = "state-format", value_enum)]
+    state_format: Option<StateFormat>,
+
+    /// Digest encoding in the written state (default: keep the existing state's, else hex)
+    #[arg(long = "digest-encoding", value_enum)]
+    digest_encoding: Option<DigestEncoding>,
+
+    /// Hash with keyed BLAKE3 using the key material in this file (or $FAST_HASH_INDEX_KEY)
+    #[arg(long = "hash-key", value_name = "FILE")]
+    hash_key: Option<PathBuf>,
+
+    /// Sign the written state with this ed25519 private key (PKCS#8 PEM, or raw/hex 32-byte seed)
+    #[arg(long = "sign-key", value_name = "FILE")]
+    sign_key: Option<PathBuf>,
+
+    /// Refuse to diff unless the previous state is signed by this ed25519 public key
+    #[arg(long = "verify-key", value_name = "FILE")]
+    verify_key: Option<PathBuf>,
+
+    #[arg(long = "no-write", action = ArgAction::SetTrue)]
+    no_write: bool,
+
+    /// Go on past files that cannot be read or synced, list them at the end, and exit with
+    /// code 3
+    #[arg(long = "keep-going", action = ArgAction::SetTrue)]
+    keep_going: bool,
+
+    /// Write the new state here instead of STATE_FILE (`-` for stdout)
+    #[arg(long = "emit-state", value_name = "FILE", conflicts_with = "no_write")]
+    emit_state: Option<PathBuf>,
+
+    /// Print what `--target` would do and do nothing: no sync, no state written
+    #[arg(long = "dry-run", action = ArgAction::SetTrue, conflicts_with = "emit_state")]
+    dry_run: bool,
+
+    /// Also write the operations that sync the changes to a copy, for the `apply` subcommand
+    #[arg(long = "emit-patch", value_name = "FILE")]
+    emit_patch: Option<PathBuf>,
+
+    /// Also append the new state as a timestamped snapshot to this history store directory
+    #[arg(long = "history", value_name = "DIR")]
+    history: Option<PathBuf>,
+
+    /// Label appended to the snapshot id (with `--history`)
+    #[arg(long = "snapshot-label", value_name = "LABEL", requires = "history")]
+    snapshot_label: Option<String>,
+
+    /// Record file owner/group and report ownership changes
+    #[arg(long = "owner", action = ArgAction::SetTrue)]
+    owner: bool,
+
+    /// Record extended attributes (including SELinux labels) and report their changes
+    #[arg(long = "xattrs", action = ArgAction::SetTrue)]
+    xattrs: bool,
+
+    /// Record a digest of each file's mode, owner, extended attributes and symlink target, and
+    /// report metadata-only changes
+    #[arg(long = "meta-hash", action = ArgAction::SetTrue)]
+    meta_hash: bool,
+
+    /// Record empty directories and replicate them in the target
+    #[arg(long = "empty-dirs", action = ArgAction::SetTrue)]
+    empty_dirs: bool,
+
+    /// Follow symbolic links (and junctions, on Windows) instead of recording them as links
+    #[arg(long = "follow-symlinks", visible_alias = "follow-junctions", action = ArgAction::SetTrue)]
+    follow_symlinks: bool,
+
+    /// Always read files with buffered reads instead of memory-mapping large ones
+    #[arg(long = "no-mmap", action = ArgAction::SetTrue)]
+    no_mmap: bool,
+
+    /// How file contents are read
+    #[arg(long = "io-engine", value_enum, default_value_t = IoEngine::Std)]
+    io_engine: IoEngine,
+
+    /// Drop hashed files from the page cache so the scan does not evict other programs' data
+    #[arg(long = "no-cache-pollution", action = ArgAction::SetTrue)]
+    no_cache_pollution: bool,
+
+    /// Size of each read when hashing (default: 1M, 256K with `--io-engine uring`)
+    #[arg(long = "buffer-size", value_name = "SIZE", value_parser = parse_byte_size)]
+    buffer_size: Option<u64>,
+
+    /// Limit how many bytes per second are read for hashing, across all threads (e.g. `20M`)
+    #[arg(long = "bwlimit", value_name = "RATE", value_parser = parse_byte_size)]
+    bwlimit: Option<u64>,
+
+    /// Only hash the size and the first and last `--quick-bytes` of each file
+    #[arg(
+        long = "quick",
+        action = ArgAction::SetTrue,
+        conflicts_with_all = ["export_manifest", "export_mtree", "sample"]
+    )]
+    quick: bool,
+
+    /// Bytes hashed at each end of a file with `--quick` (e.g. `64K`, `1M`)
+    #[arg(long = "quick-bytes", value_name = "SIZE", value_parser = parse_byte_size, default_value = "1M")]
+    quick_bytes: u64,
+
+    /// Only hash the size and `--sample-blocks` evenly spaced blocks of files of at least `--sample-threshold`
+    #[arg(
+        long = "sample",
+        action = ArgAction::SetTrue,
+        conflicts_with_all = ["export_manifest", "export_mtree"]
+    )]
+    sample: bool,
+
+    /// Smallest file that `--sample` applies to (smaller files are hashed in full)
+    #[arg(long = "sample-threshold", value_name = "SIZE", value_parser = parse_byte_size, default_value = "1G")]
+    sample_threshold: u64,
+
+    /// Number of blocks hashed per sampled file
+    #[arg(
+        long = "sample-blocks",
+        value_name = "N",
+        value_parser = clap::value_parser!(u64).range(1..),
+        default_value_t = 64
+    )]
+    sample_blocks: u64,
+
+    /// Size of each sampled block
+    #[arg(long = "sample-block-size", value_name = "SIZE", value_parser = parse_byte_size, default_value = "1M")]
+    sample_block_size: u64,
+
+    /// Do not read files larger than this: record their size and timestamps only (e.g. `100G`)
+    #[arg(long = "hash-max-size", value_name = "SIZE", value_parser = parse_byte_size)]
+    hash_max_size: Option<u64>,
+
+    /// Also record a similarity digest (TLSH) of each file, to match added files to deleted ones
+    #[arg(long = "similarity", action = ArgAction::SetTrue)]
+    similarity: bool,
+
+    /// Also record the digests of content-defined (FastCDC) chunks of each file
+    #[arg(long = "chunks", action = ArgAction::SetTrue, conflicts_with_all = ["quick", "sample"])]
+    chunks: bool,
+
+    /// Average chunk size with `--chunks` (256 bytes to 4M; chunks are 1/4 to 4 times as large)
+    #[arg(long = "chunk-size", value_name = "SIZE", value_parser = parse_byte_size, default_value = "1M")]
+    chunk_size: u64,
+
+    /// Rehash every file, even those whose size and modification time are unchanged
+    #[arg(long = "paranoid", action = ArgAction::SetTrue)]
+    paranoid: bool,
+
+    /// Diff (and sync) even if the state was recorded for another directory, and replace an existing compressed tar --target
+    #[arg(long = "force", action = ArgAction::SetTrue)]
+    force: bool,
+
+    /// Start from an empty state if the state file cannot be read, instead of failing
+    #[arg(long = "ignore-bad-state", action = ArgAction::SetTrue, conflicts_with = "verify_key")]
+    ignore_bad_state: bool,
+    
+    #[arg(long = "target")]
+    target: Option<PathBuf>,
+
+    /// Snapshot mode: the target is a new directory where files unchanged since the previous
+    /// run are hard-linked from DIR (that run's target) and only changes are copied
+    #[arg(long = "link-dest", value_name = "DIR", requires = "target")]
+    link_dest: Option<PathBuf>,
+
+    /// Also remove from the target whatever the source does not have, whether its deletion
+    /// was recorded or not (implies --delete)
+    #[arg(long = "mirror", action = ArgAction::SetTrue, requires = "target", conflicts_with = "link_dest")]
+    mirror: bool,
+
+    /// Also remove from the target what was deleted in the source (otherwise it is kept)
+    #[arg(long = "delete", action = ArgAction::SetTrue, requires = "target")]
+    delete: bool,
+
+    /// Instead of removing deleted files from the target, move them into a timestamped
+    /// directory under DIR (relative to the target)
+    #[arg(long = "delete-to", value_name = "DIR", requires = "target")]
+    delete_to: Option<PathBuf>,
+
+    /// Same as --delete-to .fhi-trash
+    #[arg(long = "trash", action = ArgAction::SetTrue, requires = "target", conflicts_with = "delete_to")]
+    trash: bool,
+
+    /// Before overwriting or deleting a target file, rename it to <FILE><SUFFIX>.<TIMESTAMP>
+    #[arg(long = "backup-suffix", value_name = "SUFFIX", requires = "target", conflicts_with_all = ["delete_to", "trash"])]
+    backup_suffix: Option<String>,
+
+    /// Move the previous versions of target files to DIR (relative to the target) instead
+    #[arg(long = "backup-dir", value_name = "DIR", requires = "target", conflicts_with_all = ["delete_to", "trash"])]
+    backup_dir: Option<PathBuf>,
+
+    /// Keep at most N previous versions of each file
+    #[arg(long = "backup-keep", value_name = "N", requires = "target")]
+    backup_keep: Option<usize>,
+
+    /// Re-hash each copied file in the target and fail if it does not match the source
+    #[arg(long = "verify-copies", action = ArgAction::SetTrue, requires = "target")]
+    verify_copies: bool,
+
+    /// How many files to copy to (or delete from) the target at a time
+    #[arg(
+        long = "copy-jobs",
+        value_name = "N",
+        value_parser = clap::value_parser!(u64).range(1..),
+        default_value_t = 4,
+        requires = "target",
+    )]
+    copy_jobs: u64,
+
+    /// Also replicate the owner and group of copied files (needs root or CAP_CHOWN)
+    #[arg(long = "preserve-owner", action = ArgAction::SetTrue, requires = "target")]
+    preserve_owner: bool,
+
+    /// Also replicate the extended attributes of copied files (other than ACLs)
+    #[arg(long = "preserve-xattrs", action = ArgAction::SetTrue, requires = "target")]
+    preserve_xattrs: bool,
+
+    /// Also replicate POSIX ACLs (Linux) or DACLs (Windows) of copied files
+    #[arg(long = "preserve-acls", action = ArgAction::SetTrue, requires = "target")]
+    preserve_acls: bool,
+
+    /// Keep directories of the target that deletions leave empty
+    #[arg(long = "keep-empty-dirs", action = ArgAction::SetTrue, requires = "target")]
+    keep_empty_dirs: bool,
+
+    /// Limit how many bytes per second are written to the target, across all copies (e.g. `5M`)
+    #[arg(long = "copy-bwlimit", value_name = "RATE", value_parser = parse_byte_size, requires = "target")]
+    copy_bwlimit: Option<u64>,
+
+    /// Retry copies and deletions that fail with a transient error (timeouts, `EAGAIN`,
+    /// `EIO` on network filesystems) up to `N` times
+    #[arg(long = "copy-retries", value_name = "N", default_value_t = 0, requires = "target")]
+    copy_retries: u32,
+
+    /// Wait before the first retry, doubled for each next one (e.g. `500ms`, `2s`)
+    #[arg(long = "copy-retry-delay", value_name = "DURATION", value_parser = parse_duration, default_value = "1s", requires = "target")]
+    copy_retry_delay: Duration,
+
+    /// State file of the target; files that changed in it since the previous run and are
+    /// changed in the source too are conflicts, and then nothing is synced
+    #[arg(long = "target-state", value_name = "STATE_FILE", requires = "target")]
+    target_state: Option<PathBuf>,
+
+    /// Also write an mtree(8) specification of the indexed files
+    #[arg(long = "export-mtree", value_name = "FILE")]
+    export_mtree: Option<PathBuf>,
+
+    /// Also write a `b3sum -c`/`sha256sum -c` compatible manifest of the indexed files
+    #[arg(long = "export-manifest", value_name = "FILE")]
+    export_manifest: Option<PathBuf>,
+
+    /// Also pack the added and updated files into this zip archive, with their relative paths
+    #[arg(long = "archive-zip", value_name = "FILE")]
+    archive_zip: Option<PathBuf>,
+
+    /// After the run, POST a JSON summary of the changes to URL (only when something changed)
+    #[arg(long = "notify-url", value_name = "URL")]
+    notify_url: Option<String>,
+
+    /// Print one summary line per directory instead of one line per file
+    #[arg(long = "group-by-dir", action = ArgAction::SetTrue)]
+    group_by_dir: bool,
+
+    /// After the change list, print the N largest added/updated files by size
+    #[arg(long = "top", value_name = "N")]
+    top: Option<usize>,
+
+    /// Output format for the change list
+    #[arg(long = "format", value_enum, default_value_t = OutputFormat::Text)]
+    format: OutputFormat,
+
+    /// Shorthand for `--format porcelain` (stable, machine-readable output)
+    #[arg(long = "porcelain", action = ArgAction::SetTrue, conflicts_with = "format")]
+    porcelain: bool,
+
+    /// Only report (and sync) these kinds of changes, e.g. `added,deleted`
+    #[arg(long = "only", value_enum, value_name = "KINDS", value_delimiter = ',')]
+    only: Vec<ChangeKind>,
+
+    /// Do not report (or sync) these kinds of changes
+    #[arg(long = "ignore", value_enum, value_name = "KINDS", value_delimiter = ',')]
+    ignore: Vec<ChangeKind>,
+
+    /// Fail without writing the state or syncing the target when there are more than N changes
+    #[arg(long = "max-changes", value_name = "N")]
+    max_changes: Option<usize>,
+
+    /// Fail without writing the state or syncing the target when more than N files were deleted
+    #[arg(long = "max-deleted", value_name = "N")]
+    max_deleted: Option<usize>,
+}
+
+#[derive(Subcommand, Debug)]
+pub(crate) enum Command {
+    /// Convert a `sha256sum`/`b3sum`-style checksum manifest into a state file
+    Import(ImportArgs),
+    /// Print what a state file contains (format, algorithm, entry count, sizes, timestamps)
+    Inspect(InspectArgs),
+    /// Show the changes between two snapshots of a history store (`--history`)
+    Diff(DiffArgs),
+    /// Delete old snapshots of a history store according to retention rules
+    Prune(PruneArgs),
+    /// Rewrite a state file (e.g. a legacy v1 one) in the current format
+    Migrate(MigrateArgs),
+    /// Combine several state files (e.g. of shards indexed in parallel) into one
+    Merge(MergeArgs),
+    /// Hash two directories and show how the second differs from the first, without a state file
+    DiffDirs(DiffDirsArgs),
+    /// Show the changes between two state files, without reading the indexed files
+    DiffStates(DiffStatesArgs),
+    /// List sets of identical files in a state file and the space they waste
+    Dupes(DupesArgs),
+    /// Replace identical files of a state file with hard links to a single copy
+    Dedupe(DedupeArgs),
+    /// Replay a patch file (`--emit-patch`) against a copy of the indexed directory
+    Apply(ApplyArgs),
+}
+
+/// Runs the command line tool on the arguments of the process, like the `fast-hash-index`
+/// binary does.
+pub fn run() -> Result<()> {
+    let cli = Cli::parse();
+
+    match cli.command {
+        Some(Command::Import(ref args)) => run_import(args),
+        Some(Command::Inspect(ref args)) => run_inspect(args),
+        Some(Command::Diff(ref args)) => run_diff(args),
+        Some(Command::Prune(ref args)) => run_prune(args),
+        Some(Command::Migrate(ref args)) => run_migrate(args),
+        Some(Command::Merge(ref args)) => run_merge(args),
+        Some(Command::DiffDirs(ref args)) => run_diff_dirs(args),
+        Some(Command::DiffStates(ref args)) => run_diff_states(args),
+        Some(Command::Dupes(ref args)) => run_dupes(args),
+        Some(Command::Dedupe(ref args)) => run_dedupe(args),
+        Some(Command::Apply(ref args)) => run_apply(args),
+        None => run_scan(cli),
+    }
+}
+
+fn run_scan(cli: Cli) -> Result<()> {
+    let Some(state_file) = cli.state_file.clone() else {
+        unreachable!("clap requires STATE_FILE and DIR without a subcommand");
+    };
+
+    let mut sources = cli
+        .dirs
+        .iter()
+        .map(|dir| fs::canonicalize(dir).with_context(|| format!("Failed to resolve directory: {dir:?}")))
+        .collect::<Result<Vec<_>>>()?;
+    sources.sort();
+    let root = common_root(&sources)?;
+    // With several directories, entries are recorded relative to their common parent, so each
+    // path starts with the directory it was found in.
+    let source_prefixes: Option<Vec<String>> = (sources.len() > 1).then(|| {
+        sources
+            .iter()
+            .map(|s| cli.normalize_paths.apply(&path_to_rel_unix(&root, s)).into_owned())
+            .collect()
+    });
+
+    let target_abs: Option<PathBuf> = if let Some(t) = &cli.target {
+        let abs = if t.is_absolute() {
+            t.clone()
+        } else {
+            std::env::current_dir()
+                .with_context(|| "Failed to get current working directory")?
+                .join(t)
+        };
+        Some(abs)
+    } else {
+        None
+    };
+
+    if let Some(ref tgt) = target_abs {
+        let tgt_can = fs::canonicalize(tgt).unwrap_or_else(|_| tgt.clone());
+        for root_can in &sources {
+            if *root_can == tgt_can {
+                return Err(anyhow!("Target (--target) cannot be the same as source."));
+            }
+            if tgt_can.starts_with(root_can) || root_can.starts_with(&tgt_can) {
+                return Err(anyhow!("Source and target cannot contain each other."));
+            }
+        }
+    }
+
+    if let Some(ref label) = cli.snapshot_label {
+        validate_snapshot_label(label)?;
+    }
+    if cli.sample && cli.sample_block_size == 0 {
+        return Err(anyhow!("--sample-block-size must be greater than zero."));
+    }
+    let buffer_size = match cli.buffer_size {
+        Some(0) => return Err(anyhow!("--buffer-size must be greater than zero.")),
+        Some(size) => usize::try_from(size).context("--buffer-size is too large")?,
+        None => default_buffer_size(cli.io_engine),
+    };
+    if cli.max_depth == Some(0) {
+        return Err(anyhow!("--max-depth must be at least 1."));
+    }
+    if cli.bwlimit == Some(0) {
+        return Err(anyhow!("--bwlimit must be greater than zero."));
+    }
+    if cli.copy_bwlimit == Some(0) {
+        return Err(anyhow!("--copy-bwlimit must be greater than zero."));
+    }
+    let archive = target_abs.as_deref().and_then(TarFormat::of);
+    if archive.is_some() {
+        let unsupported = [
+            ("--link-dest", cli.link_dest.is_some()),
+            ("--mirror", cli.mirror),
+            ("--delete-to/--trash", cli.delete_to.is_some() || cli.trash),
+            ("--backup-suffix/--backup-dir", cli.backup_suffix.is_some() || cli.backup_dir.is_some()),
+            ("--target-state", cli.target_state.is_some()),
+            ("--verify-copies", cli.verify_copies),
+            ("--preserve-xattrs/--preserve-acls", cli.preserve_xattrs || cli.preserve_acls),
+        ];
+        if let Some((flag, _)) = unsupported.iter().find(|(_, given)| *given) {
+            return Err(anyhow!("{flag} cannot be used when --target is a tar archive."));
+        }
+    }
+    let chunking = if cli.chunks {
+        let range = u64::from(fastcdc::v2020::AVERAGE_MIN)..=u64::from(fastcdc::v2020::AVERAGE_MAX);
+        if !range.contains(&cli.chunk_size) {
+            return Err(anyhow!("--chunk-size must be between 256 bytes and 4 MiB."));
+        }
+        Some(cli.chunk_size as u32)
+    } else {
+        None
+    };
+
+    if is_stdio(&state_file) && cli.emit_state.is_none() && !cli.no_write && !cli.dry_run {
+        return Err(anyhow!(
+            "The previous state is read from stdin; use --emit-state <FILE> (or `-`) or --no-write."
+        ));
+    }
+    if is_stdio(&state_file) && cli.files_from.as_deref().is_some_and(is_stdio) {
+        return Err(anyhow!("The previous state and --files-from cannot both be read from stdin."));
+    }
+    let emit_state = if cli.no_write || cli.dry_run {
+        None
+    } else {
+        Some(cli.emit_state.clone().unwrap_or_else(|| state_file.clone()))
+    };
+
+    let _lock = acquire_run_lock(&state_file)?;
+
+    let (old_state, old_format) = if let Some(ref key_path) = cli.verify_key {
+        // A baseline that cannot be verified must never be silently replaced.
+        let key = load_verifying_key(key_path)?;
+        if !is_stdio(&state_file) && !state_file.exists() {
+            return Err(anyhow!("No state file to verify: {state_file:?}"));
+        }
+        let (state, format) = read_state_file(&state_file)?;
+        verify_state(&state, &key)
+            .with_context(|| format!("Signature verification failed for {state_file:?}"))?;
+        (state, format)
+    } else {
+        // A missing state file is an empty state; one that exists but cannot be read is an
+        // error, as diffing against nothing would report every file as added.
+        match read_state_file(&state_file) {
+            Ok(state) => state,
+            Err(err) if cli.ignore_bad_state => {
+                eprintln!("Warning: ignoring previous state: {err:#}");
+                Default::default()
+            }
+            Err(err) => {
+                return Err(err.context("Cannot use the previous state (use --ignore-bad-state to start over)"))
+            }
+        }
+    };
+    let algo = cli.algo[0];
+    let mut also: Vec<Algo> = Vec::new();
+    for &extra in &cli.algo[1..] {
+        if extra != algo && !also.contains(&extra) {
+            also.push(extra);
+        }
+    }
+    if !also.is_empty() && (cli.quick || cli.sample) {
+        return Err(anyhow!("Several --algo values cannot be combined with --quick or --sample."));
+    }
+    let hash_key = load_hash_key(cli.hash_key.as_deref())?;
+    if hash_key.is_some() && !matches!(algo, Algo::Blake3) {
+        return Err(anyhow!("Keyed hashing (--hash-key) requires --algo blake3."));
+    }
+    // Only BLAKE3 is keyed; another digest next to it could be forged without the key.
+    if hash_key.is_some() && !also.is_empty() {
+        return Err(anyhow!("Keyed hashing (--hash-key) cannot be combined with several --algo values."));
+    }
+    let key_id = hash_key.as_ref().map(hash_key_id);
+    if !old_state.entries.is_empty() && old_state.header.key_id != key_id {
+        eprintln!(
+            "Warning: previous state was hashed with a different key (or without one); \
+             all files will be reported as updated"
+        );
+    }
+
+    if let Some(ref recorded) = old_state.header.root {
+        let current = root.to_string_lossy();
+        if *recorded != current && !old_state.entries.is_empty() && !cli.force {
+            let host = old_state.header.host.as_deref().unwrap_or("unknown host");
+            return Err(anyhow!(
+                "State file {state_file:?} was recorded for {recorded} (on {host}), not {current}; \
+                 refusing to diff against it (use --force if this is intended)"
+            ));
+        }
+        if old_state.header.sources != source_prefixes && !old_state.entries.is_empty() && !cli.force {
+            let show = |sources: &Option<Vec<String>>| match sources {
+                Some(sources) => sources.join(", "),
+                None => recorded.clone(),
+            };
+            return Err(anyhow!(
+                "State file {state_file:?} was recorded for other directories ({}), not {}; \
+                 refusing to diff against it (use --force if this is intended)",
+                show(&old_state.header.sources),
+                show(&source_prefixes)
+            ));
+        }
+    }
+
+    if old_state.header.version == 1 && !old_state.entries.is_empty() {
+        if emit_state.as_ref() == Some(&state_file) {
+            eprintln!("Notice: {state_file:?} is a legacy v1 state file; it is rewritten in the v2 format");
+        } else {
+            eprintln!("Notice: {state_file:?} is a legacy v1 state file; convert it with `fast-hash-index migrate`");
+        }
+    }
+
+    // The rows of a SQLite state as stored, before their paths are normalized or case-folded
+    // and their digests recomputed below, so the in-place update rewrites the rows that
+    // changed and deletes those under an old spelling.
+    let stored = (old_format == StateFormat::Sqlite).then(|| old_state.entries.clone());
+    let mut old_map = cli.normalize_paths.apply_to_map(old_state.entries);
+    let state_format = cli.state_format.unwrap_or(old_format);
+    let digest_encoding = cli.digest_encoding.unwrap_or(old_state.header.digests);
+
+    let mut excludes = cli.excludes.clone();
+    for path in &cli.exclude_from {
+        excludes.extend(read_pattern_file(path)?);
+    }
+    let walk = WalkOptions {
+        excludes: build_globset(&excludes, "exclude", cli.ignore_case)?,
+        prune: build_prune_set(&excludes, cli.ignore_case)?,
+        exclude_regex: build_regex_set(&cli.exclude_regex, cli.ignore_case).context("Invalid --exclude-regex")?,
+        includes: if cli.includes.is_empty() {
+            None
+        } else {
+            Some(build_globset(&cli.includes, "include", cli.ignore_case)?)
+        },
+        include_regex: if cli.include_regex.is_empty() {
+            None
+        } else {
+            Some(build_regex_set(&cli.include_regex, cli.ignore_case).context("Invalid --include-regex")?)
+        },
+        follow_symlinks: cli.follow_symlinks,
+        gitignore: cli.respect_gitignore,
+        fhignore: !cli.no_fhignore,
+        max_depth: cli.max_depth,
+        one_file_system: cli.one_file_system,
+        hidden: !cli.no_hidden,
+        git_tracked: if cli.git_tracked {
+            Some(Arc::new(git_tracked_files(&root)?))
+        } else {
+            None
+        },
+        cachedir_tag: !cli.no_cachedir_tag,
+        special_files: cli.special_files,
+    };
+    let (collected, unlisted) = match cli.files_from {
+        Some(ref list) => {
+            let listed = read_file_list(list, &root, &sources)?;
+            listed_files(&root, &listed, &old_map, &walk, cli.normalize_paths)
+        }
+        None => (collect_files(&root, &sources, &walk)?, Vec::new()),
+    };
+    if !unlisted.is_empty() {
+        eprintln!("Kept {} entries not listed in --files-from from the previous state", unlisted.len());
+    }
+    let window = TimeWindow {
+        newer_than: cli.newer_than,
+        older_than: cli.older_than,
+    };
+    let (to_hash, carried) = window.split(&root, &collected.files, &old_map, cli.normalize_paths);
+    if !carried.is_empty() {
+        eprintln!("Kept {} files modified outside the --newer-than/--older-than window from the previous state", carried.len());
+    }
+
+    let scan = ScanOptions {
+        algo,
+        also,
+        owner: cli.owner,
+        xattrs: cli.xattrs,
+        meta_hash: cli.meta_hash,
+        key: hash_key,
+        sampling: if cli.quick {
+            Sampling::Quick { edge: cli.quick_bytes }
+        } else if cli.sample {
+            Sampling::Blocks {
+                count: cli.sample_blocks,
+                size: cli.sample_block_size,
+                threshold: cli.sample_threshold,
+            }
+        } else {
+            Sampling::Full
+        },
+        chunking,
+        hash_max_size: cli.hash_max_size,
+        read: ReadOptions {
+            // Mapped pages cannot be dropped while mapped, and page faults cannot be throttled,
+            // so reads are used instead.
+            mmap: !cli.no_mmap && !cli.no_cache_pollution && cli.bwlimit.is_none(),
+            engine: cli.io_engine,
+            buffer_size,
+            drop_cache: cli.no_cache_pollution,
+            bwlimit: cli.bwlimit.map(|rate| Arc::new(RateLimit::new(rate))),
+        },
+        keep_going: cli.keep_going,
+    };
+    let cache = if cli.paranoid {
+        None
+    } else {
+        HashCache::new(&old_map, &old_state.header, &root, key_id.as_ref(), cli.normalize_paths)
+    };
+    let (mut entries, mut failed) = hash_entries(&root, &to_hash, &scan, cache.as_ref())?;
+    // Unreadable files keep their previous entry, so they are not reported (or synced) as
+    // deleted, and are hashed again next time.
+    entries.extend(failed.iter().filter_map(|f| old_map.get(cli.normalize_paths.apply(&f.path).as_ref()).cloned()));
+    entries.extend(carried);
+    entries.extend(unlisted);
+    entries.extend(link_entries(&root, &collected.links));
+    entries.extend(special_entries(&root, &collected.specials));
+    if cli.empty_dirs {
+        entries.extend(empty_dir_entries(&root, &collected));
+    }
+
+    let (mut new_map, disk_paths) = normalize_entries(&root, entries, cli.normalize_paths);
+    if cli.similarity {
+        add_similarity_digests(&root, &disk_paths, &old_map, &mut new_map);
+    }
+    let case_renames = if cli.case_insensitive_paths {
+        fold_case_renames(&mut old_map, &new_map)
+    } else {
+        Vec::new()
+    };
+    report_case_renames(&case_renames);
+    if cfg!(unix) && old_state.header.version == 1 {
+        let folded = fold_v1_backslashes(&mut old_map, &new_map);
+        if folded > 0 {
+            eprintln!("Notice: matched {folded} paths with `\\` to their `/` spelling in the legacy v1 state");
+        }
+    }
+
+    let rehashed = reconcile_algorithms(&root, &disk_paths, &mut old_map, &old_state.header, &new_map, &scan);
+    if rehashed > 0 {
+        eprintln!(
+            "Rehashed {rehashed} files recorded with another algorithm or sampling to compare them with this run"
+        );
+    }
+
+    let changes = filter_changes(diff_maps(&old_map, &new_map), &cli.only, &cli.ignore);
+
+    let format = if cli.porcelain {
+        OutputFormat::Porcelain
+    } else {
+        cli.format
+    };
+
+    // When the state is emitted on stdout, the report goes to stderr instead.
+    let mut out: Box<dyn Write> = if emit_state.as_deref().is_some_and(is_stdio) {
+        Box::new(io::stderr().lock())
+    } else {
+        Box::new(io::stdout().lock())
+    };
+    match format {
+        OutputFormat::Text => {
+            if cli.group_by_dir {
+                print_grouped_changes(&mut out, &changes, &old_map, &new_map)?;
+            } else {
+                print_changes(&mut out, &changes)?;
+            }
+
+            if let Some(n) = cli.top {
+                print_top_changes(&mut out, &changes, &new_map, n)?;
+            }
+            if chunking.is_some() && old_state.header.chunk_size == chunking {
+                print_chunk_changes(&mut out, &changes, &old_map, &new_map)?;
+            }
+            print_similar_files(&mut out, &changes, &old_map, &new_map)?;
+        }
+        OutputFormat::Junit => print_junit(&mut out, &changes, &root)?,
+        OutputFormat::Porcelain => print_porcelain(&mut out, &changes)?,
+    }
+    out.flush()?;
+    drop(out);
+
+    check_change_limits(&changes, cli.max_changes, cli.max_deleted)?;
+    if let (Some(path), Some(target)) = (&cli.target_state, &target_abs) {
+        check_conflicts(path, target, &old_state.header, &old_map, &new_map, &changes)?;
+    }
+
+    let mut ops = if cli.emit_patch.is_some() || target_abs.is_some() {
+        sync_ops(&changes, &old_map, &new_map, algo)
+    } else {
+        Vec::new()
+    };
+    if let Some(ref patch) = cli.emit_patch {
+        let header = PatchHeader {
+            version: PATCH_VERSION,
+            root: Some(root.to_string_lossy().into_owned()),
+            algo: Some(algo_name(algo).to_string()),
+            key_id: key_id.clone(),
+        };
+        write_patch(patch, &header, &ops)?;
+    }
+
+    let mut journal = None;
+    if let (Some(target), Some(format)) = (&target_abs, archive) {
+        let source = OpSource {
+            root: &root,
+            disk_paths: &disk_paths,
+            verify: None,
+        };
+        write_tar(target, format, &ops, &source, cli.dry_run, cli.force)?;
+    } else if let Some(ref target) = target_abs {
+        let trash = match (&cli.delete_to, cli.trash) {
+            (Some(dir), _) => Some(quarantine_dir(target, dir)?),
+            (None, true) => Some(quarantine_dir(target, Path::new(TRASH_DIR))?),
+            (None, false) => None,
+        };
+        let sync = SyncOptions {
+            dry_run: cli.dry_run,
+            delete: cli.delete || cli.mirror || trash.is_some(),
+            trash,
+            backup: backup_options(target, cli.backup_suffix.as_deref(), cli.backup_dir.as_deref(), cli.backup_keep)?,
+            verify_copies: cli.verify_copies.then_some((algo, hash_key)),
+            jobs: cli.copy_jobs as usize,
+            keep_empty_dirs: cli.keep_empty_dirs,
+            bwlimit: cli.copy_bwlimit.map(RateLimit::new),
+            preserve: Preserve::new(cli.preserve_owner, cli.preserve_xattrs, cli.preserve_acls),
+            journal: if cli.dry_run || is_stdio(&state_file) {
+                None
+            } else {
+                let mut path = state_file.as_os_str().to_owned();
+                path.push(".sync-journal");
+                Some(Journal::open(PathBuf::from(path), target)?)
+            },
+            retries: cli.copy_retries,
+            retry_delay: cli.copy_retry_delay,
+            keep_going: cli.keep_going,
+        };
+        rename_in_target(target, &case_renames, &sync)?;
+        let source = OpSource {
+            root: &root,
+            disk_paths: &disk_paths,
+            verify: None,
+        };
+        if let Some(ref link_dest) = cli.link_dest {
+            failed.extend(link_unchanged(&source, link_dest, target, &changes, &new_map, &sync)?);
+            // Deleted files are simply not in the new snapshot.
+            ops.retain(|op| !op.is_deletion());
+        }
+        if cli.mirror {
+            let stale = mirror_ops(&source, target, &sources, &new_map, &walk, cli.normalize_paths, &sync)?;
+            let deleted: HashSet<&str> = ops.iter().filter(|op| op.is_deletion()).map(|op| op.path()).collect();
+            let stale: Vec<SyncOp> = stale.into_iter().filter(|op| !deleted.contains(op.path())).collect();
+            ops.extend(stale);
+        }
+        let (_, not_synced) = apply_ops(&ops, &source, target, &sync)?;
+        journal = sync.journal;
+        // What could not be synced keeps its previous state, to be synced again next time.
+        for f in &not_synced {
+            match old_map.get(&f.path) {
+                Some(e) => new_map.insert(f.path.clone(), e.clone()),
+                None => new_map.remove(&f.path),
+            };
+        }
+        failed.extend(not_synced);
+    }
+
+    if let Some(ref manifest) = cli.export_manifest {
+        write_manifest(manifest, &new_map)?;
+    }
+    if let Some(ref mtree) = cli.export_mtree {
+        write_mtree(mtree, &new_map, algo)?;
+    }
+    if let Some(ref zip) = cli.archive_zip {
+        let source = OpSource {
+            root: &root,
+            disk_paths: &disk_paths,
+            verify: None,
+        };
+        write_zip(zip, &changes, &new_map, &source, cli.dry_run)?;
+    }
+
+    let tree = tree_hashes(&new_map).remove("");
+    if let Some(ref tree) = tree {
+        eprintln!("Tree hash: {tree}");
+    }
+
+    if let Some(ref dest) = emit_state {
+        let mut state = State {
+            header: StateHeader {
+                version: STATE_VERSION,
+                algo: Some(algo_name(algo).to_string()),
+                root: Some(root.to_string_lossy().into_owned()),
+                host: hostname(),
+                scanned_at: Some(unix_now()),
+                digests: digest_encoding,
+                key_id,
+                chunk_size: chunking,
+                tree,
+                sources: source_prefixes,
+                ..Default::default()
+            },
+            entries: new_map,
+        };
+        if let Some(ref key_path) = cli.sign_key {
+            let key = load_signing_key(key_path)?;
+            sign_state(&mut state, &key)?;
+        }
+        // SQLite states are updated in place, which needs the rows of the same database.
+        let previous = stored.as_ref().filter(|_| *dest == state_file);
+        write_state_file(dest, &state, state_format, previous)?;
+
+        if let Some(ref store) = cli.history {
+            let id = write_snapshot(store, &state, state_format, cli.snapshot_label.as_deref())?;
+            eprintln!("Saved snapshot {id} in {store:?}");
+        }
+    }
+    // Kept until the new state is written, so a failed write still resumes the sync.
+    if let Some(journal) = journal {
+        journal.finish()?;
+    }
+
+    if let Some(ref url) = cli.notify_url {
+        if !changes.is_empty() && !cli.dry_run {
+            notify_changes(url, &root, &changes)?;
+        }
+    }
+
+    report_file_errors(&failed)
+}
+
+/// Parses a byte count with an optional binary suffix (`K`, `M`, `G`, `T`; e.g. `64K`, `1.5G`).
+pub(crate) fn parse_byte_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| c.is_ascii_alphabetic()).unwrap_or(s.len());
+    let (number, suffix) = s.split_at(split);
+    let unit: u64 = match suffix.to_ascii_uppercase().trim_end_matches(['B', 'I']) {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        "T" => 1 << 40,
+        _ => return Err(format!("unknown size suffix {suffix:?} (use K, M, G or T)")),
+    };
+    let number: f64 = number.trim().parse().map_err(|_| format!("invalid size: {s:?}"))?;
+    if !number.is_finite() || number < 0.0 {
+        return Err(format!("invalid size: {s:?}"));
+    }
+    Ok((number * unit as f64) as u64)
+}
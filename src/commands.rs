@@ -0,0 +1,13 @@
+//! The subcommands, one module each; scanning without a subcommand is in [`crate::cli`].
+
+pub(crate) mod apply;
+pub(crate) mod dedupe;
+pub(crate) mod diff;
+pub(crate) mod diff_dirs;
+pub(crate) mod diff_states;
+pub(crate) mod dupes;
+pub(crate) mod import;
+pub(crate) mod inspect;
+pub(crate) mod merge;
+pub(crate) mod migrate;
+pub(crate) mod prune;
@@ -0,0 +1,235 @@
+//! `apply`: applies a patch written by `--emit-patch` to a target directory.
+
+use crate::cli::parse_byte_size;
+use crate::hash::{hash_key_id, load_hash_key, Algo, RateLimit};
+use crate::patch::{PatchHeader, PATCH_VERSION};
+use crate::scan::report_file_errors;
+use crate::sync::{
+    apply_ops, backup_options, quarantine_dir, Journal, OpSource, Preserve, SyncOp, SyncOptions,
+    TRASH_DIR,
+};
+use crate::timestamps::parse_duration;
+use anyhow::{anyhow, Context, Result};
+use clap::{ArgAction, Args};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+#[derive(Args, Debug)]
+pub(crate) struct ApplyArgs {
+    /// Patch file written with `--emit-patch`
+    patch: PathBuf,
+    /// Directory to apply it to
+    target: PathBuf,
+
+    /// Where the indexed directory is mounted here (default: the root recorded in the patch)
+    #[arg(long = "source", value_name = "DIR")]
+    source: Option<PathBuf>,
+
+    /// Key of a keyed patch (or $FAST_HASH_INDEX_KEY)
+    #[arg(long = "hash-key", value_name = "FILE")]
+    hash_key: Option<PathBuf>,
+
+    /// Only print what would be done
+    #[arg(long = "dry-run", action = ArgAction::SetTrue)]
+    dry_run: bool,
+
+    /// Also apply the deletions of the patch
+    #[arg(long = "delete", action = ArgAction::SetTrue)]
+    delete: bool,
+
+    /// Apply the deletions by moving the files into a timestamped directory under DIR
+    /// (relative to the target)
+    #[arg(long = "delete-to", value_name = "DIR")]
+    delete_to: Option<PathBuf>,
+
+    /// Same as --delete-to .fhi-trash
+    #[arg(long = "trash", action = ArgAction::SetTrue, conflicts_with = "delete_to")]
+    trash: bool,
+
+    /// Before overwriting or deleting a target file, rename it to <FILE><SUFFIX>.<TIMESTAMP>
+    #[arg(long = "backup-suffix", value_name = "SUFFIX", conflicts_with_all = ["delete_to", "trash"])]
+    backup_suffix: Option<String>,
+
+    /// Move the previous versions of target files to DIR (relative to the target) instead
+    #[arg(long = "backup-dir", value_name = "DIR", conflicts_with_all = ["delete_to", "trash"])]
+    backup_dir: Option<PathBuf>,
+
+    /// Keep at most N previous versions of each file
+    #[arg(long = "backup-keep", value_name = "N")]
+    backup_keep: Option<usize>,
+
+    /// Re-hash each copied file in the target and fail if it does not match the source
+    #[arg(long = "verify-copies", action = ArgAction::SetTrue)]
+    verify_copies: bool,
+
+    /// How many files to copy to (or delete from) the target at a time
+    #[arg(
+        long = "copy-jobs",
+        value_name = "N",
+        value_parser = clap::value_parser!(u64).range(1..),
+        default_value_t = 4,
+    )]
+    copy_jobs: u64,
+
+    /// Also replicate the owner and group of copied files (needs root or CAP_CHOWN)
+    #[arg(long = "preserve-owner", action = ArgAction::SetTrue)]
+    preserve_owner: bool,
+
+    /// Also replicate the extended attributes of copied files (other than ACLs)
+    #[arg(long = "preserve-xattrs", action = ArgAction::SetTrue)]
+    preserve_xattrs: bool,
+
+    /// Also replicate POSIX ACLs (Linux) or DACLs (Windows) of copied files
+    #[arg(long = "preserve-acls", action = ArgAction::SetTrue)]
+    preserve_acls: bool,
+
+    /// Keep directories of the target that deletions leave empty
+    #[arg(long = "keep-empty-dirs", action = ArgAction::SetTrue)]
+    keep_empty_dirs: bool,
+
+    /// Limit how many bytes per second are written to the target, across all copies (e.g. `5M`)
+    #[arg(long = "copy-bwlimit", value_name = "RATE", value_parser = parse_byte_size)]
+    copy_bwlimit: Option<u64>,
+
+    /// Retry copies and deletions that fail with a transient error (timeouts, `EAGAIN`,
+    /// `EIO` on network filesystems) up to `N` times
+    #[arg(long = "copy-retries", value_name = "N", default_value_t = 0)]
+    copy_retries: u32,
+
+    /// Wait before the first retry, doubled for each next one (e.g. `500ms`, `2s`)
+    #[arg(long = "copy-retry-delay", value_name = "DURATION", value_parser = parse_duration, default_value = "1s")]
+    copy_retry_delay: Duration,
+
+    /// Go on past operations that fail, list them at the end, and exit with code 3
+    #[arg(long = "keep-going", action = ArgAction::SetTrue)]
+    keep_going: bool,
+}
+
+pub(crate) fn run_apply(args: &ApplyArgs) -> Result<()> {
+    if args.copy_bwlimit == Some(0) {
+        return Err(anyhow!("--copy-bwlimit must be greater than zero."));
+    }
+    let file = File::open(&args.patch).with_context(|| format!("Failed to open patch file: {:?}", args.patch))?;
+    let mut lines = BufReader::new(file).lines();
+    let first = lines.next().transpose()?.unwrap_or_default();
+    let header: PatchHeader = serde_json::from_str(&first)
+        .with_context(|| format!("{:?} is not a patch file (see --emit-patch)", args.patch))?;
+    if header.version > PATCH_VERSION {
+        return Err(anyhow!(
+            "Patch {:?} has version {}; this version of fast-hash-index reads up to {PATCH_VERSION}",
+            args.patch,
+            header.version
+        ));
+    }
+    let root = match (&args.source, &header.root) {
+        (Some(dir), _) => dir.clone(),
+        (None, Some(root)) => PathBuf::from(root),
+        (None, None) => return Err(anyhow!("The patch does not record its source; use --source")),
+    };
+    let key = load_hash_key(args.hash_key.as_deref())?;
+    if header.key_id.is_some() && key.as_ref().map(hash_key_id) != header.key_id {
+        return Err(anyhow!("The patch was hashed with a key; pass the same one with --hash-key"));
+    }
+
+    let mut ops = Vec::new();
+    for (n, line) in lines.enumerate() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        let op: SyncOp = serde_json::from_str(&line)
+            .with_context(|| format!("Invalid operation on line {} of {:?}", n + 2, args.patch))?;
+        ops.push(op);
+    }
+
+    let disk_paths = HashMap::new();
+    let source = OpSource {
+        root: &root,
+        disk_paths: &disk_paths,
+        verify: header.algo.as_deref().and_then(Algo::from_name).map(|algo| (algo, key)),
+    };
+    let trash = match (&args.delete_to, args.trash) {
+        (Some(dir), _) => Some(quarantine_dir(&args.target, dir)?),
+        (None, true) => Some(quarantine_dir(&args.target, Path::new(TRASH_DIR))?),
+        (None, false) => None,
+    };
+    let opts = SyncOptions {
+        dry_run: args.dry_run,
+        delete: args.delete || trash.is_some(),
+        trash,
+        backup: backup_options(&args.target, args.backup_suffix.as_deref(), args.backup_dir.as_deref(), args.backup_keep)?,
+        verify_copies: if args.verify_copies { source.verify } else { None },
+        jobs: args.copy_jobs as usize,
+        keep_empty_dirs: args.keep_empty_dirs,
+        bwlimit: args.copy_bwlimit.map(RateLimit::new),
+        preserve: Preserve::new(args.preserve_owner, args.preserve_xattrs, args.preserve_acls),
+        journal: if args.dry_run {
+            None
+        } else {
+            let mut path = args.patch.as_os_str().to_owned();
+            path.push(".journal");
+            Some(Journal::open(PathBuf::from(path), &args.target)?)
+        },
+        retries: args.copy_retries,
+        retry_delay: args.copy_retry_delay,
+        keep_going: args.keep_going,
+    };
+    let (skipped, failed) = apply_ops(&ops, &source, &args.target, &opts)?;
+    // Applying the patch again then only retries what failed.
+    if let Some(journal) = opts.journal.filter(|_| failed.is_empty()) {
+        journal.finish()?;
+    }
+    if !args.dry_run {
+        let kept = if opts.delete { 0 } else { ops.iter().filter(|op| op.is_deletion()).count() };
+        eprintln!("Applied {} operations to {:?}", ops.len() - kept - skipped - failed.len(), args.target);
+    }
+    if skipped > 0 {
+        return Err(anyhow!("{skipped} operations of the patch were skipped"));
+    }
+    report_file_errors(&failed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::patch::write_patch;
+    use crate::test_dir::TestDir;
+    use clap::Parser;
+    use std::fs;
+
+    #[derive(Parser)]
+    struct Apply {
+        #[command(flatten)]
+        args: ApplyArgs,
+    }
+
+    #[test]
+    fn source_changed_since_the_patch_is_not_copied() {
+        let dir = TestDir::new();
+        let file = dir.write("src/a", "one\n");
+        let header = PatchHeader {
+            version: PATCH_VERSION,
+            root: Some(dir.path().join("src").to_string_lossy().into_owned()),
+            algo: Some("blake3".to_string()),
+            key_id: None,
+        };
+        let copy = SyncOp::Copy { path: "a".to_string(), size: 4, hash: blake3::hash(b"one\n").to_hex().to_string(), partial: None };
+        let patch = dir.path().join("delta.patch");
+        write_patch(&patch, &header, &[copy]).unwrap();
+        let target = dir.path().join("target");
+        let apply = Apply::parse_from([Path::new("apply"), &patch, &target]);
+
+        // Rewritten with the same size after the patch was made.
+        fs::write(&file, "two\n").unwrap();
+        let err = run_apply(&apply.args).unwrap_err();
+        assert!(err.to_string().contains("1 operations of the patch were skipped"), "{err}");
+        assert!(!target.join("a").exists());
+
+        fs::write(&file, "one\n").unwrap();
+        run_apply(&apply.args).unwrap();
+        assert_eq!(fs::read_to_string(target.join("a")).unwrap(), "one\n");
+    }
+}
@@ -0,0 +1,111 @@
+//! `dedupe`: replaces duplicate files with hard links.
+
+use crate::cli::parse_byte_size;
+use crate::commands::dupes::duplicate_sets;
+use crate::copy::replace_with_hard_link;
+use crate::output::format_size;
+use crate::scan::{file_id, file_mode, file_owner};
+use crate::state::{is_stdio, read_state_file, Entry};
+use crate::walk::find_on_disk;
+use anyhow::{anyhow, Context, Result};
+use clap::{ArgAction, Args};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+#[derive(Args, Debug)]
+pub(crate) struct DedupeArgs {
+    state_file: PathBuf,
+
+    /// Replace duplicates with hard links (required; the only method so far)
+    #[arg(long = "hardlink", action = ArgAction::SetTrue, required = true)]
+    hardlink: bool,
+
+    /// Only print what would be linked
+    #[arg(long = "dry-run", action = ArgAction::SetTrue)]
+    dry_run: bool,
+
+    /// Ignore files smaller than this (e.g. `1M`)
+    #[arg(long = "min-size", value_name = "SIZE", value_parser = parse_byte_size, default_value = "1")]
+    min_size: u64,
+}
+
+pub(crate) fn run_dedupe(args: &DedupeArgs) -> Result<()> {
+    debug_assert!(args.hardlink);
+    if !is_stdio(&args.state_file) && !args.state_file.exists() {
+        return Err(anyhow!("State file not found: {:?}", args.state_file));
+    }
+    let (state, _) = read_state_file(&args.state_file)?;
+    let root = state
+        .header
+        .root
+        .as_ref()
+        .map(PathBuf::from)
+        .ok_or_else(|| anyhow!("State file {:?} does not record the indexed root", args.state_file))?;
+
+    let mut out = io::stdout().lock();
+    let (mut linked, mut saved) = (0, 0);
+    for set in duplicate_sets(&state, args.min_size) {
+        // One copy is kept per file system; the others become links to it.
+        let mut kept: HashMap<u64, (PathBuf, &Entry)> = HashMap::new();
+        for e in set {
+            let path = find_on_disk(&root, &e.rel_path);
+            let Some((dev, ino)) = file_id(&path) else {
+                eprintln!("Warning: {} is missing; skipped", e.rel_path);
+                continue;
+            };
+            let Some((keep, keep_entry)) = kept.get(&dev) else {
+                kept.insert(dev, (path, e));
+                continue;
+            };
+            if file_id(keep) == Some((dev, ino)) {
+                continue;
+            }
+            let (meta, keep_meta) = (fs::metadata(&path)?, fs::metadata(keep)?);
+            if (file_mode(&meta), file_owner(&meta)) != (file_mode(&keep_meta), file_owner(&keep_meta)) {
+                eprintln!(
+                    "Notice: {} and {} differ in permissions or owner; not linked",
+                    e.rel_path, keep_entry.rel_path
+                );
+                continue;
+            }
+            if !files_identical(&path, keep)? {
+                eprintln!(
+                    "Warning: {} and {} no longer have the same content; not linked",
+                    e.rel_path, keep_entry.rel_path
+                );
+                continue;
+            }
+            if args.dry_run {
+                writeln!(out, "would link {} => {}", e.rel_path, keep_entry.rel_path)?;
+            } else {
+                replace_with_hard_link(keep, &path)?;
+                writeln!(out, "linked {} => {}", e.rel_path, keep_entry.rel_path)?;
+            }
+            linked += 1;
+            saved += e.size;
+        }
+    }
+    let verb = if args.dry_run { "Would link" } else { "Linked" };
+    writeln!(out, "{verb} {linked} files, saving {}", format_size(saved))?;
+    Ok(())
+}
+
+/// Byte-for-byte comparison, so a state that is out of date never merges different files.
+fn files_identical(a: &Path, b: &Path) -> Result<bool> {
+    let open = |p: &Path| File::open(p).with_context(|| format!("Failed to open {p:?}"));
+    let (mut fa, mut fb) = (BufReader::new(open(a)?), BufReader::new(open(b)?));
+    loop {
+        let (ba, bb) = (fa.fill_buf()?, fb.fill_buf()?);
+        let n = ba.len().min(bb.len());
+        if ba[..n] != bb[..n] {
+            return Ok(false);
+        }
+        if n == 0 {
+            return Ok(ba.is_empty() && bb.is_empty());
+        }
+        fa.consume(n);
+        fb.consume(n);
+    }
+}
@@ -0,0 +1,59 @@
+//! `diff`: compares two snapshots of a history store.
+
+use crate::diff::ChangeKind;
+use crate::output::{print_state_diff, OutputFormat};
+use crate::snapshot::{resolve_snapshot, snapshot_path};
+use crate::state::read_state_file;
+use anyhow::Result;
+use clap::{ArgAction, Args};
+use std::path::PathBuf;
+
+#[derive(Args, Debug)]
+pub(crate) struct DiffArgs {
+    /// History store directory
+    store: PathBuf,
+
+    /// Older snapshot: an id, a unique id prefix, a label, or `latest`
+    #[arg(long = "from", value_name = "ID")]
+    from: String,
+
+    /// Newer snapshot (same forms as `--from`)
+    #[arg(long = "to", value_name = "ID", default_value = "latest")]
+    to: String,
+
+    /// Output format for the change list
+    #[arg(long = "format", value_enum, default_value_t = OutputFormat::Text)]
+    format: OutputFormat,
+
+    /// Shorthand for `--format porcelain`
+    #[arg(long = "porcelain", action = ArgAction::SetTrue, conflicts_with = "format")]
+    porcelain: bool,
+
+    /// Compare paths case-insensitively (see the scan option of the same name)
+    #[arg(long = "case-insensitive-paths", action = ArgAction::SetTrue)]
+    case_insensitive_paths: bool,
+
+    /// Only report these kinds of changes (see the scan option of the same name)
+    #[arg(long = "only", value_enum, value_name = "KINDS", value_delimiter = ',')]
+    only: Vec<ChangeKind>,
+
+    /// Do not report these kinds of changes
+    #[arg(long = "ignore", value_enum, value_name = "KINDS", value_delimiter = ',')]
+    ignore: Vec<ChangeKind>,
+}
+
+pub(crate) fn run_diff(args: &DiffArgs) -> Result<()> {
+    let from = resolve_snapshot(&args.store, &args.from)?;
+    let to = resolve_snapshot(&args.store, &args.to)?;
+    let (old, _) = read_state_file(&snapshot_path(&args.store, &from))?;
+    let (new, _) = read_state_file(&snapshot_path(&args.store, &to))?;
+    if old.header.algo != new.header.algo || old.header.key_id != new.header.key_id {
+        eprintln!("Warning: snapshots {from} and {to} were hashed differently; all files will be reported as updated");
+    }
+    let format = if args.porcelain {
+        OutputFormat::Porcelain
+    } else {
+        args.format
+    };
+    print_state_diff(old, &new, format, args.case_insensitive_paths, &args.only, &args.ignore, &args.store)
+}
@@ -0,0 +1,126 @@
+//! `diff-dirs`: compares two directories without state files.
+
+use crate::cli::algo_parser;
+use crate::diff::{diff_maps, filter_changes, ChangeKind};
+use crate::hash::{default_buffer_size, Algo, IoEngine, ReadOptions, Sampling};
+use crate::output::{print_changes, print_junit, print_porcelain, OutputFormat};
+use crate::scan::{hash_entries, ScanOptions};
+use crate::state::Entry;
+use crate::walk::{
+    build_globset, build_prune_set, collect_files, empty_dir_entries, link_entries,
+    normalize_entries, PathForm, SpecialFiles, WalkOptions,
+};
+use anyhow::{Context, Result};
+use clap::{ArgAction, Args};
+use regex::RegexSet;
+use std::collections::HashMap;
+use std::fs::{self};
+use std::io::{self};
+use std::path::{Path, PathBuf};
+
+#[derive(Args, Debug)]
+pub(crate) struct DiffDirsArgs {
+    /// Old side of the comparison
+    a: PathBuf,
+    /// New side of the comparison
+    b: PathBuf,
+
+    /// Exclude files/directories matching a glob pattern (relative to each directory)
+    #[arg(short = 'x', long = "exclude", value_name = "PATTERN")]
+    excludes: Vec<String>,
+
+    #[arg(long = "algo", value_parser = algo_parser(), default_value = "blake3")]
+    algo: Algo,
+
+    #[arg(long = "follow-symlinks", action = ArgAction::SetTrue)]
+    follow_symlinks: bool,
+
+    /// Also compare empty directories
+    #[arg(long = "empty-dirs", action = ArgAction::SetTrue)]
+    empty_dirs: bool,
+
+    /// Output format for the change list
+    #[arg(long = "format", value_enum, default_value_t = OutputFormat::Text)]
+    format: OutputFormat,
+
+    /// Shorthand for `--format porcelain`
+    #[arg(long = "porcelain", action = ArgAction::SetTrue, conflicts_with = "format")]
+    porcelain: bool,
+
+    /// Only report these kinds of changes (see the scan option of the same name)
+    #[arg(long = "only", value_enum, value_name = "KINDS", value_delimiter = ',')]
+    only: Vec<ChangeKind>,
+
+    /// Do not report these kinds of changes
+    #[arg(long = "ignore", value_enum, value_name = "KINDS", value_delimiter = ',')]
+    ignore: Vec<ChangeKind>,
+}
+
+pub(crate) fn run_diff_dirs(args: &DiffDirsArgs) -> Result<()> {
+    let resolve = |dir: &PathBuf| fs::canonicalize(dir).with_context(|| format!("Failed to resolve directory: {dir:?}"));
+    let (a, b) = (resolve(&args.a)?, resolve(&args.b)?);
+    let walk = WalkOptions {
+        excludes: build_globset(&args.excludes, "exclude", false)?,
+        prune: build_prune_set(&args.excludes, false)?,
+        exclude_regex: RegexSet::empty(),
+        includes: None,
+        include_regex: None,
+        follow_symlinks: args.follow_symlinks,
+        gitignore: false,
+        fhignore: true,
+        max_depth: None,
+        one_file_system: false,
+        hidden: true,
+        git_tracked: None,
+        cachedir_tag: true,
+        special_files: SpecialFiles::Warn,
+    };
+    let scan = ScanOptions {
+        algo: args.algo,
+        also: Vec::new(),
+        owner: false,
+        xattrs: false,
+        meta_hash: false,
+        key: None,
+        sampling: Sampling::Full,
+        chunking: None,
+        hash_max_size: None,
+        read: ReadOptions {
+            mmap: true,
+            engine: IoEngine::Std,
+            buffer_size: default_buffer_size(IoEngine::Std),
+            drop_cache: false,
+            bwlimit: None,
+        },
+        keep_going: false,
+    };
+    let index = |root: &Path| -> Result<HashMap<String, Entry>> {
+        let collected = collect_files(root, &[root.to_path_buf()], &walk)?;
+        let (mut entries, _) = hash_entries(root, &collected.files, &scan, None)?;
+        // Modification times of two different trees say nothing (copies rarely keep them), so
+        // they are not compared: no `S:` or `T:` lines.
+        for e in &mut entries {
+            e.mtime_ns = None;
+        }
+        entries.extend(link_entries(root, &collected.links));
+        if args.empty_dirs {
+            entries.extend(empty_dir_entries(root, &collected));
+        }
+        Ok(normalize_entries(root, entries, PathForm::Nfc).0)
+    };
+    let (old, new) = rayon::join(|| index(&a), || index(&b));
+    let (old, new) = (old?, new?);
+
+    let changes = filter_changes(diff_maps(&old, &new), &args.only, &args.ignore);
+    let format = if args.porcelain {
+        OutputFormat::Porcelain
+    } else {
+        args.format
+    };
+    let mut out = io::stdout().lock();
+    match format {
+        OutputFormat::Text => print_changes(&mut out, &changes),
+        OutputFormat::Junit => print_junit(&mut out, &changes, &b),
+        OutputFormat::Porcelain => print_porcelain(&mut out, &changes),
+    }
+}
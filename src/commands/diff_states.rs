@@ -0,0 +1,67 @@
+//! `diff-states`: compares two state files.
+
+use crate::diff::ChangeKind;
+use crate::output::{print_state_diff, OutputFormat};
+use crate::state::{is_stdio, read_state_file};
+use anyhow::{anyhow, Result};
+use clap::{ArgAction, Args};
+use std::path::{Path, PathBuf};
+
+#[derive(Args, Debug)]
+pub(crate) struct DiffStatesArgs {
+    /// Older state file
+    old: PathBuf,
+    /// Newer state file
+    new: PathBuf,
+
+    /// Output format for the change list
+    #[arg(long = "format", value_enum, default_value_t = OutputFormat::Text)]
+    format: OutputFormat,
+
+    /// Shorthand for `--format porcelain`
+    #[arg(long = "porcelain", action = ArgAction::SetTrue, conflicts_with = "format")]
+    porcelain: bool,
+
+    /// Compare paths case-insensitively (see the scan option of the same name)
+    #[arg(long = "case-insensitive-paths", action = ArgAction::SetTrue)]
+    case_insensitive_paths: bool,
+
+    /// Only report these kinds of changes (see the scan option of the same name)
+    #[arg(long = "only", value_enum, value_name = "KINDS", value_delimiter = ',')]
+    only: Vec<ChangeKind>,
+
+    /// Do not report these kinds of changes
+    #[arg(long = "ignore", value_enum, value_name = "KINDS", value_delimiter = ',')]
+    ignore: Vec<ChangeKind>,
+}
+
+pub(crate) fn run_diff_states(args: &DiffStatesArgs) -> Result<()> {
+    for path in [&args.old, &args.new] {
+        if !is_stdio(path) && !path.exists() {
+            return Err(anyhow!("State file not found: {path:?}"));
+        }
+    }
+    if is_stdio(&args.old) && is_stdio(&args.new) {
+        return Err(anyhow!("Only one of the state files can be read from stdin."));
+    }
+    let (old, _) = read_state_file(&args.old)?;
+    let (new, _) = read_state_file(&args.new)?;
+    if old.header.algo != new.header.algo || old.header.key_id != new.header.key_id {
+        eprintln!(
+            "Warning: {:?} and {:?} were hashed differently; all files will be reported as updated",
+            args.old, args.new
+        );
+    }
+    if let (Some(a), Some(b)) = (&old.header.root, &new.header.root) {
+        if a != b {
+            eprintln!("Warning: {:?} was recorded for {a} and {:?} for {b}", args.old, args.new);
+        }
+    }
+    let format = if args.porcelain {
+        OutputFormat::Porcelain
+    } else {
+        args.format
+    };
+    let fallback = args.new.parent().unwrap_or(Path::new("."));
+    print_state_diff(old, &new, format, args.case_insensitive_paths, &args.only, &args.ignore, fallback)
+}
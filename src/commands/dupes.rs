@@ -0,0 +1,81 @@
+//! `dupes`: lists duplicate files of a state.
+
+use crate::cli::parse_byte_size;
+use crate::output::format_size;
+use crate::state::{is_stdio, read_state_file, Entry, State};
+use anyhow::{anyhow, Result};
+use clap::Args;
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+#[derive(Args, Debug)]
+pub(crate) struct DupesArgs {
+    state_file: PathBuf,
+
+    /// Ignore files smaller than this (e.g. `1M`)
+    #[arg(long = "min-size", value_name = "SIZE", value_parser = parse_byte_size, default_value = "1")]
+    min_size: u64,
+}
+
+pub(crate) fn run_dupes(args: &DupesArgs) -> Result<()> {
+    if !is_stdio(&args.state_file) && !args.state_file.exists() {
+        return Err(anyhow!("State file not found: {:?}", args.state_file));
+    }
+    let (state, _) = read_state_file(&args.state_file)?;
+    let partial = state.entries.values().filter(|e| e.kind.is_file() && e.partial.is_some()).count();
+    if partial > 0 {
+        eprintln!("Notice: {partial} partially hashed or unhashed files are not compared");
+    }
+
+    let sets = duplicate_sets(&state, args.min_size);
+    let mut out = io::stdout().lock();
+    let (mut redundant, mut wasted) = (0, 0);
+    for set in &sets {
+        let size = set[0].size;
+        let set_wasted = size * (set.len() as u64 - 1);
+        writeln!(
+            out,
+            "{} copies of {} ({} wasted):",
+            set.len(),
+            format_size(size),
+            format_size(set_wasted)
+        )?;
+        for e in set {
+            writeln!(out, "  {}", e.rel_path)?;
+        }
+        redundant += set.len() - 1;
+        wasted += set_wasted;
+    }
+    writeln!(
+        out,
+        "{} duplicate sets, {redundant} redundant files, {} wasted",
+        sets.len(),
+        format_size(wasted)
+    )?;
+    Ok(())
+}
+
+/// Sets of fully hashed files of at least `min_size` bytes with the same digest (and size),
+/// the most wasteful first; each set is sorted by path.
+pub(crate) fn duplicate_sets(state: &State, min_size: u64) -> Vec<Vec<&Entry>> {
+    let header_algo = state.header.algo.as_deref();
+    let mut by_digest: HashMap<(Option<&str>, &str, u64), Vec<&Entry>> = HashMap::new();
+    for e in state.entries.values() {
+        // Hard links to a file take no extra space.
+        if e.hardlink.is_some() {
+            continue;
+        }
+        if e.kind.is_file() && e.partial.is_none() && e.size >= min_size && !e.hash_hex.is_empty() {
+            let algo = e.algo.as_deref().or(header_algo);
+            by_digest.entry((algo, &e.hash_hex, e.size)).or_default().push(e);
+        }
+    }
+    let mut sets: Vec<Vec<&Entry>> = by_digest.into_values().filter(|set| set.len() > 1).collect();
+    for set in &mut sets {
+        set.sort_by(|a, b| a.rel_path.cmp(&b.rel_path));
+    }
+    let wasted = |set: &Vec<&Entry>| set[0].size * (set.len() as u64 - 1);
+    sets.sort_by(|a, b| wasted(b).cmp(&wasted(a)).then_with(|| a[0].rel_path.cmp(&b[0].rel_path)));
+    sets
+}
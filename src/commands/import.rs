@@ -0,0 +1,51 @@
+//! `import`: converts a `*sum` manifest into a state file.
+
+use crate::cli::algo_parser;
+use crate::hash::{algo_name, Algo};
+use crate::manifest::parse_manifest;
+use crate::state::{
+    acquire_run_lock, write_state_file, State, StateFormat, StateHeader, STATE_VERSION,
+};
+use crate::timestamps::unix_now;
+use anyhow::{Context, Result};
+use clap::Args;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::PathBuf;
+
+#[derive(Args, Debug)]
+pub(crate) struct ImportArgs {
+    manifest: PathBuf,
+    state_file: PathBuf,
+
+    /// Algorithm the manifest digests were computed with
+    #[arg(long = "algo", value_parser = algo_parser(), default_value = "blake3")]
+    algo: Algo,
+
+    #[arg(long = "state-format", value_enum, default_value_t = StateFormat::Text)]
+    state_format: StateFormat,
+}
+
+pub(crate) fn run_import(args: &ImportArgs) -> Result<()> {
+    let file = File::open(&args.manifest)
+        .with_context(|| format!("Failed to open manifest: {:?}", args.manifest))?;
+    let entries = parse_manifest(BufReader::new(file), args.algo)
+        .with_context(|| format!("Failed to parse manifest: {:?}", args.manifest))?;
+
+    let count = entries.len();
+    let state = State {
+        header: StateHeader {
+            version: STATE_VERSION,
+            algo: Some(algo_name(args.algo).to_string()),
+            root: None,
+            scanned_at: Some(unix_now()),
+            ..Default::default()
+        },
+        entries,
+    };
+
+    let _lock = acquire_run_lock(&args.state_file)?;
+    write_state_file(&args.state_file, &state, args.state_format, None)?;
+    eprintln!("Imported {count} entries into {:?}", args.state_file);
+    Ok(())
+}
@@ -0,0 +1,89 @@
+//! `inspect`: prints a summary of a state file.
+
+use crate::output::{display_dir, format_size};
+use crate::state::{is_stdio, read_state_file, tree_hashes, Entry, EntryKind};
+use crate::timestamps::format_utc;
+use anyhow::{anyhow, Result};
+use clap::{ArgAction, Args, ValueEnum};
+use std::collections::BTreeSet;
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+#[derive(Args, Debug)]
+pub(crate) struct InspectArgs {
+    state_file: PathBuf,
+
+    /// Also print the rollup hash of every directory
+    #[arg(long = "tree", action = ArgAction::SetTrue)]
+    tree: bool,
+}
+
+pub(crate) fn run_inspect(args: &InspectArgs) -> Result<()> {
+    if !is_stdio(&args.state_file) && !args.state_file.exists() {
+        return Err(anyhow!("State file not found: {:?}", args.state_file));
+    }
+    let (state, format) = read_state_file(&args.state_file)?;
+    let header = &state.header;
+
+    let files: Vec<&Entry> = state.entries.values().filter(|e| e.kind.is_file()).collect();
+    let links = state.entries.values().filter(|e| e.kind == EntryKind::Link).count();
+    let specials = state.entries.values().filter(|e| e.kind == EntryKind::Special).count();
+    let total_size: u64 = files.iter().map(|e| e.size).sum();
+    let unhashed = files.iter().filter(|e| e.is_unhashed()).count();
+    let partial = files.iter().filter(|e| e.partial.is_some()).count() - unhashed;
+    let oldest = files.iter().map(|e| e.tstamp).filter(|&t| t > 0).min();
+    let newest = files.iter().map(|e| e.tstamp).max().filter(|&t| t > 0);
+    let show_time = |t: Option<u64>| t.map(format_utc).unwrap_or_else(|| "-".to_string());
+
+    let format_name = format.to_possible_value().map(|v| v.get_name().to_string()).unwrap_or_default();
+    let mut out = io::stdout().lock();
+    writeln!(out, "format:      {format_name} (v{})", header.version)?;
+    let also: BTreeSet<&str> = files
+        .iter()
+        .flat_map(|e| e.hashes.iter().flat_map(|h| h.keys().map(String::as_str)))
+        .collect();
+    if also.is_empty() {
+        writeln!(out, "algorithm:   {}", header.algo.as_deref().unwrap_or("-"))?;
+    } else {
+        let also: Vec<&str> = also.into_iter().collect();
+        writeln!(out, "algorithm:   {} (also: {})", header.algo.as_deref().unwrap_or("-"), also.join(", "))?;
+    }
+    writeln!(out, "root:        {}", header.root.as_deref().unwrap_or("-"))?;
+    if let Some(ref sources) = header.sources {
+        writeln!(out, "sources:     {}", sources.join(", "))?;
+    }
+    writeln!(out, "host:        {}", header.host.as_deref().unwrap_or("-"))?;
+    writeln!(out, "scanned:     {}", show_time(header.scanned_at))?;
+    match header.key_id {
+        Some(ref id) => writeln!(out, "keyed:       yes (key id {id})")?,
+        None => writeln!(out, "keyed:       no")?,
+    }
+    writeln!(out, "signed:      {}", if header.signature.is_some() { "yes" } else { "no" })?;
+    writeln!(out, "files:       {}", files.len())?;
+    writeln!(out, "directories: {}", state.entries.len() - files.len() - links - specials)?;
+    if links > 0 {
+        writeln!(out, "links:       {links}")?;
+    }
+    if specials > 0 {
+        writeln!(out, "special:     {specials}")?;
+    }
+    writeln!(out, "partial:     {partial} files hashed partially (--quick/--sample)")?;
+    if unhashed > 0 {
+        writeln!(out, "unhashed:    {unhashed} files above --hash-max-size (size and time only)")?;
+    }
+    writeln!(out, "total size:  {} ({total_size} bytes)", format_size(total_size))?;
+    writeln!(out, "oldest file: {}", show_time(oldest))?;
+    writeln!(out, "newest file: {}", show_time(newest))?;
+
+    let trees = tree_hashes(&state.entries);
+    writeln!(out, "tree hash:   {}", trees[""])?;
+    if header.tree.as_ref().is_some_and(|t| *t != trees[""]) {
+        eprintln!("Warning: the recorded tree hash does not match the entries");
+    }
+    if args.tree {
+        for (dir, hash) in &trees {
+            writeln!(out, "{hash}  {}", display_dir(dir))?;
+        }
+    }
+    Ok(())
+}
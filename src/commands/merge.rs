@@ -0,0 +1,135 @@
+//! `merge`: combines several state files into one.
+
+use crate::state::{
+    acquire_run_lock, read_state_file, tree_hashes, write_state_file, State, StateFormat,
+    STATE_VERSION,
+};
+use anyhow::{anyhow, Result};
+use clap::{Args, ValueEnum};
+use std::collections::HashSet;
+use std::path::PathBuf;
+
+#[derive(Args, Debug)]
+pub(crate) struct MergeArgs {
+    /// State files to merge
+    #[arg(required = true)]
+    inputs: Vec<PathBuf>,
+
+    /// State file to write
+    #[arg(short = 'o', long = "output", value_name = "STATE_FILE")]
+    output: PathBuf,
+
+    /// Prefix the paths of the corresponding input (in order) with `<PREFIX>/`
+    #[arg(long = "prefix", value_name = "PREFIX")]
+    prefixes: Vec<String>,
+
+    /// What to do when several inputs contain the same path
+    #[arg(long = "on-conflict", value_enum, default_value_t = ConflictRule::Error)]
+    on_conflict: ConflictRule,
+
+    #[arg(long = "state-format", value_enum, default_value_t = StateFormat::Text)]
+    state_format: StateFormat,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum ConflictRule {
+    /// Fail the merge
+    Error,
+    /// Keep the entry from the first input that has the path
+    First,
+    /// Keep the entry from the last input that has the path
+    Last,
+    /// Keep the most recently modified entry
+    Newest,
+}
+
+pub(crate) fn run_merge(args: &MergeArgs) -> Result<()> {
+    if !args.prefixes.is_empty() && args.prefixes.len() != args.inputs.len() {
+        return Err(anyhow!(
+            "Got {} --prefix values for {} inputs; give one per input or none.",
+            args.prefixes.len(),
+            args.inputs.len()
+        ));
+    }
+
+    let mut merged = State::default();
+    let mut roots = HashSet::new();
+    let mut sources = HashSet::new();
+    let mut hosts = HashSet::new();
+    for (i, input) in args.inputs.iter().enumerate() {
+        let (state, _) = read_state_file(input)?;
+        let header = state.header;
+
+        // Digests are only comparable when computed the same way.
+        if i == 0 {
+            merged.header.algo = header.algo.clone();
+            merged.header.key_id = header.key_id.clone();
+            merged.header.chunk_size = header.chunk_size;
+        } else if header.algo != merged.header.algo || header.key_id != merged.header.key_id {
+            return Err(anyhow!(
+                "Cannot merge {input:?}: hashed with a different algorithm or key than {:?}",
+                args.inputs[0]
+            ));
+        } else if header.chunk_size != merged.header.chunk_size {
+            // Chunks of different sizes cannot be compared with each other.
+            merged.header.chunk_size = None;
+        }
+        roots.insert(header.root);
+        sources.insert(header.sources);
+        hosts.insert(header.host);
+        // The merged state is only as fresh as its oldest shard.
+        merged.header.scanned_at = match (merged.header.scanned_at, header.scanned_at) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+
+        let prefix = args.prefixes.get(i).map(|p| p.trim_matches('/')).unwrap_or("");
+        for (path, mut entry) in state.entries {
+            let path = if prefix.is_empty() {
+                path
+            } else {
+                format!("{prefix}/{path}")
+            };
+            entry.rel_path = path.clone();
+
+            let Some(existing) = merged.entries.get(&path) else {
+                merged.entries.insert(path, entry);
+                continue;
+            };
+            let replace = match args.on_conflict {
+                ConflictRule::Error => {
+                    return Err(anyhow!(
+                        "Path {path:?} from {input:?} is already present in an earlier input \
+                         (use --prefix or --on-conflict)"
+                    ));
+                }
+                ConflictRule::First => false,
+                ConflictRule::Last => true,
+                ConflictRule::Newest => {
+                    (entry.mtime_ns, entry.tstamp) > (existing.mtime_ns, existing.tstamp)
+                }
+            };
+            if replace {
+                merged.entries.insert(path, entry);
+            }
+        }
+    }
+    merged.header.version = STATE_VERSION;
+    merged.header.tree = tree_hashes(&merged.entries).remove("");
+    // A common root only makes sense for shards of the same directory.
+    if roots.len() == 1 {
+        merged.header.root = roots.into_iter().next().flatten();
+        if sources.len() == 1 && args.prefixes.is_empty() {
+            merged.header.sources = sources.into_iter().next().flatten();
+        }
+    }
+    if hosts.len() == 1 {
+        merged.header.host = hosts.into_iter().next().flatten();
+    }
+
+    let count = merged.entries.len();
+    let _lock = acquire_run_lock(&args.output)?;
+    write_state_file(&args.output, &merged, args.state_format, None)?;
+    eprintln!("Merged {} state files ({count} entries) into {:?}", args.inputs.len(), args.output);
+    Ok(())
+}
@@ -0,0 +1,67 @@
+//! `migrate`: rewrites a state file in another format or version.
+
+use crate::cli::algo_parser;
+use crate::hash::{algo_name, Algo};
+use crate::state::{
+    acquire_run_lock, is_stdio, read_state_file, write_state_file, DigestEncoding, StateFormat,
+    STATE_VERSION,
+};
+use anyhow::{anyhow, Result};
+use clap::Args;
+use std::path::PathBuf;
+
+#[derive(Args, Debug)]
+pub(crate) struct MigrateArgs {
+    state_file: PathBuf,
+
+    /// Write the migrated state here instead of replacing STATE_FILE
+    #[arg(short = 'o', long = "output", value_name = "FILE")]
+    output: Option<PathBuf>,
+
+    /// Algorithm of the digests, for legacy states where it cannot be inferred
+    #[arg(long = "algo", value_parser = algo_parser())]
+    algo: Option<Algo>,
+
+    /// Format to write (default: keep the format, text for legacy states)
+    #[arg(long = "state-format", value_enum)]
+    state_format: Option<StateFormat>,
+
+    /// Digest encoding to write (default: keep the current one)
+    #[arg(long = "digest-encoding", value_enum)]
+    digest_encoding: Option<DigestEncoding>,
+}
+
+pub(crate) fn run_migrate(args: &MigrateArgs) -> Result<()> {
+    let output = args.output.as_ref().unwrap_or(&args.state_file);
+    let _lock = acquire_run_lock(&args.state_file)?;
+    if !is_stdio(&args.state_file) && !args.state_file.exists() {
+        return Err(anyhow!("State file not found: {:?}", args.state_file));
+    }
+    let (mut state, format) = read_state_file(&args.state_file)?;
+    let from_version = state.header.version;
+
+    if let Some(algo) = args.algo {
+        let recorded = state.header.algo.as_deref().unwrap_or(algo_name(algo));
+        if recorded != algo_name(algo) {
+            return Err(anyhow!("State was hashed with {recorded}, not {}", algo_name(algo)));
+        }
+        state.header.algo = Some(algo_name(algo).to_string());
+    } else if state.header.algo.is_none() {
+        eprintln!("Warning: could not infer the hash algorithm; pass --algo to record it");
+    }
+    state.header.version = STATE_VERSION;
+    if let Some(encoding) = args.digest_encoding {
+        state.header.digests = encoding;
+    }
+
+    let state_format = args.state_format.unwrap_or(format);
+    // SQLite is only updated in place when rewriting the same database.
+    let previous = (format == StateFormat::Sqlite && *output == args.state_file).then_some(&state.entries);
+    write_state_file(output, &state, state_format, previous)?;
+    eprintln!(
+        "Migrated {:?} (v{from_version}, {} entries) to {output:?}",
+        args.state_file,
+        state.entries.len()
+    );
+    Ok(())
+}
@@ -0,0 +1,69 @@
+//! `prune`: removes old snapshots from a history store.
+
+use crate::snapshot::{list_snapshots, snapshot_path};
+use anyhow::{Context, Result};
+use clap::{ArgAction, Args};
+use std::collections::HashSet;
+use std::fs::{self};
+use std::path::PathBuf;
+
+#[derive(Args, Debug)]
+#[command(group = clap::ArgGroup::new("policy").required(true).multiple(true))]
+pub(crate) struct PruneArgs {
+    /// History store directory
+    store: PathBuf,
+
+    /// Keep the N most recent snapshots
+    #[arg(long = "keep-last", value_name = "N", group = "policy")]
+    keep_last: Option<usize>,
+
+    /// Keep the newest snapshot of each of the last N days that have one
+    #[arg(long = "keep-daily", value_name = "N", group = "policy")]
+    keep_daily: Option<usize>,
+
+    /// Keep the newest snapshot of each of the last N months that have one
+    #[arg(long = "keep-monthly", value_name = "N", group = "policy")]
+    keep_monthly: Option<usize>,
+
+    /// Only print the snapshots that would be deleted
+    #[arg(long = "dry-run", action = ArgAction::SetTrue)]
+    dry_run: bool,
+}
+
+pub(crate) fn run_prune(args: &PruneArgs) -> Result<()> {
+    let ids = list_snapshots(&args.store)?;
+
+    // Snapshots kept by any rule survive; ids start with `YYYYMMDD`, so prefixes give
+    // the day (8 chars) and month (6 chars) of each snapshot.
+    let mut keep: HashSet<&str> = HashSet::new();
+    if let Some(n) = args.keep_last {
+        keep.extend(ids.iter().rev().take(n).map(String::as_str));
+    }
+    for (n, period_len) in [(args.keep_daily, 8), (args.keep_monthly, 6)] {
+        let Some(n) = n else { continue };
+        let mut periods = HashSet::new();
+        for id in ids.iter().rev() {
+            let period = id.get(..period_len).unwrap_or(id);
+            if periods.len() == n && !periods.contains(period) {
+                break;
+            }
+            if periods.insert(period) {
+                keep.insert(id);
+            }
+        }
+    }
+
+    let mut removed = 0;
+    for id in ids.iter().filter(|id| !keep.contains(id.as_str())) {
+        if args.dry_run {
+            println!("would remove {id}");
+        } else {
+            let path = snapshot_path(&args.store, id);
+            fs::remove_file(&path).with_context(|| format!("Failed to remove snapshot {path:?}"))?;
+            println!("removed {id}");
+        }
+        removed += 1;
+    }
+    eprintln!("Kept {} of {} snapshots", ids.len() - removed, ids.len());
+    Ok(())
+}
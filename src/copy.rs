@@ -0,0 +1,322 @@
+//! Copying files into the target: permissions, owner, extended attributes and ACLs, sparse files.
+
+use crate::hash::RateLimit;
+use crate::scan::{file_id, file_owner};
+use crate::sync::{Preserve, SyncOptions};
+use anyhow::{Context, Result};
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, Write};
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+/// Copies `src` to a temporary file next to `dst` and renames it over `dst`, so readers of
+/// the target never see a partially written file.
+pub(crate) fn copy_with_permissions(src: &Path, dst: &Path, opts: &SyncOptions) -> Result<()> {
+    let tmp = temp_path(dst);
+    let result = copy_file_to(src, &tmp, opts)
+        .and_then(|()| fs::rename(&tmp, dst).with_context(|| format!("Failed to replace {dst:?}")));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    result
+}
+
+fn copy_file_to(src: &Path, dst: &Path, opts: &SyncOptions) -> Result<()> {
+    let preserve = &opts.preserve;
+    let src_md = fs::metadata(src)
+        .with_context(|| format!("Failed to read source metadata: {src:?}"))?;
+    let limit = opts.bwlimit.as_ref();
+    if is_sparse(&src_md) {
+        copy_sparse(src, dst, src_md.len(), limit)
+    } else if limit.is_some() {
+        File::open(src).and_then(|mut input| copy_data(&mut input, &mut File::create(dst)?, src_md.len(), limit))
+    } else {
+        fs::copy(src, dst).map(|_| ())
+    }
+    .with_context(|| format!("Failed copying '{src:?}' -> '{dst:?}'"))?;
+
+    // Before the mode, as changing the owner clears the set-user-ID and set-group-ID bits.
+    if preserve.owner {
+        if let Some((uid, gid)) = file_owner(&src_md) {
+            set_owner(dst, uid, gid)?;
+        }
+    }
+    apply_permissions(&src_md.permissions(), dst)?;
+    copy_xattrs_and_acls(src, dst, preserve)?;
+
+    let mtime = filetime::FileTime::from_last_modification_time(&src_md);
+    let atime = filetime::FileTime::from_last_access_time(&src_md);
+
+    filetime::set_file_times(dst, atime, mtime)
+        .with_context(|| format!("Failed to apply timestamps to: {dst:?}"))?;
+
+    Ok(())
+}
+
+#[cfg(unix)]
+pub(crate) fn set_owner(path: &Path, uid: u32, gid: u32) -> Result<()> {
+    std::os::unix::fs::chown(path, Some(uid), Some(gid))
+        .with_context(|| format!("Failed to set owner {uid}:{gid} on {path:?} (this needs root or CAP_CHOWN)"))
+}
+
+#[cfg(not(unix))]
+pub(crate) fn set_owner(_path: &Path, _uid: u32, _gid: u32) -> Result<()> {
+    Ok(())
+}
+
+/// Copies the extended attributes of `src` to `dst`: POSIX ACLs (`system.posix_acl_*`) with
+/// `--preserve-acls`, the others with `--preserve-xattrs`. Those of `dst` that `src` does not
+/// have (such as an ACL inherited from its directory) are removed.
+#[cfg(unix)]
+pub(crate) fn copy_xattrs_and_acls(src: &Path, dst: &Path, preserve: &Preserve) -> Result<()> {
+    if !preserve.xattrs && !preserve.acls {
+        return Ok(());
+    }
+    let wanted = |name: &std::ffi::OsStr| {
+        if name.to_string_lossy().starts_with("system.posix_acl_") {
+            preserve.acls && cfg!(target_os = "linux")
+        } else {
+            preserve.xattrs
+        }
+    };
+    let names: Vec<_> = xattr::list(src)
+        .with_context(|| format!("Failed to list extended attributes of {src:?}"))?
+        .filter(|name| wanted(name))
+        .collect();
+    let existing = xattr::list(dst).with_context(|| format!("Failed to list extended attributes of {dst:?}"))?;
+    for name in existing.filter(|name| wanted(name) && !names.contains(name)) {
+        xattr::remove(dst, &name)
+            .with_context(|| format!("Failed to remove extended attribute {name:?} of {dst:?}"))?;
+    }
+    for name in &names {
+        let value = xattr::get(src, name)
+            .with_context(|| format!("Failed to read extended attribute {name:?} of {src:?}"))?;
+        if let Some(value) = value {
+            xattr::set(dst, name, &value)
+                .with_context(|| format!("Failed to set extended attribute {name:?} on {dst:?}"))?;
+        }
+    }
+    Ok(())
+}
+
+/// Copies the DACL of `src` (and whether it inherits entries from its directory) to `dst`
+/// with `--preserve-acls`. Windows files have no other extended attributes to copy.
+#[cfg(windows)]
+pub(crate) fn copy_xattrs_and_acls(src: &Path, dst: &Path, preserve: &Preserve) -> Result<()> {
+    use std::os::windows::ffi::OsStrExt;
+    use windows_sys::Win32::Foundation::{LocalFree, ERROR_SUCCESS};
+    use windows_sys::Win32::Security::Authorization::{GetNamedSecurityInfoW, SetNamedSecurityInfoW, SE_FILE_OBJECT};
+    use windows_sys::Win32::Security::{
+        GetSecurityDescriptorControl, ACL, DACL_SECURITY_INFORMATION, PROTECTED_DACL_SECURITY_INFORMATION,
+        PSECURITY_DESCRIPTOR, SE_DACL_PROTECTED, UNPROTECTED_DACL_SECURITY_INFORMATION,
+    };
+
+    if !preserve.acls {
+        return Ok(());
+    }
+    let wide = |p: &Path| -> Vec<u16> { p.as_os_str().encode_wide().chain(Some(0)).collect() };
+    let (src_w, dst_w) = (wide(src), wide(dst));
+    let mut dacl: *mut ACL = std::ptr::null_mut();
+    let mut descriptor: PSECURITY_DESCRIPTOR = std::ptr::null_mut();
+    // SAFETY: the out pointers are valid; `dacl` points into `descriptor`, freed below.
+    let err = unsafe {
+        GetNamedSecurityInfoW(
+            src_w.as_ptr(),
+            SE_FILE_OBJECT,
+            DACL_SECURITY_INFORMATION,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+            &mut dacl,
+            std::ptr::null_mut(),
+            &mut descriptor,
+        )
+    };
+    if err != ERROR_SUCCESS {
+        let err = io::Error::from_raw_os_error(err as i32);
+        return Err(anyhow!("Failed to read the ACL of {src:?}: {err}"));
+    }
+    let (mut control, mut revision) = (0, 0);
+    // SAFETY: `descriptor` was returned by GetNamedSecurityInfoW.
+    unsafe { GetSecurityDescriptorControl(descriptor, &mut control, &mut revision) };
+    let inheritance = if control & SE_DACL_PROTECTED != 0 {
+        PROTECTED_DACL_SECURITY_INFORMATION
+    } else {
+        UNPROTECTED_DACL_SECURITY_INFORMATION
+    };
+    // SAFETY: `dacl` is valid as long as `descriptor` is.
+    let err = unsafe {
+        SetNamedSecurityInfoW(
+            dst_w.as_ptr(),
+            SE_FILE_OBJECT,
+            DACL_SECURITY_INFORMATION | inheritance,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+            dacl,
+            std::ptr::null(),
+        )
+    };
+    // SAFETY: allocated by GetNamedSecurityInfoW, and no longer used.
+    unsafe { LocalFree(descriptor) };
+    if err != ERROR_SUCCESS {
+        let err = io::Error::from_raw_os_error(err as i32);
+        return Err(anyhow!("Failed to set the ACL of {dst:?}: {err}"));
+    }
+    Ok(())
+}
+
+#[cfg(not(any(unix, windows)))]
+pub(crate) fn copy_xattrs_and_acls(_src: &Path, _dst: &Path, _preserve: &Preserve) -> Result<()> {
+    Ok(())
+}
+
+/// Whether `path` is a directory, and not a link to one.
+pub(crate) fn is_real_dir(path: &Path) -> bool {
+    fs::symlink_metadata(path).is_ok_and(|m| m.is_dir())
+}
+
+/// Replaces `path` with a hard link to `original`, through a temporary name so that `path`
+/// is never missing.
+pub(crate) fn replace_with_hard_link(original: &Path, path: &Path) -> Result<()> {
+    // Renaming a link over another link to the same file does nothing.
+    if file_id(path).is_some() && file_id(path) == file_id(original) {
+        return Ok(());
+    }
+    let tmp = temp_path(path);
+    fs::hard_link(original, &tmp).with_context(|| format!("Failed to link {original:?} to {tmp:?}"))?;
+    fs::rename(&tmp, path).with_context(|| {
+        let _ = fs::remove_file(&tmp);
+        format!("Failed to replace {path:?}")
+    })
+}
+
+/// Creates a symbolic link to `to` next to `path` and renames it over `path`.
+pub(crate) fn replace_with_symlink(to: &Path, to_dir: bool, path: &Path) -> Result<()> {
+    let tmp = temp_path(path);
+    let _ = fs::remove_file(&tmp);
+    #[cfg(unix)]
+    let created = {
+        let _ = to_dir;
+        std::os::unix::fs::symlink(to, &tmp)
+    };
+    #[cfg(windows)]
+    let created = if to_dir {
+        std::os::windows::fs::symlink_dir(to, &tmp)
+    } else {
+        std::os::windows::fs::symlink_file(to, &tmp)
+    };
+    created.with_context(|| format!("Failed to create link {tmp:?} -> {to:?}"))?;
+    fs::rename(&tmp, path).with_context(|| {
+        let _ = fs::remove_file(&tmp);
+        format!("Failed to replace {path:?}")
+    })
+}
+
+/// Whether the file has holes: fewer blocks than its size takes.
+#[cfg(unix)]
+fn is_sparse(meta: &fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    meta.blocks().saturating_mul(512) < meta.len()
+}
+
+#[cfg(not(unix))]
+fn is_sparse(_meta: &fs::Metadata) -> bool {
+    false
+}
+
+/// Copies the data regions of `src` to `dst` (`len` bytes long), so the holes between them
+/// stay holes instead of being written as zeros.
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "freebsd"))]
+fn copy_sparse(src: &Path, dst: &Path, len: u64, limit: Option<&RateLimit>) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    let mut input = File::open(src)?;
+    let mut output = File::create(dst)?;
+    output.set_len(len)?;
+    let fd = input.as_raw_fd();
+    let mut pos = 0;
+    while pos < len {
+        // SAFETY: plain syscalls on a descriptor owned by `input`.
+        let data = unsafe { libc::lseek(fd, pos as libc::off_t, libc::SEEK_DATA) };
+        if data < 0 {
+            let err = io::Error::last_os_error();
+            // No data after `pos`: the rest is a hole.
+            if err.raw_os_error() == Some(libc::ENXIO) {
+                break;
+            }
+            return Err(err);
+        }
+        // SAFETY: as above.
+        let hole = unsafe { libc::lseek(fd, data, libc::SEEK_HOLE) };
+        if hole < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let (data, hole) = (data as u64, (hole as u64).min(len));
+        input.seek(io::SeekFrom::Start(data))?;
+        output.seek(io::SeekFrom::Start(data))?;
+        copy_data(&mut input, &mut output, hole - data, limit)?;
+        pos = hole;
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "freebsd")))]
+fn copy_sparse(src: &Path, dst: &Path, len: u64, limit: Option<&RateLimit>) -> io::Result<()> {
+    match limit {
+        Some(_) => copy_data(&mut File::open(src)?, &mut File::create(dst)?, len, limit),
+        None => fs::copy(src, dst).map(|_| ()),
+    }
+}
+
+/// Copies up to `len` bytes from the current position of `input` to that of `output`, at most
+/// as fast as `limit` allows (`--copy-bwlimit`).
+fn copy_data(input: &mut File, output: &mut File, len: u64, limit: Option<&RateLimit>) -> io::Result<()> {
+    let Some(limit) = limit else {
+        io::copy(&mut input.take(len), output)?;
+        return Ok(());
+    };
+    let mut buf = vec![0u8; COPY_CHUNK];
+    let mut remaining = len;
+    while remaining > 0 {
+        let want = remaining.min(buf.len() as u64) as usize;
+        let n = input.read(&mut buf[..want])?;
+        if n == 0 {
+            break;
+        }
+        limit.consume(n as u64);
+        output.write_all(&buf[..n])?;
+        remaining -= n as u64;
+    }
+    Ok(())
+}
+
+/// Size of the writes throttled by `--copy-bwlimit`.
+const COPY_CHUNK: usize = 256 * 1024;
+
+/// Where a file is written before being renamed over `path`.
+pub(crate) fn temp_path(path: &Path) -> PathBuf {
+    let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    path.with_file_name(format!(".{name}.fhi-tmp"))
+}
+
+pub(crate) fn apply_permissions(src_perm: &fs::Permissions, dst: &Path) -> Result<()> {
+    #[cfg(unix)]
+    {
+        let mode = PermissionsExt::mode(src_perm);
+        let dst_perm = std::fs::Permissions::from_mode(mode);
+        fs::set_permissions(dst, dst_perm)
+            .with_context(|| format!("Failed to apply permissions (mode {mode:o}) to: {dst:?}"))?;
+    }
+
+    #[cfg(windows)]
+    {
+        let readonly = src_perm.readonly();
+        let mut dst_perm = fs::metadata(dst)
+            .with_context(|| format!("Failed to read target metadata: {dst:?}"))?
+            .permissions();
+        dst_perm.set_readonly(readonly);
+        fs::set_permissions(dst, dst_perm)
+            .with_context(|| format!("Failed to apply permissions (readonly={readonly}) to: {dst:?}"))?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,550 @@
+//! Comparing two sets of entries into [`Change`]s, and the checks run on them.
+
+use crate::state::{is_stdio, read_state_file, Entry, StateHeader};
+use crate::walk::disk_path;
+use anyhow::{anyhow, Result};
+use clap::ValueEnum;
+use rayon::prelude::*;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+/// Kind of a [`Change`], as named by `--only`/`--ignore` and the JUnit and webhook outputs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub(crate) enum ChangeKind {
+    Added,
+    Updated,
+    SizeAnomaly,
+    Permissions,
+    Owner,
+    Xattrs,
+    Metadata,
+    Touched,
+    Deleted,
+}
+
+impl ChangeKind {
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            ChangeKind::Added => "added",
+            ChangeKind::Updated => "updated",
+            ChangeKind::SizeAnomaly => "size-anomaly",
+            ChangeKind::Permissions => "permissions",
+            ChangeKind::Owner => "owner",
+            ChangeKind::Xattrs => "xattrs",
+            ChangeKind::Metadata => "metadata",
+            ChangeKind::Touched => "touched",
+            ChangeKind::Deleted => "deleted",
+        }
+    }
+
+    /// `low` for touched files (worth knowing, e.g. a touched binary during an intrusion, but
+    /// no change of content or access), `high` for size anomalies, `normal` otherwise.
+    pub(crate) fn severity(self) -> &'static str {
+        match self {
+            ChangeKind::Touched => "low",
+            ChangeKind::SizeAnomaly => "high",
+            _ => "normal",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum Change {
+    Added(String),
+    Updated(String),
+    /// Different content and size, but the same modification time: the file was rewritten
+    /// and its timestamp preserved or reset.
+    SizeAnomaly(String),
+    /// Same content, different permission bits.
+    Permissions(String),
+    /// Same content, different owner or group (`--owner`).
+    Owner(String),
+    /// Same content, different extended attributes (`--xattrs`).
+    Xattrs(String),
+    /// Same content, different metadata digest (`--meta-hash`) that none of the above explains.
+    Metadata(String),
+    /// Same content, different modification time.
+    Touched(String),
+    Deleted(String),
+}
+
+impl Change {
+    pub(crate) fn path(&self) -> &str {
+        match self {
+            Change::Added(p)
+            | Change::Updated(p)
+            | Change::SizeAnomaly(p)
+            | Change::Permissions(p)
+            | Change::Owner(p)
+            | Change::Xattrs(p)
+            | Change::Metadata(p)
+            | Change::Touched(p)
+            | Change::Deleted(p) => p,
+        }
+    }
+
+    /// Single-letter status used by the text and porcelain outputs.
+    pub(crate) fn tag(&self) -> char {
+        match self {
+            Change::Added(_) => 'A',
+            Change::Updated(_) => 'U',
+            Change::SizeAnomaly(_) => 'S',
+            Change::Permissions(_) => 'P',
+            Change::Owner(_) => 'O',
+            Change::Xattrs(_) => 'X',
+            Change::Metadata(_) => 'M',
+            Change::Touched(_) => 'T',
+            Change::Deleted(_) => 'D',
+        }
+    }
+
+    pub(crate) fn kind(&self) -> ChangeKind {
+        match self {
+            Change::Added(_) => ChangeKind::Added,
+            Change::Updated(_) => ChangeKind::Updated,
+            Change::SizeAnomaly(_) => ChangeKind::SizeAnomaly,
+            Change::Permissions(_) => ChangeKind::Permissions,
+            Change::Owner(_) => ChangeKind::Owner,
+            Change::Xattrs(_) => ChangeKind::Xattrs,
+            Change::Metadata(_) => ChangeKind::Metadata,
+            Change::Touched(_) => ChangeKind::Touched,
+            Change::Deleted(_) => ChangeKind::Deleted,
+        }
+    }
+
+    fn order(&self) -> u8 {
+        match self {
+            Change::Added(_) => 0,
+            Change::Updated(_) => 1,
+            Change::SizeAnomaly(_) => 2,
+            Change::Permissions(_) => 3,
+            Change::Owner(_) => 4,
+            Change::Xattrs(_) => 5,
+            Change::Metadata(_) => 6,
+            Change::Touched(_) => 7,
+            Change::Deleted(_) => 8,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct ChangeCounts {
+    pub(crate) added: usize,
+    pub(crate) updated: usize,
+    pub(crate) size_anomaly: usize,
+    pub(crate) permissions: usize,
+    pub(crate) owner: usize,
+    pub(crate) xattrs: usize,
+    pub(crate) metadata: usize,
+    pub(crate) touched: usize,
+    pub(crate) deleted: usize,
+}
+
+impl ChangeCounts {
+    pub(crate) fn record(&mut self, change: &Change) {
+        match change {
+            Change::Added(_) => self.added += 1,
+            Change::Updated(_) => self.updated += 1,
+            Change::SizeAnomaly(_) => self.size_anomaly += 1,
+            Change::Permissions(_) => self.permissions += 1,
+            Change::Owner(_) => self.owner += 1,
+            Change::Xattrs(_) => self.xattrs += 1,
+            Change::Metadata(_) => self.metadata += 1,
+            Change::Touched(_) => self.touched += 1,
+            Change::Deleted(_) => self.deleted += 1,
+        }
+    }
+
+    pub(crate) fn total(&self) -> usize {
+        self.added
+            + self.updated
+            + self.size_anomaly
+            + self.permissions
+            + self.owner
+            + self.xattrs
+            + self.metadata
+            + self.touched
+            + self.deleted
+    }
+}
+
+impl std::fmt::Display for ChangeCounts {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "A: {}, U: {}, S: {}, P: {}, O: {}, X: {}, M: {}, T: {}, D: {}",
+            self.added,
+            self.updated,
+            self.size_anomaly,
+            self.permissions,
+            self.owner,
+            self.xattrs,
+            self.metadata,
+            self.touched,
+            self.deleted
+        )
+    }
+}
+
+/// With `--case-insensitive-paths`: re-keys the previous entries whose path only differs in
+/// case from a new one (that has no previous entry of its own), so they are compared instead
+/// of reported as deleted and added. Returns these case-only renames as (old, new) paths.
+pub(crate) fn fold_case_renames(old: &mut HashMap<String, Entry>, new: &HashMap<String, Entry>) -> Vec<(String, String)> {
+    let mut by_folded: HashMap<String, Vec<&String>> = HashMap::new();
+    for rel in new.keys() {
+        by_folded.entry(rel.to_lowercase()).or_default().push(rel);
+    }
+    let mut clashes: Vec<Vec<&String>> = by_folded.values().filter(|rels| rels.len() > 1).cloned().collect();
+    clashes.sort();
+    for mut rels in clashes {
+        rels.sort();
+        let rels: Vec<&str> = rels.iter().map(|r| r.as_str()).collect();
+        eprintln!("Warning: {} only differ in case; they cannot coexist on a case-insensitive file system", rels.join(" and "));
+    }
+
+    let mut gone: Vec<String> = old.keys().filter(|rel| !new.contains_key(*rel)).cloned().collect();
+    gone.sort();
+    let mut renames = Vec::new();
+    for rel in gone {
+        let Some(&[to]) = by_folded.get(&rel.to_lowercase()).map(Vec::as_slice) else {
+            continue;
+        };
+        if old.contains_key(to) {
+            continue;
+        }
+        if let Some(mut e) = old.remove(&rel) {
+            e.rel_path = to.clone();
+            old.insert(to.clone(), e);
+            renames.push((rel, to.clone()));
+        }
+    }
+    renames
+}
+
+/// Legacy (v1) states were recorded with `\` in file names turned into `/`, which later
+/// versions keep on Unix. Re-keys the previous entries whose path is a new one so spelled
+/// (that is not itself a previous or new path), so these files are compared instead of
+/// reported as deleted and added. Returns how many were re-keyed.
+pub(crate) fn fold_v1_backslashes(old: &mut HashMap<String, Entry>, new: &HashMap<String, Entry>) -> usize {
+    let mut folded = 0;
+    for rel in new.keys().filter(|rel| rel.contains('\\')) {
+        if old.contains_key(rel) {
+            continue;
+        }
+        let spelled = rel.replace('\\', "/");
+        if new.contains_key(&spelled) {
+            continue;
+        }
+        if let Some(mut e) = old.remove(&spelled) {
+            e.rel_path = rel.clone();
+            old.insert(rel.clone(), e);
+            folded += 1;
+        }
+    }
+    folded
+}
+
+pub(crate) fn report_case_renames(renames: &[(String, String)]) {
+    for (from, to) in renames {
+        eprintln!("Notice: {from} is now {to} (case only); compared as the same file");
+    }
+}
+
+pub(crate) fn diff_maps(old: &HashMap<String, Entry>, new: &HashMap<String, Entry>) -> Vec<Change> {
+    let mut changes = Vec::new();
+
+    for (path, e_new) in new {
+        match old.get(path) {
+            None => changes.push(Change::Added(path.clone())),
+            Some(e_old) => {
+                // Partial digests (`--quick`, `--sample`) never vouch for a full one or vice versa.
+                let updated = if e_old.is_unhashed() && e_new.is_unhashed() {
+                    (e_old.size, e_old.mtime_ns) != (e_new.size, e_new.mtime_ns)
+                } else {
+                    e_old.hash_hex != e_new.hash_hex
+                        || e_old.partial != e_new.partial
+                        || e_old.kind != e_new.kind
+                        || e_old.target != e_new.target
+                        || e_old.special != e_new.special
+                };
+                if updated {
+                    let same_mtime = e_old.mtime_ns.is_some() && e_old.mtime_ns == e_new.mtime_ns;
+                    if same_mtime && e_old.size != e_new.size && e_old.kind == e_new.kind {
+                        changes.push(Change::SizeAnomaly(path.clone()));
+                    } else {
+                        changes.push(Change::Updated(path.clone()));
+                    }
+                    continue;
+                }
+                // Metadata is only compared when recorded on both sides.
+                let before = changes.len();
+                if e_old.mode.is_some() && e_new.mode.is_some() && e_old.mode != e_new.mode {
+                    changes.push(Change::Permissions(path.clone()));
+                }
+                let owned = |e: &Entry| e.uid.is_some() || e.gid.is_some();
+                if owned(e_old) && owned(e_new) && (e_old.uid, e_old.gid) != (e_new.uid, e_new.gid) {
+                    changes.push(Change::Owner(path.clone()));
+                }
+                if let (Some(a), Some(b)) = (&e_old.xattrs, &e_new.xattrs) {
+                    if a != b {
+                        changes.push(Change::Xattrs(path.clone()));
+                    }
+                }
+                if let (Some(a), Some(b)) = (&e_old.meta, &e_new.meta) {
+                    if a != b && changes.len() == before {
+                        changes.push(Change::Metadata(path.clone()));
+                    }
+                }
+                if e_old.mtime_ns.is_some() && e_new.mtime_ns.is_some() && e_old.mtime_ns != e_new.mtime_ns {
+                    changes.push(Change::Touched(path.clone()));
+                }
+            }
+        }
+    }
+    for path in old.keys() {
+        if !new.contains_key(path) {
+            changes.push(Change::Deleted(path.clone()));
+        }
+    }
+
+    changes.sort_by(|a, b| (a.order(), a.path()).cmp(&(b.order(), b.path())));
+
+    changes
+}
+
+/// Keeps the changes of the kinds in `only` (all if empty) and not in `ignore`.
+pub(crate) fn filter_changes(mut changes: Vec<Change>, only: &[ChangeKind], ignore: &[ChangeKind]) -> Vec<Change> {
+    changes.retain(|c| (only.is_empty() || only.contains(&c.kind())) && !ignore.contains(&c.kind()));
+    changes
+}
+
+/// The `--max-changes`/`--max-deleted` guard, checked before anything is written: a source
+/// that looks emptied (e.g. an unmounted volume) must not be recorded or mirrored.
+pub(crate) fn check_change_limits(changes: &[Change], max_changes: Option<usize>, max_deleted: Option<usize>) -> Result<()> {
+    if let Some(max) = max_changes {
+        if changes.len() > max {
+            return Err(anyhow!(
+                "{} changes exceed --max-changes {max}; the state was not written and the target not synced",
+                changes.len()
+            ));
+        }
+    }
+    if let Some(max) = max_deleted {
+        let deleted = changes.iter().filter(|c| matches!(c, Change::Deleted(_))).count();
+        if deleted > max {
+            return Err(anyhow!(
+                "{deleted} deleted files exceed --max-deleted {max}; the state was not written and the target not synced"
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Three-way check for `--target-state`: the previous state is the common base, so a content
+/// change about to be synced conflicts when the target changed the same path since then
+/// (unless both ended up with the same content).
+pub(crate) fn check_conflicts(
+    path: &Path,
+    target: &Path,
+    base_header: &StateHeader,
+    base: &HashMap<String, Entry>,
+    new: &HashMap<String, Entry>,
+    changes: &[Change],
+) -> Result<()> {
+    if !is_stdio(path) && !path.exists() {
+        return Err(anyhow!("Target state file not found: {path:?}"));
+    }
+    let (theirs, _) = read_state_file(path)?;
+    if !base.is_empty() && (theirs.header.algo != base_header.algo || theirs.header.key_id != base_header.key_id) {
+        return Err(anyhow!(
+            "Target state {path:?} was hashed differently from the previous state; index the target with the same --algo and --hash-key"
+        ));
+    }
+    if let Some(ref root) = theirs.header.root {
+        if Path::new(root) != fs::canonicalize(target).unwrap_or_else(|_| target.to_path_buf()) {
+            eprintln!("Warning: target state {path:?} was recorded for {root}, not {target:?}");
+        }
+    }
+
+    let content = |c: &&Change| {
+        matches!(c, Change::Added(_) | Change::Updated(_) | Change::SizeAnomaly(_) | Change::Deleted(_))
+    };
+    let changed_in_target: HashSet<String> = diff_maps(base, &theirs.entries)
+        .iter()
+        .filter(content)
+        .map(|c| c.path().to_string())
+        .collect();
+    let same = |a: Option<&Entry>, b: Option<&Entry>| match (a, b) {
+        (None, None) => true,
+        (Some(a), Some(b)) => {
+            !a.is_unhashed()
+                && (&a.hash_hex, &a.partial, a.kind, &a.target, &a.special)
+                    == (&b.hash_hex, &b.partial, b.kind, &b.target, &b.special)
+        }
+        _ => false,
+    };
+    let conflicts: Vec<&str> = changes
+        .iter()
+        .filter(content)
+        .map(Change::path)
+        .filter(|rel| changed_in_target.contains(*rel) && !same(new.get(*rel), theirs.entries.get(*rel)))
+        .collect();
+    for rel in &conflicts {
+        eprintln!("Warning: {rel} changed in both the source and the target since the previous run");
+    }
+    if !conflicts.is_empty() {
+        return Err(anyhow!(
+            "{} conflicts with changes in the target; the state was not written and the target not synced",
+            conflicts.len()
+        ));
+    }
+    Ok(())
+}
+
+/// Added files at least this similar (in percent) to a deleted one are reported as such.
+pub(crate) const MIN_SIMILARITY: u32 = 70;
+
+/// TLSH digest of a file's content; `None` for files that are too small (under 50 bytes) or
+/// too uniform to have one.
+fn similarity_digest(path: &Path) -> Option<String> {
+    let mut file = File::open(path).ok()?;
+    let mut builder = tlsh2::TlshDefaultBuilder::new();
+    let mut buf = vec![0; 1 << 16];
+    loop {
+        match file.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => builder.update(&buf[..n]),
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(_) => return None,
+        }
+    }
+    String::from_utf8(builder.build()?.hash().to_vec()).ok()
+}
+
+/// Fills in the similarity digests of `--similarity`: kept from the previous state for
+/// unchanged content, computed (in an extra read) for new and changed files.
+pub(crate) fn add_similarity_digests(
+    root: &Path,
+    disk_paths: &HashMap<String, PathBuf>,
+    old: &HashMap<String, Entry>,
+    new: &mut HashMap<String, Entry>,
+) {
+    let mut missing = Vec::new();
+    for (rel, e) in new.iter_mut() {
+        if !e.kind.is_file() || e.is_unhashed() {
+            continue;
+        }
+        match old.get(rel) {
+            Some(o) if o.tlsh.is_some() && o.hash_hex == e.hash_hex && o.partial == e.partial => {
+                e.tlsh = o.tlsh.clone();
+            }
+            _ => missing.push(rel.clone()),
+        }
+    }
+    let digests: Vec<(String, Option<String>)> = missing
+        .into_par_iter()
+        .map(|rel| {
+            let digest = similarity_digest(&disk_path(root, disk_paths, &rel));
+            (rel, digest)
+        })
+        .collect();
+    for (rel, digest) in digests {
+        if let Some(e) = new.get_mut(&rel) {
+            e.tlsh = digest;
+        }
+    }
+}
+
+/// Similarity of two TLSH digests in percent: 100 for the same digest, 0 from a distance of
+/// 300 (TLSH distances below about 100 mean closely related content).
+pub(crate) fn similarity_percent(a: &str, b: &str) -> Option<u32> {
+    let a: tlsh2::TlshDefault = a.parse().ok()?;
+    let b: tlsh2::TlshDefault = b.parse().ok()?;
+    let distance = a.diff(&b, true).clamp(0, 300) as u32;
+    Some(100 - distance / 3)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scan::file_mtime_ns;
+    use crate::state::{write_state_file, State, StateFormat};
+    use crate::test_dir::TestDir;
+
+    fn entries(paths: &[&str]) -> HashMap<String, Entry> {
+        let entry = |rel: &str| Entry { rel_path: rel.to_string(), size: 1, hash_hex: "00".repeat(32), ..Default::default() };
+        paths.iter().map(|rel| (rel.to_string(), entry(rel))).collect()
+    }
+
+    #[test]
+    fn v1_slash_spelling_is_matched_to_backslash_paths() {
+        let mut old = entries(&["a/b", "c/d", "e/f"]);
+        // `c/d` is still a path of its own, and `e\f` is not in the new scan.
+        let new = entries(&["a\\b", "c/d", "c\\d"]);
+
+        assert_eq!(fold_v1_backslashes(&mut old, &new), 1);
+        let mut keys: Vec<&str> = old.keys().map(String::as_str).collect();
+        keys.sort();
+        assert_eq!(keys, ["a\\b", "c/d", "e/f"]);
+        assert_eq!(old["a\\b"].rel_path, "a\\b");
+    }
+
+    #[test]
+    fn changes_made_on_both_sides_conflict() {
+        // Each path's digest in the base, the source now, and the target now.
+        let versions = [("a", '1', '2', '3'), ("b", '1', '2', '2'), ("c", '1', '1', '3')];
+        let side = |pick: fn(&(&str, char, char, char)) -> char| -> HashMap<String, Entry> {
+            versions
+                .iter()
+                .map(|v| {
+                    let e = Entry { rel_path: v.0.to_string(), size: 1, hash_hex: pick(v).to_string().repeat(64), ..Default::default() };
+                    (v.0.to_string(), e)
+                })
+                .collect()
+        };
+        let (base, new, theirs) = (side(|v| v.1), side(|v| v.2), side(|v| v.3));
+        let changes = diff_maps(&base, &new);
+        let dir = TestDir::new();
+        let target = dir.path().join("target");
+        let target_state = dir.path().join("target.txt");
+        let header = StateHeader::default();
+
+        assert!(check_conflicts(&target_state, &target, &header, &base, &new, &changes).is_err(), "no target state yet");
+        let entries = theirs.clone();
+        write_state_file(&target_state, &State { entries, ..Default::default() }, StateFormat::Text, None).unwrap();
+        // `a` changed differently on both sides; `b` the same way, and `c` only in the target.
+        let err = check_conflicts(&target_state, &target, &header, &base, &new, &changes).unwrap_err();
+        assert!(err.to_string().starts_with("1 conflicts"), "{err}");
+
+        let mut entries = theirs;
+        entries.insert("a".to_string(), base["a"].clone());
+        write_state_file(&target_state, &State { entries, ..Default::default() }, StateFormat::Text, None).unwrap();
+        check_conflicts(&target_state, &target, &header, &base, &new, &changes).unwrap();
+    }
+
+    #[test]
+    fn touched_files_keep_their_content() {
+        let dir = TestDir::new();
+        let file = dir.write("a", "same\n");
+        let recorded = |hash: &str| Entry {
+            rel_path: "a".to_string(),
+            size: 5,
+            hash_hex: hash.repeat(64),
+            mtime_ns: file_mtime_ns(&fs::metadata(&file).unwrap()),
+            ..Default::default()
+        };
+        let old = HashMap::from([("a".to_string(), recorded("1"))]);
+        filetime::set_file_mtime(&file, filetime::FileTime::from_unix_time(1_600_000_000, 0)).unwrap();
+
+        let touched = HashMap::from([("a".to_string(), recorded("1"))]);
+        assert!(matches!(diff_maps(&old, &touched)[..], [Change::Touched(ref p)] if p == "a"));
+        let rewritten = HashMap::from([("a".to_string(), recorded("2"))]);
+        assert!(matches!(diff_maps(&old, &rewritten)[..], [Change::Updated(_)]));
+
+        // Entries recorded without a modification time cannot tell.
+        let legacy = HashMap::from([("a".to_string(), Entry { mtime_ns: None, ..recorded("1") })]);
+        assert!(diff_maps(&legacy, &touched).is_empty());
+    }
+}
@@ -0,0 +1,245 @@
+//! Hex, multihash, base32/base58 and the path escaping of text formats.
+
+use crate::hash::{digest_hex_len, Algo};
+
+pub(crate) fn is_hex(s: &str) -> bool {
+    !s.is_empty() && s.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Multihash code of the digests of `algo` (https://github.com/multiformats/multicodec).
+pub(crate) fn multihash_code(algo: Algo) -> Option<u64> {
+    match algo {
+        Algo::Blake3 => Some(0x1e),
+        Algo::Xxh3 => Some(0xb3e4),
+        Algo::Xxh64 => Some(0xb3e2),
+        Algo::Sha256 => Some(0x12),
+        Algo::Blake2b => Some(0xb240),
+        Algo::Sha1 => Some(0x11),
+        Algo::Md5 => Some(0xd5),
+        Algo::Custom(a) => a.multihash_code(),
+    }
+}
+
+/// `<varint code><varint length><digest>`, multibase base32 (lowercase, unpadded, `b` prefix).
+pub(crate) fn encode_multihash(algo: Algo, hex: &str) -> Option<String> {
+    let digest = hex_decode(hex)?;
+    let mut bytes = Vec::with_capacity(digest.len() + 4);
+    push_varint(&mut bytes, multihash_code(algo)?);
+    push_varint(&mut bytes, digest.len() as u64);
+    bytes.extend_from_slice(&digest);
+    Some(format!("b{}", base32_encode(&bytes)))
+}
+
+/// Decodes a multibase (base32 `b`/`B` or base58btc `z`) multihash of a known algorithm
+/// into the algorithm and its hex digest.
+pub(crate) fn decode_multihash(s: &str) -> Option<(Algo, String)> {
+    let bytes = match s.split_at_checked(1)? {
+        ("b", rest) => base32_decode(rest)?,
+        ("B", rest) => base32_decode(&rest.to_ascii_lowercase())?,
+        ("z", rest) => base58_decode(rest)?,
+        _ => return None,
+    };
+    let mut rest = bytes.as_slice();
+    let code = read_varint(&mut rest)?;
+    let len = read_varint(&mut rest)?;
+    if rest.len() as u64 != len {
+        return None;
+    }
+    let algo = Algo::all().into_iter().find(|a| multihash_code(*a) == Some(code))?;
+    (rest.len() * 2 == digest_hex_len(algo)).then(|| (algo, hex_encode(rest)))
+}
+
+fn push_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(input: &mut &[u8]) -> Option<u64> {
+    let mut value = 0u64;
+    for shift in (0..63).step_by(7) {
+        let (&byte, rest) = input.split_first()?;
+        *input = rest;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+const BASE32_ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+fn base32_encode(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len().div_ceil(5) * 8);
+    let (mut buffer, mut bits) = (0u32, 0);
+    for &b in bytes {
+        buffer = (buffer << 8) | u32::from(b);
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(BASE32_ALPHABET[((buffer >> bits) & 31) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 31) as usize] as char);
+    }
+    out
+}
+
+fn base32_decode(s: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(s.len() * 5 / 8);
+    let (mut buffer, mut bits) = (0u32, 0);
+    for c in s.bytes() {
+        let value = BASE32_ALPHABET.iter().position(|&a| a == c)? as u32;
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+fn base58_decode(s: &str) -> Option<Vec<u8>> {
+    const ALPHABET: &[u8; 58] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+    // Big-endian base-256 number, multiplied by 58 and incremented for every digit.
+    let mut num: Vec<u8> = Vec::new();
+    for c in s.bytes() {
+        let mut carry = ALPHABET.iter().position(|&a| a == c)? as u32;
+        for byte in num.iter_mut().rev() {
+            carry += u32::from(*byte) * 58;
+            *byte = carry as u8;
+            carry >>= 8;
+        }
+        while carry > 0 {
+            num.insert(0, carry as u8);
+            carry >>= 8;
+        }
+    }
+    // Leading '1's encode leading zero bytes.
+    let zeros = s.bytes().take_while(|&c| c == b'1').count();
+    let mut out = vec![0u8; zeros];
+    out.extend(num);
+    Some(out)
+}
+
+pub(crate) fn hex_encode(bytes: &[u8]) -> String {
+    use std::fmt::Write as _;
+    let mut out = String::with_capacity(bytes.len() * 2);
+    for b in bytes {
+        let _ = write!(out, "{b:02x}");
+    }
+    out
+}
+
+/// Decodes a lowercase hex string; `None` if it is not canonical lowercase hex.
+pub(crate) fn hex_decode(s: &str) -> Option<Vec<u8>> {
+    if s.is_empty() || !s.len().is_multiple_of(2) {
+        return None;
+    }
+    let nibble = |c: u8| match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        _ => None,
+    };
+    s.as_bytes()
+        .chunks(2)
+        .map(|pair| Some((nibble(pair[0])? << 4) | nibble(pair[1])?))
+        .collect()
+}
+
+/// Backslash-escapes `\`, tab, newline, carriage return and other control characters
+/// (as `\xHH`), so the result never contains a separator or line break.
+pub(crate) fn escape_path(path: &str) -> String {
+    let mut out = String::with_capacity(path.len());
+    for ch in path.chars() {
+        match ch {
+            '\\' => out.push_str("\\\\"),
+            '\t' => out.push_str("\\t"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            c if c.is_ascii_control() => out.push_str(&format!("\\x{:02x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Inverse of [`escape_path`]; also accepts `\#` for a literal `#`.
+pub(crate) fn unescape_path(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(ch) = chars.next() {
+        if ch != '\\' {
+            out.push(ch);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => out.push('\t'),
+            Some('n') => out.push('\n'),
+            Some('r') => out.push('\r'),
+            Some('x') => {
+                let hex: String = chars.by_ref().take(2).collect();
+                match u8::from_str_radix(&hex, 16) {
+                    Ok(b) => out.push(b as char),
+                    Err(_) => {
+                        out.push_str("\\x");
+                        out.push_str(&hex);
+                    }
+                }
+            }
+            Some(other) => out.push(other),
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::{algo_name, digest_hex_len, Algo, BUILTIN_ALGOS};
+
+    #[test]
+    fn escape_path_round_trip() {
+        for path in ["plain", "back\\slash", "tab\there", "new\nline", "cr\r", "\u{7f}\u{1b}", "#x", ""] {
+            let escaped = escape_path(path);
+            assert!(!escaped.contains(['\t', '\n', '\r']));
+            assert_eq!(unescape_path(&escaped), path);
+        }
+        assert_eq!(unescape_path("\\#x"), "#x");
+        assert_eq!(unescape_path("\\xzz"), "\\xzz");
+    }
+
+    #[test]
+    fn multihash_round_trip() {
+        for algo in BUILTIN_ALGOS {
+            let hex = "ab".repeat(digest_hex_len(algo) / 2);
+            let encoded = encode_multihash(algo, &hex).unwrap();
+            assert!(encoded.starts_with('b'));
+            let (decoded, decoded_hex) = decode_multihash(&encoded).unwrap();
+            assert_eq!(algo_name(decoded), algo_name(algo));
+            assert_eq!(decoded_hex, hex);
+            assert!(decode_multihash(&encoded.to_ascii_uppercase()).is_some());
+        }
+        // Digest length that does not match the algorithm.
+        let short = encode_multihash(Algo::Sha256, "abcd").unwrap();
+        assert!(decode_multihash(&short).is_none());
+        assert!(decode_multihash("x123").is_none());
+    }
+
+    #[test]
+    fn base32_round_trip() {
+        let bytes: Vec<u8> = (0..=255).collect();
+        for len in 0..12 {
+            let encoded = base32_encode(&bytes[..len]);
+            assert_eq!(base32_decode(&encoded).unwrap(), &bytes[..len]);
+        }
+        assert_eq!(base32_encode(b"foobar"), "mzxw6ytboi");
+        assert!(base32_decode("not base32!").is_none());
+    }
+}
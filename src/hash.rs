@@ -0,0 +1,694 @@
+//! The built-in algorithms and hashing files: plain, sampled, chunked, several algorithms at once, io_uring.
+
+use crate::algorithm::{self, HashAlgorithm, Hasher};
+use crate::encoding::hex_encode;
+use crate::scan::ScanOptions;
+use crate::state::{Chunk, Entry};
+use anyhow::{anyhow, Context, Result};
+use clap::ValueEnum;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy)]
+pub(crate) enum Algo {
+    Blake3,
+    Xxh3,
+    Xxh64,
+    Sha256,
+    Blake2b,
+    Sha1,
+    Md5,
+    /// Registered with [`register_algorithm`](crate::register_algorithm).
+    Custom(&'static dyn HashAlgorithm),
+}
+
+pub(crate) const BUILTIN_ALGOS: [Algo; 7] = [
+    Algo::Blake3,
+    Algo::Xxh3,
+    Algo::Xxh64,
+    Algo::Sha256,
+    Algo::Blake2b,
+    Algo::Sha1,
+    Algo::Md5,
+];
+
+impl Algo {
+    /// Built-in algorithms followed by the registered ones.
+    pub(crate) fn all() -> Vec<Algo> {
+        BUILTIN_ALGOS.into_iter().chain(algorithm::registered().into_iter().map(Algo::Custom)).collect()
+    }
+
+    /// The algorithm called `name` (as recorded in state files), if known.
+    pub(crate) fn from_name(name: &str) -> Option<Algo> {
+        Algo::all().into_iter().find(|a| algo_name(*a).eq_ignore_ascii_case(name))
+    }
+
+    /// `--help` description.
+    pub(crate) fn help(self) -> &'static str {
+        match self {
+            Algo::Blake3 => "BLAKE3 (default; cryptographic and fast)",
+            Algo::Xxh3 => "XXH3 128-bit (very fast, non-cryptographic)",
+            Algo::Xxh64 => "XXH64 (very fast, non-cryptographic, 64-bit digests for small state files)",
+            Algo::Sha256 => "SHA-256",
+            Algo::Blake2b => "BLAKE2b-512 (same digests as `b2sum`)",
+            Algo::Sha1 => "SHA-1 (broken; only for interop with legacy manifests)",
+            Algo::Md5 => "MD5 (broken; only for interop with legacy manifests)",
+            Algo::Custom(_) => "Custom algorithm",
+        }
+    }
+}
+
+impl PartialEq for Algo {
+    fn eq(&self, other: &Algo) -> bool {
+        algo_name(*self) == algo_name(*other)
+    }
+}
+
+impl Eq for Algo {}
+
+impl std::fmt::Debug for Algo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(algo_name(*self))
+    }
+}
+
+/// Guesses the algorithm of headerless (v1) states from the digest length. Only blake3 and
+/// xxh3 existed when v1 states were written, and their digest lengths differ.
+pub(crate) fn infer_algo(entries: &HashMap<String, Entry>) -> Option<Algo> {
+    let mut lens = entries.values().map(|e| e.hash_hex.len());
+    let len = lens.next()?;
+    if lens.any(|l| l != len) {
+        return None;
+    }
+    [Algo::Blake3, Algo::Xxh3].into_iter().find(|a| digest_hex_len(*a) == len)
+}
+
+/// Length of the hex digests produced by `algo`.
+pub(crate) fn digest_hex_len(algo: Algo) -> usize {
+    match algo {
+        Algo::Blake3 => 64,
+        Algo::Xxh3 => 32,
+        Algo::Xxh64 => 16,
+        Algo::Sha256 => 64,
+        Algo::Blake2b => 128,
+        Algo::Sha1 => 40,
+        Algo::Md5 => 32,
+        Algo::Custom(a) => a.digest_hex_len(),
+    }
+}
+
+pub(crate) fn algo_name(algo: Algo) -> &'static str {
+    match algo {
+        Algo::Blake3 => "blake3",
+        Algo::Xxh3 => "xxh3",
+        Algo::Xxh64 => "xxh64",
+        Algo::Sha256 => "sha256",
+        Algo::Blake2b => "blake2b",
+        Algo::Sha1 => "sha1",
+        Algo::Md5 => "md5",
+        Algo::Custom(a) => a.name(),
+    }
+}
+
+/// How file contents are read for hashing.
+#[derive(Clone, Debug)]
+pub(crate) struct ReadOptions {
+    /// Memory-map large files instead of reading them (`--no-mmap` disables it).
+    pub(crate) mmap: bool,
+    pub(crate) engine: IoEngine,
+    /// Size of each read (`--buffer-size`).
+    pub(crate) buffer_size: usize,
+    /// Drop the pages of hashed files from the page cache (`--no-cache-pollution`).
+    pub(crate) drop_cache: bool,
+    /// Shared by all hashing threads (`--bwlimit`).
+    pub(crate) bwlimit: Option<Arc<RateLimit>>,
+}
+
+impl ReadOptions {
+    /// Accounts for `n` bytes just read, sleeping as long as `--bwlimit` requires.
+    fn throttle(&self, n: usize) {
+        if let Some(limit) = &self.bwlimit {
+            limit.consume(n as u64);
+        }
+    }
+}
+
+/// Limits the combined read rate of all threads to `bytes_per_sec`. Each read reserves the next
+/// slot of time its size is worth, and the reader sleeps until that slot has passed; idle time
+/// is not saved up for later bursts.
+#[derive(Debug)]
+pub(crate) struct RateLimit {
+    bytes_per_sec: u64,
+    next: Mutex<Instant>,
+}
+
+impl RateLimit {
+    pub(crate) fn new(bytes_per_sec: u64) -> Self {
+        RateLimit {
+            bytes_per_sec,
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    pub(crate) fn consume(&self, bytes: u64) {
+        let cost = Duration::from_secs_f64(bytes as f64 / self.bytes_per_sec as f64);
+        let until = {
+            let mut next = self.next.lock().unwrap_or_else(|e| e.into_inner());
+            *next = (*next).max(Instant::now()) + cost;
+            *next
+        };
+        std::thread::sleep(until.saturating_duration_since(Instant::now()));
+    }
+}
+
+/// Default `--buffer-size`: large sequential reads for blocking I/O, smaller ones for io_uring,
+/// which keeps [`URING_DEPTH`] buffers per thread.
+pub(crate) fn default_buffer_size(engine: IoEngine) -> usize {
+    match engine {
+        IoEngine::Std => 1024 * 1024,
+        IoEngine::Uring => 256 * 1024,
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub(crate) enum IoEngine {
+    /// Blocking reads (or mmap) from the worker threads
+    #[default]
+    Std,
+    /// Batched io_uring reads with many files in flight per thread (Linux, `io-uring` feature)
+    Uring,
+}
+
+impl Hasher for blake3::Hasher {
+    fn update(&mut self, data: &[u8]) {
+        blake3::Hasher::update(self, data);
+    }
+    fn finalize_hex(self: Box<Self>) -> String {
+        self.finalize().to_hex().to_string()
+    }
+}
+
+impl Hasher for xxhash_rust::xxh3::Xxh3 {
+    fn update(&mut self, data: &[u8]) {
+        xxhash_rust::xxh3::Xxh3::update(self, data);
+    }
+    fn finalize_hex(self: Box<Self>) -> String {
+        format!("{:032x}", self.digest128())
+    }
+}
+
+impl Hasher for xxhash_rust::xxh64::Xxh64 {
+    fn update(&mut self, data: &[u8]) {
+        xxhash_rust::xxh64::Xxh64::update(self, data);
+    }
+    fn finalize_hex(self: Box<Self>) -> String {
+        format!("{:016x}", self.digest())
+    }
+}
+
+/// Adapter for RustCrypto [`sha2::Digest`] implementations (SHA-2, SHA-1, MD5, BLAKE2).
+struct DigestHasher<D>(D);
+
+impl<D: sha2::Digest + Send> Hasher for DigestHasher<D> {
+    fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+    fn finalize_hex(self: Box<Self>) -> String {
+        hex_encode(&self.0.finalize())
+    }
+}
+
+pub(crate) fn new_hasher(algo: Algo, key: Option<&[u8; 32]>) -> Box<dyn Hasher> {
+    match algo {
+        Algo::Blake3 => Box::new(match key {
+            Some(key) => blake3::Hasher::new_keyed(key),
+            None => blake3::Hasher::new(),
+        }),
+        Algo::Xxh3 => Box::new(xxhash_rust::xxh3::Xxh3::new()),
+        Algo::Xxh64 => Box::new(xxhash_rust::xxh64::Xxh64::new(0)),
+        Algo::Sha256 => Box::new(DigestHasher(<sha2::Sha256 as sha2::Digest>::new())),
+        Algo::Blake2b => Box::new(DigestHasher(<blake2::Blake2b512 as sha2::Digest>::new())),
+        Algo::Sha1 => Box::new(DigestHasher(<sha1::Sha1 as sha2::Digest>::new())),
+        Algo::Md5 => Box::new(DigestHasher(<md5::Md5 as sha2::Digest>::new())),
+        Algo::Custom(a) => a.new_hasher(),
+    }
+}
+
+fn hash_file(path: &Path, algo: Algo, key: Option<&[u8; 32]>, read: &ReadOptions) -> Result<String> {
+    if matches!(algo, Algo::Blake3) && read.mmap {
+        // A huge file would otherwise hash on one core while the rest of the pool sits idle.
+        let len = fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+        if len >= BLAKE3_PARALLEL_THRESHOLD {
+            let mut hasher = match key {
+                Some(key) => blake3::Hasher::new_keyed(key),
+                None => blake3::Hasher::new(),
+            };
+            hasher
+                .update_mmap_rayon(path)
+                .with_context(|| format!("Failed to hash (blake3): {path:?}"))?;
+            return Ok(hasher.finalize().to_hex().to_string());
+        }
+    }
+    let mut hasher = new_hasher(algo, key);
+    for_each_chunk(path, algo_name(algo), read, |chunk| hasher.update(chunk))?;
+    Ok(hasher.finalize_hex())
+}
+
+/// Partial hashing (`--quick`, `--sample`). Files it does not apply to are hashed in full, and
+/// entries hashed partially record [`Sampling::marker`] so they are never compared with full
+/// digests (or with digests sampled differently).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) enum Sampling {
+    #[default]
+    Full,
+    /// The size plus the first and last `edge` bytes.
+    Quick { edge: u64 },
+    /// The size plus `count` evenly spaced blocks of `size` bytes (the first one at the start
+    /// of the file, the last one at its end), for files of at least `threshold` bytes.
+    Blocks { count: u64, size: u64, threshold: u64 },
+}
+
+/// `partial` marker of entries recorded without a content digest (`--hash-max-size`).
+pub(crate) const UNHASHED: &str = "none";
+
+impl Sampling {
+    /// Whether a file of `size` bytes is only partially hashed.
+    pub(crate) fn applies(&self, size: u64) -> bool {
+        match *self {
+            Sampling::Full => false,
+            Sampling::Quick { edge } => size > edge.saturating_mul(2),
+            Sampling::Blocks { count, size: block, threshold } => {
+                size >= threshold && size > count.saturating_mul(block)
+            }
+        }
+    }
+
+    /// Value of the `partial` field of entries hashed this way (e.g. `quick:1048576`).
+    pub(crate) fn marker(&self) -> Option<String> {
+        match *self {
+            Sampling::Full => None,
+            Sampling::Quick { edge } => Some(format!("quick:{edge}")),
+            Sampling::Blocks { count, size, .. } => Some(format!("sample:{count}x{size}")),
+        }
+    }
+
+    /// Parses a recorded marker. The threshold is not recorded: a marked entry was sampled.
+    pub(crate) fn from_marker(marker: Option<&str>) -> Option<Self> {
+        let Some(marker) = marker else {
+            return Some(Sampling::Full);
+        };
+        match marker.split_once(':')? {
+            ("quick", edge) => Some(Sampling::Quick { edge: edge.parse().ok()? }),
+            ("sample", blocks) => {
+                let (count, size) = blocks.split_once('x')?;
+                Some(Sampling::Blocks {
+                    count: count.parse().ok().filter(|&c| c > 0)?,
+                    size: size.parse().ok()?,
+                    threshold: 0,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// Byte ranges (offset, length) hashed for a file of `size` bytes the sampling applies to.
+    fn ranges(&self, size: u64) -> Vec<(u64, u64)> {
+        match *self {
+            Sampling::Full => vec![(0, size)],
+            Sampling::Quick { edge } => vec![(0, edge), (size - edge, edge)],
+            Sampling::Blocks { count, size: block, .. } => {
+                let span = u128::from(size - block);
+                let last = u128::from(count.max(2) - 1);
+                (0..u128::from(count))
+                    .map(|i| ((span * i / last) as u64, block))
+                    .collect()
+            }
+        }
+    }
+}
+
+/// Hashes `path` in full, or only the parts selected by `sampling` if it applies to `size`.
+/// Partial digests also cover the size, so a file that only grew or shrank is still caught.
+pub(crate) fn hash_file_sampled(
+    path: &Path,
+    size: u64,
+    algo: Algo,
+    key: Option<&[u8; 32]>,
+    sampling: Sampling,
+    read: &ReadOptions,
+) -> Result<String> {
+    if !sampling.applies(size) {
+        return hash_file(path, algo, key, read);
+    }
+    let mut file = File::open(path)
+        .with_context(|| format!("Failed to open for hashing ({}): {path:?}", algo_name(algo)))?;
+    let mut hasher = new_hasher(algo, key);
+    hasher.update(&size.to_le_bytes());
+    let mut buf = vec![0u8; read.buffer_size];
+    for (offset, len) in sampling.ranges(size) {
+        file.seek(io::SeekFrom::Start(offset))?;
+        let mut remaining = len;
+        while remaining > 0 {
+            let n = remaining.min(buf.len() as u64) as usize;
+            file.read_exact(&mut buf[..n])
+                .with_context(|| format!("Failed to read for hashing ({}): {path:?}", algo_name(algo)))?;
+            read.throttle(n);
+            hasher.update(&buf[..n]);
+            remaining -= n as u64;
+        }
+    }
+    if read.drop_cache {
+        drop_cached_pages(&file, 0, 0);
+    }
+    Ok(hasher.finalize_hex())
+}
+
+/// Hashes `path` in full (with each of `algos`, see [`hash_file_multi`]) and also splits it
+/// into content-defined chunks (FastCDC with minimum and maximum sizes of a quarter and four
+/// times `avg`), each hashed on its own with the first algorithm.
+pub(crate) fn hash_file_chunked(
+    path: &Path,
+    algos: &[Algo],
+    key: Option<&[u8; 32]>,
+    avg: u32,
+    read: &ReadOptions,
+) -> Result<(Vec<String>, Vec<Chunk>)> {
+    let algo = algos[0];
+    let file = File::open(path)
+        .with_context(|| format!("Failed to open for hashing ({}): {path:?}", algo_name(algo)))?;
+    advise_sequential(&file);
+    let mut whole: Vec<_> = algos.iter().map(|&a| new_hasher(a, key)).collect();
+    let mut chunks = Vec::new();
+    let mut dropped = 0;
+    for chunk in fastcdc::v2020::StreamCDC::new(&file, avg / 4, avg, avg * 4) {
+        let chunk = chunk.with_context(|| format!("Failed to read for hashing ({}): {path:?}", algo_name(algo)))?;
+        read.throttle(chunk.length);
+        whole.iter_mut().for_each(|h| h.update(&chunk.data));
+        let mut hasher = new_hasher(algo, key);
+        hasher.update(&chunk.data);
+        chunks.push(Chunk {
+            len: chunk.length as u64,
+            hash: hasher.finalize_hex(),
+        });
+        let end = chunk.offset + chunk.length as u64;
+        if read.drop_cache && end - dropped >= DROP_CACHE_INTERVAL {
+            drop_cached_pages(&file, dropped, end - dropped);
+            dropped = end;
+        }
+    }
+    if read.drop_cache {
+        drop_cached_pages(&file, 0, 0);
+    }
+    Ok((whole.into_iter().map(|h| h.finalize_hex()).collect(), chunks))
+}
+
+/// Like [`hash_file`] for several algorithms at once (in this order), reading the file once.
+pub(crate) fn hash_file_multi(path: &Path, algos: &[Algo], key: Option<&[u8; 32]>, read: &ReadOptions) -> Result<Vec<String>> {
+    if let [algo] = algos {
+        return Ok(vec![hash_file(path, *algo, key, read)?]);
+    }
+    let mut hashers: Vec<_> = algos.iter().map(|&algo| new_hasher(algo, key)).collect();
+    for_each_chunk(path, algo_name(algos[0]), read, |chunk| {
+        hashers.iter_mut().for_each(|h| h.update(chunk));
+    })?;
+    Ok(hashers.into_iter().map(|h| h.finalize_hex()).collect())
+}
+
+/// Files at least this large are hashed with BLAKE3's multi-threaded (rayon) implementation.
+const BLAKE3_PARALLEL_THRESHOLD: u64 = 64 * 1024 * 1024;
+
+/// Files at least this large are memory-mapped instead of read in `--buffer-size` chunks.
+const MMAP_THRESHOLD: u64 = 4 * 1024 * 1024;
+
+/// Feeds the contents of `path` to `consume`: memory-mapped in one piece for large files
+/// (unless disabled), else in reads of `--buffer-size`. Files that cannot be mapped fall back
+/// to reads.
+fn for_each_chunk(
+    path: &Path,
+    algo: &str,
+    read: &ReadOptions,
+    mut consume: impl FnMut(&[u8]),
+) -> Result<()> {
+    let mut file = File::open(path)
+        .with_context(|| format!("Failed to open for hashing ({algo}): {path:?}"))?;
+
+    let len = file.metadata().map(|m| m.len()).unwrap_or(0);
+    if read.mmap && len >= MMAP_THRESHOLD {
+        // SAFETY: the map is only read. If another process truncates the file meanwhile, the
+        // read faults (SIGBUS) instead of returning stale data; `--no-mmap` avoids that risk.
+        if let Ok(map) = unsafe { memmap2::Mmap::map(&file) } {
+            #[cfg(unix)]
+            let _ = map.advise(memmap2::Advice::Sequential);
+            consume(&map);
+            return Ok(());
+        }
+    }
+
+    advise_sequential(&file);
+    // Small files do not need a full-size buffer; one extra byte sees the end of file.
+    let cap = usize::try_from(len.saturating_add(1)).unwrap_or(usize::MAX);
+    let mut buf = vec![0u8; read.buffer_size.min(cap.max(8 * 1024))];
+    let (mut pos, mut dropped) = (0u64, 0u64);
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        read.throttle(n);
+        consume(&buf[..n]);
+        pos += n as u64;
+        // Drop as we go, so a single huge file does not fill the cache before it is done.
+        if read.drop_cache && pos - dropped >= DROP_CACHE_INTERVAL {
+            drop_cached_pages(&file, dropped, pos - dropped);
+            dropped = pos;
+        }
+    }
+    if read.drop_cache {
+        drop_cached_pages(&file, 0, 0);
+    }
+    Ok(())
+}
+
+/// With `--no-cache-pollution`, pages already read are dropped every this many bytes.
+const DROP_CACHE_INTERVAL: u64 = 64 * 1024 * 1024;
+
+/// Drops the cached pages of `file` from `offset` on (`len` bytes, 0 for the rest of the file).
+/// The kernel keeps dirty pages, so files that are being written are not affected.
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+fn drop_cached_pages(file: &File, offset: u64, len: u64) {
+    use std::os::unix::io::AsRawFd;
+    let (Ok(offset), Ok(len)) = (libc::off_t::try_from(offset), libc::off_t::try_from(len)) else {
+        return;
+    };
+    // SAFETY: a plain syscall on a descriptor owned by `file`; failing only keeps the pages.
+    unsafe {
+        libc::posix_fadvise(file.as_raw_fd(), offset, len, libc::POSIX_FADV_DONTNEED);
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+fn drop_cached_pages(_file: &File, _offset: u64, _len: u64) {}
+
+/// Tells the kernel that `file` is about to be read sequentially, so it reads ahead more.
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+fn advise_sequential(file: &File) {
+    use std::os::unix::io::AsRawFd;
+    // SAFETY: a plain syscall on a descriptor owned by `file`; failing only loses the hint.
+    unsafe {
+        libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_SEQUENTIAL);
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+fn advise_sequential(_file: &File) {}
+
+type UringSkip<'a> = dyn Fn(usize, &fs::Metadata) -> bool + Sync + 'a;
+
+/// Files per io_uring batch; each batch runs on one worker thread with its own ring.
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+const URING_BATCH: usize = 512;
+
+/// Reads in flight per ring.
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+const URING_DEPTH: usize = 32;
+
+/// Hashes `files` (digests in [`ScanOptions::algos`] order, results in the same order as the
+/// files) with io_uring, keeping up to
+/// [`URING_DEPTH`] reads in flight per thread so scans of many small files are not
+/// bound by one blocking `read` per file. Files for which `skip(index, metadata)` holds
+/// (e.g. partially hashed or cached ones) are not read and yield `None`.
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+pub(crate) fn hash_files_uring(files: &[PathBuf], scan: &ScanOptions, skip: &UringSkip) -> Result<Vec<Option<io::Result<Vec<String>>>>> {
+    let batches: Result<Vec<_>> = files
+        .par_chunks(URING_BATCH)
+        .enumerate()
+        .map(|(n, batch)| hash_batch_uring(batch, n * URING_BATCH, scan, skip))
+        .collect();
+    Ok(batches?.into_iter().flatten().collect())
+}
+
+#[cfg(not(all(target_os = "linux", feature = "io-uring")))]
+pub(crate) fn hash_files_uring(
+    _files: &[PathBuf],
+    _scan: &ScanOptions,
+    _skip: &UringSkip,
+) -> Result<Vec<Option<io::Result<Vec<String>>>>> {
+    Err(anyhow!("--io-engine uring requires a Linux build with the `io-uring` feature"))
+}
+
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+struct UringRead {
+    index: usize,
+    file: File,
+    buf: Vec<u8>,
+    offset: u64,
+    hashers: Vec<Box<dyn Hasher>>,
+}
+
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+fn hash_batch_uring(
+    files: &[PathBuf],
+    base: usize,
+    scan: &ScanOptions,
+    skip: &UringSkip,
+) -> Result<Vec<Option<io::Result<Vec<String>>>>> {
+    use io_uring::IoUring;
+
+    let mut ring = IoUring::new(URING_DEPTH as u32).context("Failed to set up io_uring")?;
+    let mut results: Vec<Option<io::Result<Vec<String>>>> = files.iter().map(|_| None).collect();
+    let mut skipped = vec![false; files.len()];
+    let mut slots: Vec<Option<UringRead>> = (0..URING_DEPTH).map(|_| None).collect();
+    let mut next = 0;
+    let mut in_flight = 0;
+
+    loop {
+        for (slot, state) in slots.iter_mut().enumerate() {
+            while state.is_none() && next < files.len() {
+                let index = next;
+                next += 1;
+                if fs::metadata(&files[index]).is_ok_and(|m| skip(base + index, &m)) {
+                    skipped[index] = true;
+                    continue;
+                }
+                match File::open(&files[index]) {
+                    Ok(file) => {
+                        advise_sequential(&file);
+                        let mut read = UringRead {
+                            index,
+                            file,
+                            buf: vec![0u8; scan.read.buffer_size],
+                            offset: 0,
+                            hashers: scan.algos().into_iter().map(|a| new_hasher(a, scan.key.as_ref())).collect(),
+                        };
+                        push_uring_read(&mut ring, slot, &mut read);
+                        *state = Some(read);
+                        in_flight += 1;
+                    }
+                    Err(err) => results[index] = Some(Err(err)),
+                }
+            }
+        }
+        if in_flight == 0 {
+            break;
+        }
+
+        match ring.submit_and_wait(1) {
+            Ok(_) => {}
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => {
+                // Reads may still be in flight: leak their buffers rather than free them.
+                std::mem::forget(slots);
+                return Err(err).context("io_uring submission failed");
+            }
+        }
+        let completions: Vec<(u64, i32)> = ring.completion().map(|c| (c.user_data(), c.result())).collect();
+        for (user_data, res) in completions {
+            let slot = user_data as usize;
+            let Some(mut read) = slots[slot].take() else {
+                continue;
+            };
+            if res > 0 {
+                let n = res as usize;
+                scan.read.throttle(n);
+                read.hashers.iter_mut().for_each(|h| h.update(&read.buf[..n]));
+                read.offset += n as u64;
+                push_uring_read(&mut ring, slot, &mut read);
+                slots[slot] = Some(read);
+                continue;
+            }
+            in_flight -= 1;
+            if scan.read.drop_cache {
+                drop_cached_pages(&read.file, 0, 0);
+            }
+            results[read.index] = Some(if res == 0 {
+                Ok(read.hashers.into_iter().map(|h| h.finalize_hex()).collect())
+            } else {
+                Err(io::Error::from_raw_os_error(-res))
+            });
+        }
+    }
+
+    Ok(results
+        .into_iter()
+        .zip(skipped)
+        .map(|(r, skipped)| match r {
+            None if !skipped => Some(Err(io::Error::other("file was not read"))),
+            r => r,
+        })
+        .collect())
+}
+
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+fn push_uring_read(ring: &mut io_uring::IoUring, slot: usize, read: &mut UringRead) {
+    use io_uring::{opcode, types};
+    use std::os::unix::io::AsRawFd;
+
+    let entry = opcode::Read::new(
+        types::Fd(read.file.as_raw_fd()),
+        read.buf.as_mut_ptr(),
+        read.buf.len() as u32,
+    )
+    .offset(read.offset)
+    .build()
+    .user_data(slot as u64);
+    // SAFETY: the file and buffer are owned by the slot until this read's completion is
+    // reaped (a `Vec`'s heap buffer does not move with it), and there is at most one read per
+    // slot, so the queue (one entry per slot) cannot overflow.
+    unsafe {
+        ring.submission()
+            .push(&entry)
+            .expect("io_uring submission queue has one entry per slot");
+    }
+}
+
+/// Environment variable holding the `--hash-key` material when the option is not given.
+const HASH_KEY_ENV: &str = "FAST_HASH_INDEX_KEY";
+
+/// Reads the keyed-hashing secret from `path` or `$FAST_HASH_INDEX_KEY`. Key material of any
+/// length is accepted and turned into a BLAKE3 key with `derive_key`.
+pub(crate) fn load_hash_key(path: Option<&Path>) -> Result<Option<[u8; 32]>> {
+    let material = match path {
+        Some(path) => fs::read(path).with_context(|| format!("Failed to read hash key: {path:?}"))?,
+        None => match std::env::var_os(HASH_KEY_ENV) {
+            Some(value) => value.into_encoded_bytes(),
+            None => return Ok(None),
+        },
+    };
+    // Keys kept in text files usually end with a newline, which is not part of the secret.
+    let material = material.trim_ascii_end();
+    if material.is_empty() {
+        return Err(anyhow!("Hash key is empty"));
+    }
+    Ok(Some(blake3::derive_key("fast-hash-index keyed file hashing v1", material)))
+}
+
+/// Short public fingerprint of a hash key, stored in the header to detect key changes.
+pub(crate) fn hash_key_id(key: &[u8; 32]) -> String {
+    blake3::keyed_hash(key, b"fast-hash-index key id").to_hex()[..16].to_string()
+}
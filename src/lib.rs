@@ -0,0 +1,27 @@
+//! The `fast-hash-index` command line tool as a library, so that downstream builds can
+//! plug in their own hash algorithms (e.g. an HSM-backed digest) without forking:
+//! implement [`HashAlgorithm`], call [`register_algorithm`] and then [`run`].
+
+mod algorithm;
+mod archive;
+mod cli;
+mod commands;
+mod copy;
+mod diff;
+mod encoding;
+mod hash;
+mod manifest;
+mod output;
+mod patch;
+mod scan;
+mod snapshot;
+mod state;
+mod sync;
+#[cfg(test)]
+mod test_dir;
+mod timestamps;
+mod walk;
+
+pub use algorithm::{register_algorithm, HashAlgorithm, Hasher};
+pub use cli::run;
+pub use scan::Incomplete;
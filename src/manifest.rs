@@ -0,0 +1,196 @@
+//! `*sum -c` manifests and mtree(8) specifications.
+
+use crate::encoding::{is_hex, unescape_path};
+use crate::hash::{algo_name, digest_hex_len, Algo};
+use crate::state::{Entry, EntryKind};
+use crate::walk::ancestor_dirs;
+use anyhow::{Context, Result};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufRead, BufWriter, Write};
+use std::path::Path;
+
+/// Parses GNU (`<hex>  <path>`, `<hex> *<path>`, with `\`-escaped lines) and BSD
+/// (`ALGO (<path>) = <hex>`) checksum lines. Size and timestamp are unknown and set to 0.
+pub(crate) fn parse_manifest(reader: impl BufRead, algo: Algo) -> Result<HashMap<String, Entry>> {
+    let expected_len = digest_hex_len(algo);
+    let mut warned_len = false;
+
+    let mut map = HashMap::new();
+    for (idx, line_res) in reader.lines().enumerate() {
+        let lineno = idx + 1;
+        let line = line_res?;
+        let line = line.trim_end_matches('\r');
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let Some((hash, path)) = parse_manifest_line(line) else {
+            eprintln!("Warning: invalid manifest line {lineno}: {line}");
+            continue;
+        };
+        let hash = hash.to_ascii_lowercase();
+        if hash.len() != expected_len && !warned_len {
+            eprintln!(
+                "Warning: line {lineno}: {}-digit digest does not look like {} ({expected_len} digits)",
+                hash.len(),
+                algo_name(algo)
+            );
+            warned_len = true;
+        }
+
+        let rel = path.trim_start_matches("./").to_string();
+        map.insert(
+            rel.clone(),
+            Entry {
+                rel_path: rel,
+                hash_hex: hash,
+                algo: Some(algo_name(algo).to_string()),
+                ..Default::default()
+            },
+        );
+    }
+    Ok(map)
+}
+
+/// Writes `<hex>  <path>` lines (GNU coreutils format, paths relative to the indexed root).
+/// Files without a digest (`--hash-max-size`) are left out.
+pub(crate) fn write_manifest(path: &Path, map: &HashMap<String, Entry>) -> Result<()> {
+    let file = File::create(path).with_context(|| format!("Failed to create manifest: {path:?}"))?;
+    let mut w = BufWriter::new(file);
+
+    let ordered: BTreeMap<&String, &Entry> = map
+        .iter()
+        .filter(|(_, e)| e.kind == EntryKind::File && !e.is_unhashed())
+        .collect();
+    for e in ordered.values() {
+        // Same escaping as coreutils: `\` and newlines escaped, line prefixed with `\`.
+        if e.rel_path.contains(['\\', '\n', '\r']) {
+            let escaped = e
+                .rel_path
+                .replace('\\', "\\\\")
+                .replace('\n', "\\n")
+                .replace('\r', "\\r");
+            writeln!(w, "\\{}  {escaped}", e.hash_hex)?;
+        } else {
+            writeln!(w, "{}  {}", e.hash_hex, e.rel_path)?;
+        }
+    }
+    w.flush()?;
+    Ok(())
+}
+
+/// Writes an mtree(8) specification in "full path" form: `./<path> <keyword>=<value>...`.
+/// The digest keyword is `<algo>digest` (e.g. `blake3digest`).
+pub(crate) fn write_mtree(path: &Path, map: &HashMap<String, Entry>, algo: Algo) -> Result<()> {
+    let file = File::create(path).with_context(|| format!("Failed to create mtree file: {path:?}"))?;
+    let mut w = BufWriter::new(file);
+
+    // mtree consumers expect every directory to be listed before its content.
+    let mut dirs: BTreeMap<&str, Option<&Entry>> = BTreeMap::new();
+    for e in map.values() {
+        for dir in ancestor_dirs(&e.rel_path) {
+            dirs.entry(dir).or_insert(None);
+        }
+        if e.kind == EntryKind::Dir {
+            dirs.insert(&e.rel_path, Some(e));
+        }
+    }
+    let files: BTreeMap<&str, &Entry> = map
+        .values()
+        .filter(|e| e.kind == EntryKind::File)
+        .map(|e| (e.rel_path.as_str(), e))
+        .collect();
+
+    writeln!(w, "#mtree")?;
+    writeln!(w, ". type=dir")?;
+    let mut lines: BTreeMap<&str, String> = BTreeMap::new();
+    for (dir, entry) in dirs {
+        let mut line = format!("./{} type=dir", mtree_escape(dir));
+        if let Some(e) = entry {
+            push_mtree_metadata(&mut line, e);
+        }
+        lines.insert(dir, line);
+    }
+    for e in map.values().filter(|e| e.kind == EntryKind::Link) {
+        let target = e.target.as_deref().unwrap_or_default();
+        let line = format!("./{} type=link link={}", mtree_escape(&e.rel_path), mtree_escape(target));
+        lines.insert(&e.rel_path, line);
+    }
+    for e in map.values().filter(|e| e.kind == EntryKind::Special) {
+        let mut line = format!("./{} type={}", mtree_escape(&e.rel_path), e.special.as_deref().unwrap_or_default());
+        push_mtree_metadata(&mut line, e);
+        lines.insert(&e.rel_path, line);
+    }
+    for (rel, e) in files {
+        let mut line = format!("./{} type=file size={}", mtree_escape(rel), e.size);
+        push_mtree_metadata(&mut line, e);
+        if !e.is_unhashed() {
+            line.push_str(&format!(" {}digest={}", algo_name(algo), e.hash_hex));
+        }
+        lines.insert(rel, line);
+    }
+    for line in lines.values() {
+        writeln!(w, "{line}")?;
+    }
+    w.flush()?;
+    Ok(())
+}
+
+fn push_mtree_metadata(line: &mut String, e: &Entry) {
+    if let Some(ns) = e.mtime_ns {
+        line.push_str(&format!(" time={}.{:09}", ns / 1_000_000_000, ns % 1_000_000_000));
+    }
+    if let Some(mode) = e.mode {
+        line.push_str(&format!(" mode={mode:04o}"));
+    }
+    if let Some(uid) = e.uid {
+        line.push_str(&format!(" uid={uid}"));
+    }
+    if let Some(gid) = e.gid {
+        line.push_str(&format!(" gid={gid}"));
+    }
+}
+
+/// vis(3)-style encoding used by mtree: anything but safe printable ASCII becomes `\ooo`.
+fn mtree_escape(path: &str) -> String {
+    let mut out = String::with_capacity(path.len());
+    for b in path.bytes() {
+        if b.is_ascii_graphic() && !matches!(b, b'\\' | b'#' | b'=' | b'*' | b'?' | b'[') {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("\\{b:03o}"));
+        }
+    }
+    out
+}
+
+fn parse_manifest_line(line: &str) -> Option<(String, String)> {
+    // GNU tools prefix the line with `\` when the file name needed escaping.
+    let (escaped, line) = match line.strip_prefix('\\') {
+        Some(rest) => (true, rest),
+        None => (false, line),
+    };
+    let unescape = |p: &str| {
+        if escaped {
+            unescape_path(p)
+        } else {
+            p.to_string()
+        }
+    };
+
+    if let Some((tag_and_path, hash)) = line.rsplit_once(") = ") {
+        let (_tag, path) = tag_and_path.split_once(" (")?;
+        let hash = hash.trim();
+        if is_hex(hash) {
+            return Some((hash.to_string(), unescape(path)));
+        }
+    }
+
+    let (hash, rest) = line.split_once(' ')?;
+    let path = rest.strip_prefix(' ').or_else(|| rest.strip_prefix('*'))?;
+    if !is_hex(hash) || path.is_empty() {
+        return None;
+    }
+    Some((hash.to_string(), unescape(path)))
+}
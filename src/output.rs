@@ -0,0 +1,362 @@
+//! Printing the changes (text, porcelain, JUnit) and sending them to a webhook.
+
+use crate::diff::{
+    diff_maps, filter_changes, fold_case_renames, report_case_renames, similarity_percent, Change,
+    ChangeCounts, ChangeKind, MIN_SIMILARITY,
+};
+use crate::encoding::escape_path;
+use crate::state::{parent_dir, Chunk, Entry, State};
+use crate::walk::ancestor_dirs;
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use rayon::prelude::*;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub(crate) enum OutputFormat {
+    Text,
+    Junit,
+    Porcelain,
+}
+
+/// Prints the changes from `old` to `new` (the `diff` and `diff-states` output). The JUnit
+/// suite is named after the recorded root of `new`, or `fallback` if it has none.
+pub(crate) fn print_state_diff(
+    mut old: State,
+    new: &State,
+    format: OutputFormat,
+    case_insensitive: bool,
+    only: &[ChangeKind],
+    ignore: &[ChangeKind],
+    fallback: &Path,
+) -> Result<()> {
+    if case_insensitive {
+        report_case_renames(&fold_case_renames(&mut old.entries, &new.entries));
+    }
+    let changes = filter_changes(diff_maps(&old.entries, &new.entries), only, ignore);
+    let mut out = io::stdout().lock();
+    match format {
+        OutputFormat::Text => {
+            print_changes(&mut out, &changes)?;
+            if old.header.chunk_size.is_some() && old.header.chunk_size == new.header.chunk_size {
+                print_chunk_changes(&mut out, &changes, &old.entries, &new.entries)?;
+            }
+            print_similar_files(&mut out, &changes, &old.entries, &new.entries)?;
+            Ok(())
+        }
+        OutputFormat::Junit => {
+            let root = new.header.root.as_ref().map(PathBuf::from).unwrap_or_else(|| fallback.to_path_buf());
+            print_junit(&mut out, &changes, &root)
+        }
+        OutputFormat::Porcelain => print_porcelain(&mut out, &changes),
+    }
+}
+
+pub(crate) fn print_changes(out: &mut impl Write, changes: &[Change]) -> Result<()> {
+    for c in changes {
+        writeln!(out, "{}: {}", c.tag(), c.path())?;
+    }
+    Ok(())
+}
+
+/// Porcelain v1: `<status>\t<escaped path>\n`, see README. Must never change.
+pub(crate) fn print_porcelain(out: &mut impl Write, changes: &[Change]) -> Result<()> {
+    for c in changes {
+        writeln!(out, "{}\t{}", c.tag(), escape_path(c.path()))?;
+    }
+    Ok(())
+}
+
+pub(crate) fn print_junit(out: &mut impl Write, changes: &[Change], root: &Path) -> Result<()> {
+    let suite = xml_escape(&root.to_string_lossy());
+    let tests = changes.len().max(1);
+    // Low-severity changes are reported as passing test cases with a note.
+    let failures = changes.iter().filter(|c| c.kind().severity() != "low").count();
+
+    writeln!(out, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(
+        out,
+        r#"<testsuites name="fast-hash-index" tests="{tests}" failures="{failures}">"#
+    )?;
+    writeln!(
+        out,
+        r#"  <testsuite name="{suite}" tests="{tests}" failures="{failures}" errors="0" skipped="0">"#
+    )?;
+
+    if changes.is_empty() {
+        writeln!(out, r#"    <testcase classname="{suite}" name="no changes"/>"#)?;
+    }
+    for c in changes {
+        let kind = c.kind().name();
+        let name = xml_escape(c.path());
+        writeln!(out, r#"    <testcase classname="{suite}" name="{name}">"#)?;
+        if c.kind().severity() == "low" {
+            writeln!(out, "      <system-out>File {kind}: {name}</system-out>")?;
+        } else {
+            writeln!(
+                out,
+                r#"      <failure type="{kind}" message="File {kind}: {name}"/>"#
+            )?;
+        }
+        writeln!(out, "    </testcase>")?;
+    }
+
+    writeln!(out, "  </testsuite>")?;
+    writeln!(out, "</testsuites>")?;
+    Ok(())
+}
+
+fn xml_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for ch in s.chars() {
+        match ch {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            // Control characters are not representable in XML 1.0.
+            c if (c as u32) < 0x20 && !matches!(c, '\t' | '\n' | '\r') => out.push('\u{FFFD}'),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+pub(crate) fn print_top_changes(
+    out: &mut impl Write,
+    changes: &[Change],
+    new: &HashMap<String, Entry>,
+    n: usize,
+) -> Result<()> {
+    let mut sized: Vec<(&Change, u64)> = changes
+        .iter()
+        .filter(|c| matches!(c, Change::Added(_) | Change::Updated(_) | Change::SizeAnomaly(_)))
+        .filter_map(|c| new.get(c.path()).map(|e| (c, e.size)))
+        .collect();
+    sized.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.path().cmp(b.0.path())));
+    sized.truncate(n);
+
+    writeln!(out, "Top {} added/updated files by size:", sized.len())?;
+    for (c, size) in sized {
+        writeln!(out, "{:>10}  {}: {}", format_size(size), c.tag(), c.path())?;
+    }
+    Ok(())
+}
+
+/// For added files with a similarity digest (`--similarity`), the most similar deleted file,
+/// so renames with edits show up. Each deleted file is matched at most once, best pairs first.
+pub(crate) fn print_similar_files(
+    out: &mut impl Write,
+    changes: &[Change],
+    old: &HashMap<String, Entry>,
+    new: &HashMap<String, Entry>,
+) -> Result<()> {
+    let digests = |kind: ChangeKind, map: &HashMap<String, Entry>| -> Vec<(String, String)> {
+        changes
+            .iter()
+            .filter(|c| c.kind() == kind)
+            .filter_map(|c| Some((c.path().to_string(), map.get(c.path())?.tlsh.clone()?)))
+            .collect()
+    };
+    let added = digests(ChangeKind::Added, new);
+    let deleted = digests(ChangeKind::Deleted, old);
+    if added.is_empty() || deleted.is_empty() {
+        return Ok(());
+    }
+
+    let mut pairs: Vec<(u32, &str, &str)> = added
+        .par_iter()
+        .flat_map_iter(|(a, a_digest)| {
+            deleted.iter().filter_map(move |(d, d_digest)| {
+                let percent = similarity_percent(a_digest, d_digest)?;
+                (percent >= MIN_SIMILARITY).then_some((percent, a.as_str(), d.as_str()))
+            })
+        })
+        .collect();
+    pairs.sort_by(|x, y| y.0.cmp(&x.0).then_with(|| (x.1, x.2).cmp(&(y.1, y.2))));
+    let (mut used_added, mut used_deleted) = (HashSet::new(), HashSet::new());
+    let mut matches = Vec::new();
+    for (percent, a, d) in pairs {
+        if used_added.contains(a) || used_deleted.contains(d) {
+            continue;
+        }
+        used_added.insert(a);
+        used_deleted.insert(d);
+        matches.push((a, d, percent));
+    }
+    if matches.is_empty() {
+        return Ok(());
+    }
+    matches.sort();
+
+    writeln!(out, "Added files similar to deleted ones:")?;
+    for (a, d, percent) in matches {
+        writeln!(out, "{percent:>4}%  {a} <- {d}")?;
+    }
+    Ok(())
+}
+
+/// For updated files with chunks on both sides, how many bytes are in chunks that the old
+/// version did not have.
+pub(crate) fn print_chunk_changes(
+    out: &mut impl Write,
+    changes: &[Change],
+    old: &HashMap<String, Entry>,
+    new: &HashMap<String, Entry>,
+) -> Result<()> {
+    let mut rows = Vec::new();
+    for c in changes.iter().filter(|c| matches!(c, Change::Updated(_) | Change::SizeAnomaly(_))) {
+        let (Some(o), Some(n)) = (old.get(c.path()), new.get(c.path())) else {
+            continue;
+        };
+        let (Some(old_chunks), Some(new_chunks)) = (&o.chunks, &n.chunks) else {
+            continue;
+        };
+        if o.algo != n.algo {
+            continue;
+        }
+        let known: HashSet<&str> = old_chunks.iter().map(|c| c.hash.as_str()).collect();
+        let changed: Vec<&Chunk> = new_chunks.iter().filter(|c| !known.contains(c.hash.as_str())).collect();
+        let changed_bytes: u64 = changed.iter().map(|c| c.len).sum();
+        rows.push((c.path(), changed_bytes, n.size, changed.len(), new_chunks.len()));
+    }
+    if rows.is_empty() {
+        return Ok(());
+    }
+
+    writeln!(out, "Changed content of updated files (by chunk):")?;
+    for (path, changed, size, changed_chunks, total_chunks) in rows {
+        writeln!(
+            out,
+            "{:>10} of {:>10} ({changed_chunks}/{total_chunks} chunks)  {path}",
+            format_size(changed),
+            format_size(size)
+        )?;
+    }
+    Ok(())
+}
+
+pub(crate) fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 6] = ["B", "KiB", "MiB", "GiB", "TiB", "PiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{value:.1} {}", UNITS[unit])
+    }
+}
+
+pub(crate) fn display_dir(dir: &str) -> String {
+    if dir.is_empty() {
+        "./".to_string()
+    } else {
+        format!("{dir}/")
+    }
+}
+
+pub(crate) fn print_grouped_changes(
+    out: &mut impl Write,
+    changes: &[Change],
+    old: &HashMap<String, Entry>,
+    new: &HashMap<String, Entry>,
+) -> Result<()> {
+    // Number of files known (old or new) under every directory, recursively.
+    let mut totals: HashMap<&str, usize> = HashMap::new();
+    for path in new.keys().chain(old.keys().filter(|k| !new.contains_key(*k))) {
+        for dir in ancestor_dirs(path) {
+            *totals.entry(dir).or_default() += 1;
+        }
+    }
+
+    let mut subtree: BTreeMap<&str, ChangeCounts> = BTreeMap::new();
+    for c in changes {
+        for dir in ancestor_dirs(c.path()) {
+            subtree.entry(dir).or_default().record(c);
+        }
+    }
+
+    // Collapse the outermost directories in which every file changed.
+    let mut collapsed: BTreeMap<&str, ChangeCounts> = BTreeMap::new();
+    for (dir, counts) in &subtree {
+        let fully_changed = totals.get(dir).copied().unwrap_or(0) == counts.total();
+        if fully_changed && !ancestor_dirs(dir).any(|a| collapsed.contains_key(a)) {
+            collapsed.insert(dir, *counts);
+        }
+    }
+
+    let mut groups: BTreeMap<&str, ChangeCounts> = BTreeMap::new();
+    for c in changes {
+        let path = c.path();
+        if ancestor_dirs(path).any(|a| collapsed.contains_key(a)) {
+            continue;
+        }
+        groups.entry(parent_dir(path)).or_default().record(c);
+    }
+
+    let mut lines: BTreeMap<&str, String> = BTreeMap::new();
+    for (dir, counts) in collapsed {
+        let line = format!(
+            "{}**: all {} files changed ({counts})",
+            display_dir(dir),
+            counts.total()
+        );
+        lines.insert(dir, line);
+    }
+    for (dir, counts) in groups {
+        let line = format!("{}: {} changes ({counts})", display_dir(dir), counts.total());
+        lines.insert(dir, line);
+    }
+
+    for line in lines.values() {
+        writeln!(out, "{line}")?;
+    }
+    Ok(())
+}
+
+/// Most changes listed in a `--notify-url` payload; the others are only counted, so that a
+/// large change set does not make a payload the webhook rejects.
+const NOTIFY_MAX_CHANGES: usize = 100;
+
+pub(crate) fn notify_changes(url: &str, root: &Path, changes: &[Change]) -> Result<()> {
+    let mut counts = ChangeCounts::default();
+    for c in changes {
+        counts.record(c);
+    }
+    let list: Vec<_> = changes
+        .iter()
+        .take(NOTIFY_MAX_CHANGES)
+        .map(|c| serde_json::json!({ "type": c.kind().name(), "severity": c.kind().severity(), "path": c.path() }))
+        .collect();
+
+    let root_str = root.to_string_lossy();
+    let payload = serde_json::json!({
+        "text": format!("fast-hash-index: {} changes in {root_str} ({counts})", counts.total()),
+        "root": root_str,
+        "added": counts.added,
+        "updated": counts.updated,
+        "size_anomaly": counts.size_anomaly,
+        "permissions": counts.permissions,
+        "owner": counts.owner,
+        "xattrs": counts.xattrs,
+        "metadata": counts.metadata,
+        "touched": counts.touched,
+        "deleted": counts.deleted,
+        "changes": list,
+        "omitted": changes.len() - list.len(),
+    });
+
+    ureq::post(url)
+        .header("Content-Type", "application/json")
+        .send(payload.to_string())
+        .with_context(|| format!("Failed to send notification to {url}"))?;
+
+    Ok(())
+}
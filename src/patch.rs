@@ -0,0 +1,45 @@
+//! Patches written by `--emit-patch` and read by `apply`.
+
+use crate::sync::SyncOp;
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Component, Path, PathBuf};
+
+pub(crate) const PATCH_VERSION: u32 = 1;
+
+/// First line of a patch file.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct PatchHeader {
+    #[serde(rename = "fast-hash-index-patch")]
+    pub(crate) version: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) root: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) algo: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) key_id: Option<String>,
+}
+
+pub(crate) fn write_patch(path: &Path, header: &PatchHeader, ops: &[SyncOp]) -> Result<()> {
+    let file = File::create(path).with_context(|| format!("Failed to create patch file: {path:?}"))?;
+    let mut out = io::BufWriter::new(file);
+    serde_json::to_writer(&mut out, header)?;
+    writeln!(out)?;
+    for op in ops {
+        serde_json::to_writer(&mut out, op)?;
+        writeln!(out)?;
+    }
+    out.flush().with_context(|| format!("Failed to write patch file: {path:?}"))?;
+    Ok(())
+}
+
+/// A path of a patch, which must stay inside the directory it is applied to.
+pub(crate) fn patch_path(base: &Path, rel: &str) -> Result<PathBuf> {
+    let rel_path = Path::new(rel);
+    if rel.is_empty() || !rel_path.components().all(|c| matches!(c, Component::Normal(_))) {
+        return Err(anyhow!("Invalid path in patch: {rel:?}"));
+    }
+    Ok(base.join(rel_path))
+}
@@ -0,0 +1,660 @@
+//! Hashing the collected files into entries, reusing the previous state where possible.
+
+use crate::encoding::hex_encode;
+use crate::hash::{
+    algo_name, hash_file_chunked, hash_file_multi, hash_file_sampled, hash_files_uring,
+    hash_key_id, new_hasher, Algo, IoEngine, ReadOptions, Sampling, UNHASHED,
+};
+use crate::state::{Entry, EntryKind, StateHeader};
+use crate::sync::SyncOptions;
+use crate::walk::{disk_path, path_to_rel_unix, PathForm};
+use anyhow::{anyhow, Context, Result};
+use rayon::prelude::*;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self};
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// What to record for every file besides size, timestamp and content hash.
+pub(crate) struct ScanOptions {
+    pub(crate) algo: Algo,
+    /// Additional algorithms computed in the same read (`--algo a,b,...`); never `algo`.
+    pub(crate) also: Vec<Algo>,
+    pub(crate) owner: bool,
+    pub(crate) xattrs: bool,
+    /// Record [`metadata_hash`] (`--meta-hash`).
+    pub(crate) meta_hash: bool,
+    /// BLAKE3 key for keyed hashing (`--hash-key`).
+    pub(crate) key: Option<[u8; 32]>,
+    pub(crate) sampling: Sampling,
+    /// Average FastCDC chunk size (`--chunks`).
+    pub(crate) chunking: Option<u32>,
+    /// Files larger than this are not read (`--hash-max-size`).
+    pub(crate) hash_max_size: Option<u64>,
+    pub(crate) read: ReadOptions,
+    /// Report files that cannot be read instead of failing (`--keep-going`).
+    pub(crate) keep_going: bool,
+}
+
+/// Digests of the previous state, reused for files whose size, modification time and (where
+/// recorded) status change time and inode are unchanged (unless `--paranoid`).
+pub(crate) struct HashCache<'a> {
+    entries: &'a HashMap<String, Entry>,
+    /// Algorithm of entries that do not record their own.
+    algo: Option<&'a str>,
+    chunk_size: Option<u32>,
+    /// Normalization of the recorded paths.
+    paths: PathForm,
+    /// Scan time of the previous state. Files modified (or changed) at or after it may have
+    /// changed again within the timestamp granularity of the file system, so they are always
+    /// rehashed.
+    scanned_ns: u64,
+}
+
+impl<'a> HashCache<'a> {
+    /// Returns `None` if the previous digests cannot be reused: computed with another key, for
+    /// another directory, or without a recorded scan time.
+    pub(crate) fn new(
+        entries: &'a HashMap<String, Entry>,
+        header: &'a StateHeader,
+        root: &Path,
+        key_id: Option<&String>,
+        paths: PathForm,
+    ) -> Option<Self> {
+        if header.key_id.as_ref() != key_id || header.root.as_deref() != Some(&*root.to_string_lossy()) {
+            return None;
+        }
+        Some(HashCache {
+            entries,
+            algo: header.algo.as_deref(),
+            chunk_size: header.chunk_size,
+            paths,
+            scanned_ns: header.scanned_at?.saturating_mul(1_000_000_000),
+        })
+    }
+
+    /// The recorded entry of `rel` if this run would compute its digest (and chunks) the same
+    /// way and the file's size and timestamps did not change since (nor, if recorded, its inode).
+    fn lookup(&self, rel: &str, meta: &fs::Metadata, scan: &ScanOptions) -> Option<&'a Entry> {
+        let old = self.entries.get(self.paths.apply(rel).as_ref())?;
+        let mtime_ns = file_mtime_ns(meta)?;
+        let ctime_ns = file_ctime_ns(meta);
+        let size = meta.len();
+        let partial = if scan.sampling.applies(size) { scan.sampling.marker() } else { None };
+        // Entries recorded before ctime and inodes were only have size and mtime.
+        let same_ctime = old.ctime_ns.is_none() || old.ctime_ns == ctime_ns;
+        let same_inode = old.ino.is_none() || (old.dev, old.ino) == file_inode(meta).unzip();
+        let reusable = old.kind.is_file()
+            && old.size == size
+            && old.mtime_ns == Some(mtime_ns)
+            && same_ctime
+            && same_inode
+            && mtime_ns.max(ctime_ns.unwrap_or(0)) < self.scanned_ns
+            && old.algo.as_deref().or(self.algo) == Some(algo_name(scan.algo))
+            && old.partial == partial
+            && scan.also.iter().all(|&a| old.hashes.as_ref().is_some_and(|h| h.contains_key(algo_name(a))))
+            && (scan.chunking.is_none() || (scan.chunking == self.chunk_size && old.chunks.is_some()));
+        reusable.then_some(old)
+    }
+}
+
+impl ScanOptions {
+    /// Whether a file of `size` bytes is recorded without a content digest.
+    fn unhashed(&self, size: u64) -> bool {
+        self.hash_max_size.is_some_and(|max| size > max)
+    }
+
+    /// `algo` followed by `also`, the order of the digests of [`hash_file_multi`].
+    pub(crate) fn algos(&self) -> Vec<Algo> {
+        std::iter::once(self.algo).chain(self.also.iter().copied()).collect()
+    }
+
+    /// Splits digests in [`ScanOptions::algos`] order into the primary one and the others.
+    fn split_digests(&self, mut digests: Vec<String>) -> (String, Option<BTreeMap<String, String>>) {
+        let others: BTreeMap<String, String> = self
+            .also
+            .iter()
+            .map(|&a| algo_name(a).to_string())
+            .zip(digests.drain(1..))
+            .collect();
+        (digests.remove(0), (!others.is_empty()).then_some(others))
+    }
+}
+
+/// Hashes `files`. With `--keep-going`, the files that cannot be read are returned apart
+/// (with their relative path) instead of failing.
+pub(crate) fn hash_entries(
+    root: &Path,
+    files: &[PathBuf],
+    scan: &ScanOptions,
+    cache: Option<&HashCache>,
+) -> Result<(Vec<Entry>, Vec<FileError>)> {
+    let cached = |abs_path: &Path, meta: &fs::Metadata| {
+        cache.and_then(|c| c.lookup(&path_to_rel_unix(root, abs_path), meta, scan))
+    };
+    let aliases = hard_link_aliases(files);
+    // io_uring reads all contents up front; the per-file work below then only stats.
+    let prehashed = match scan.read.engine {
+        IoEngine::Std => None,
+        IoEngine::Uring if scan.chunking.is_some() => None,
+        IoEngine::Uring => Some(hash_files_uring(files, scan, &|i, meta| {
+            aliases[i].is_some()
+                || scan.sampling.applies(meta.len())
+                || scan.unhashed(meta.len())
+                || cached(&files[i], meta).is_some()
+        })?),
+    };
+
+    let failed = Mutex::new(Vec::new());
+    let hash_one = |i: usize, abs_path: &PathBuf| -> Result<Option<Entry>> {
+        if aliases[i].is_some() {
+            return Ok(None);
+        }
+        let rel = path_to_rel_unix(root, abs_path);
+
+        let meta = fs::metadata(abs_path)
+            .with_context(|| format!("Failed to read metadata for {abs_path:?}"))?;
+        let size = meta.len();
+        let tstamp = file_timestamp(&meta);
+
+        let partial = scan.sampling.applies(size);
+        let unhashed = scan.unhashed(size);
+        let (hash_hex, hashes, chunks) = if unhashed {
+            (String::new(), None, None)
+        } else if let Some(old) = cached(abs_path, &meta) {
+            let names: Vec<&str> = scan.also.iter().map(|&a| algo_name(a)).collect();
+            let hashes = old.hashes.clone().map(|mut h| {
+                h.retain(|algo, _| names.contains(&algo.as_str()));
+                h
+            });
+            let hashes = hashes.filter(|h| !h.is_empty());
+            let chunks = old.chunks.clone().filter(|_| scan.chunking.is_some());
+            (old.hash_hex.clone(), hashes, chunks)
+        } else if let Some(avg) = scan.chunking {
+            let (digests, chunks) = hash_file_chunked(abs_path, &scan.algos(), scan.key.as_ref(), avg, &scan.read)?;
+            let (hash, hashes) = scan.split_digests(digests);
+            (hash, hashes, Some(chunks))
+        } else {
+            let digests = match prehashed.as_ref().and_then(|hashes| hashes[i].as_ref()) {
+                Some(Ok(digests)) if !partial => digests.clone(),
+                Some(Err(err)) => {
+                    return Err(anyhow!(
+                        "Failed to read for hashing ({}): {abs_path:?}: {err}",
+                        algo_name(scan.algo)
+                    ));
+                }
+                _ if !scan.also.is_empty() => {
+                    hash_file_multi(abs_path, &scan.algos(), scan.key.as_ref(), &scan.read)?
+                }
+                _ => vec![hash_file_sampled(
+                    abs_path,
+                    size,
+                    scan.algo,
+                    scan.key.as_ref(),
+                    scan.sampling,
+                    &scan.read,
+                )?],
+            };
+            let (hash, hashes) = scan.split_digests(digests);
+            (hash, hashes, None)
+        };
+
+        let (dev, ino) = file_inode(&meta).unzip();
+        let (uid, gid) = match file_owner(&meta) {
+            Some((uid, gid)) if scan.owner => (Some(uid), Some(gid)),
+            _ => (None, None),
+        };
+        let all_xattrs = if scan.xattrs || scan.meta_hash {
+            file_xattrs(abs_path)
+        } else {
+            None
+        };
+        let meta_hash = scan
+            .meta_hash
+            .then(|| metadata_hash(abs_path, &meta, all_xattrs.as_ref(), scan.key.as_ref()));
+        let xattrs = all_xattrs.filter(|_| scan.xattrs);
+
+        Ok(Some(Entry {
+            rel_path: rel,
+            size,
+            tstamp,
+            hash_hex,
+            kind: EntryKind::File,
+            algo: Some(algo_name(scan.algo).to_string()),
+            hashes,
+            partial: if unhashed {
+                Some(UNHASHED.to_string())
+            } else if partial {
+                scan.sampling.marker()
+            } else {
+                None
+            },
+            chunks,
+            mtime_ns: file_mtime_ns(&meta),
+            ctime_ns: file_ctime_ns(&meta),
+            dev,
+            ino,
+            nlink: file_nlink(&meta).filter(|&n| n > 1),
+            hardlink: None,
+            mode: file_mode(&meta),
+            uid,
+            gid,
+            xattrs,
+            meta: meta_hash,
+            target: None,
+            special: None,
+            tlsh: None,
+        }))
+    };
+    let results: Result<Vec<_>> = files
+        .par_iter()
+        .enumerate()
+        .map(|(i, abs_path)| match hash_one(i, abs_path) {
+            Err(error) if scan.keep_going => {
+                let path = path_to_rel_unix(root, abs_path);
+                failed.lock().unwrap().push(FileError { path, error });
+                Ok(None)
+            }
+            result => result,
+        })
+        .collect();
+
+    let mut results = results?;
+    let mut failed = failed.into_inner().unwrap();
+    // Every other link to a file shares its entry, except for the path.
+    for (i, first) in aliases.iter().enumerate() {
+        if let Some(first) = *first {
+            let path = path_to_rel_unix(root, &files[i]);
+            match results[first].clone() {
+                Some(mut e) => {
+                    e.hardlink = Some(e.rel_path);
+                    e.rel_path = path;
+                    results[i] = Some(e);
+                }
+                None => {
+                    let error = anyhow!("Its hard link {:?} could not be read", files[first]);
+                    failed.push(FileError { path, error });
+                }
+            }
+        }
+    }
+    let mut entries: Vec<Entry> = results.into_iter().flatten().collect();
+    entries.sort_by(|a, b| a.rel_path.cmp(&b.rel_path));
+    failed.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok((entries, failed))
+}
+
+/// A file that could not be read or synced, with `--keep-going`.
+#[derive(Debug)]
+pub(crate) struct FileError {
+    pub(crate) path: String,
+    error: anyhow::Error,
+}
+
+/// Prints the files that could not be read or synced, and returns the error ending the run
+/// once everything else is done.
+pub(crate) fn report_file_errors(failed: &[FileError]) -> Result<()> {
+    if failed.is_empty() {
+        return Ok(());
+    }
+    eprintln!("{} files could not be read or synced:", failed.len());
+    for f in failed {
+        eprintln!("  {}: {:#}", f.path, f.error);
+    }
+    Err(Incomplete { failed: failed.len() }.into())
+}
+
+/// The error of a run that completed except for some files (`--keep-going`); the process
+/// exits with code 3 instead of 1.
+#[derive(Debug)]
+pub struct Incomplete {
+    pub failed: usize,
+}
+
+impl std::fmt::Display for Incomplete {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} files could not be read or synced (--keep-going)", self.failed)
+    }
+}
+
+impl std::error::Error for Incomplete {}
+
+/// For each file that is a hard link to an earlier one (by path) of `files`, the index of
+/// that one, so their shared content is only read once.
+fn hard_link_aliases(files: &[PathBuf]) -> Vec<Option<usize>> {
+    let mut aliases = vec![None; files.len()];
+    if !cfg!(unix) {
+        return aliases;
+    }
+    let ids: Vec<Option<(u64, u64)>> = files
+        .par_iter()
+        .map(|path| {
+            let meta = fs::metadata(path).ok()?;
+            if file_nlink(&meta)? > 1 {
+                file_inode(&meta)
+            } else {
+                None
+            }
+        })
+        .collect();
+    let mut linked: Vec<usize> = (0..files.len()).filter(|&i| ids[i].is_some()).collect();
+    linked.sort_by(|&a, &b| files[a].cmp(&files[b]));
+    let mut first: HashMap<(u64, u64), usize> = HashMap::new();
+    for i in linked {
+        if let Some(id) = ids[i] {
+            match first.get(&id) {
+                Some(&f) => aliases[i] = Some(f),
+                None => {
+                    first.insert(id, i);
+                }
+            }
+        }
+    }
+    aliases
+}
+
+#[cfg(unix)]
+fn file_nlink(meta: &fs::Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Some(meta.nlink())
+}
+
+#[cfg(not(unix))]
+fn file_nlink(_meta: &fs::Metadata) -> Option<u64> {
+    None
+}
+
+fn file_timestamp(meta: &fs::Metadata) -> u64 {
+    let created = meta.created().ok();
+    let modified = meta.modified().ok();
+
+    let ts = created.or(modified).unwrap_or(SystemTime::UNIX_EPOCH);
+    ts.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+pub(crate) fn file_mtime_ns(meta: &fs::Metadata) -> Option<u64> {
+    let since_epoch = meta.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    u64::try_from(since_epoch.as_nanos()).ok()
+}
+
+#[cfg(unix)]
+fn file_ctime_ns(meta: &fs::Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    let secs = u64::try_from(meta.ctime()).ok()?;
+    secs.checked_mul(1_000_000_000)?.checked_add(meta.ctime_nsec() as u64)
+}
+
+#[cfg(not(unix))]
+fn file_ctime_ns(_meta: &fs::Metadata) -> Option<u64> {
+    None
+}
+
+/// Device and inode number.
+#[cfg(unix)]
+fn file_inode(meta: &fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((meta.dev(), meta.ino()))
+}
+
+#[cfg(not(unix))]
+fn file_inode(_meta: &fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+/// Identity of the file or directory `path` resolves to: device and inode on Unix, volume
+/// serial number and file index on Windows.
+#[cfg(windows)]
+pub(crate) fn file_id(path: &Path) -> Option<(u64, u64)> {
+    let handle = winapi_util::Handle::from_path_any(path).ok()?;
+    let info = winapi_util::file::information(&handle).ok()?;
+    Some((info.volume_serial_number(), info.file_index()))
+}
+
+#[cfg(not(windows))]
+pub(crate) fn file_id(path: &Path) -> Option<(u64, u64)> {
+    fs::metadata(path).ok().as_ref().and_then(file_inode)
+}
+
+#[cfg(unix)]
+pub(crate) fn file_mode(meta: &fs::Metadata) -> Option<u32> {
+    Some(PermissionsExt::mode(&meta.permissions()) & 0o7777)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn file_mode(_meta: &fs::Metadata) -> Option<u32> {
+    None
+}
+
+#[cfg(unix)]
+pub(crate) fn file_owner(meta: &fs::Metadata) -> Option<(u32, u32)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((meta.uid(), meta.gid()))
+}
+
+#[cfg(not(unix))]
+pub(crate) fn file_owner(_meta: &fs::Metadata) -> Option<(u32, u32)> {
+    None
+}
+
+#[cfg(unix)]
+fn file_xattrs(path: &Path) -> Option<BTreeMap<String, String>> {
+    let names = match xattr::list(path) {
+        Ok(names) => names,
+        Err(err) => {
+            eprintln!("Warning: failed to list extended attributes of {path:?}: {err}");
+            return None;
+        }
+    };
+    let mut attrs = BTreeMap::new();
+    for name in names {
+        match xattr::get(path, &name) {
+            Ok(value) => {
+                let value = value.map(|v| hex_encode(&v)).unwrap_or_default();
+                attrs.insert(name.to_string_lossy().into_owned(), value);
+            }
+            Err(err) => eprintln!("Warning: failed to read extended attribute {name:?} of {path:?}: {err}"),
+        }
+    }
+    Some(attrs)
+}
+
+#[cfg(not(unix))]
+fn file_xattrs(_path: &Path) -> Option<BTreeMap<String, String>> {
+    None
+}
+
+/// BLAKE3 digest (keyed with `--hash-key`) of the permission bits, owner and group, extended
+/// attributes and, if `path` itself is a symlink (`--follow-symlinks`), its target. Fields the
+/// platform does not have are left out. It is independent of `--owner` and `--xattrs`, so
+/// metadata changes are noticed even where the values themselves are not recorded.
+pub(crate) fn metadata_hash(
+    path: &Path,
+    meta: &fs::Metadata,
+    xattrs: Option<&BTreeMap<String, String>>,
+    key: Option<&[u8; 32]>,
+) -> String {
+    let mut hasher = new_hasher(Algo::Blake3, key);
+    // Tagged, length-prefixed fields, so no two different sets of metadata hash alike.
+    let mut field = |tag: u8, value: &[u8]| {
+        hasher.update(&[tag]);
+        hasher.update(&(value.len() as u64).to_le_bytes());
+        hasher.update(value);
+    };
+    if let Some(mode) = file_mode(meta) {
+        field(b'm', &mode.to_le_bytes());
+    }
+    if let Some((uid, gid)) = file_owner(meta) {
+        field(b'u', &uid.to_le_bytes());
+        field(b'g', &gid.to_le_bytes());
+    }
+    for (name, value) in xattrs.into_iter().flatten() {
+        field(b'x', name.as_bytes());
+        field(b'v', value.as_bytes());
+    }
+    if let Ok(target) = fs::read_link(path) {
+        field(b'l', target.as_os_str().as_encoded_bytes());
+    }
+    hasher.finalize_hex()
+}
+
+pub(crate) fn hostname() -> Option<String> {
+    gethostname::gethostname().into_string().ok().filter(|h| !h.is_empty())
+}
+
+/// Entries hashed with another algorithm or sampling than this run's cannot be compared by
+/// digest. Rehashes those files the way they were recorded and, if the content is unchanged,
+/// carries the new digest over to the old entry so the diff does not report them as updated.
+/// Returns the number of files that were rehashed.
+pub(crate) fn reconcile_algorithms(
+    root: &Path,
+    disk_paths: &HashMap<String, PathBuf>,
+    old: &mut HashMap<String, Entry>,
+    old_header: &StateHeader,
+    new: &HashMap<String, Entry>,
+    scan: &ScanOptions,
+) -> usize {
+    let current = algo_name(scan.algo);
+    // Digests of this run's algorithm may already have been stored next to the old ones.
+    for (path, e) in old.iter_mut() {
+        let recorded = e.algo.as_deref().or(old_header.algo.as_deref());
+        if recorded == Some(current) || e.partial.is_some() || !new.contains_key(path) {
+            continue;
+        }
+        if let Some(hash) = e.hashes.as_ref().and_then(|h| h.get(current)).cloned() {
+            e.hash_hex = hash;
+            e.algo = Some(current.to_string());
+        }
+    }
+    let pending: Vec<(&String, Algo, Sampling)> = old
+        .iter()
+        .filter(|(_, e)| e.kind.is_file())
+        .filter_map(|(path, e)| {
+            let n = new.get(path)?;
+            let recorded = e.algo.as_deref().or(old_header.algo.as_deref())?;
+            // Entries carried over from the previous state were not rehashed by this run.
+            if recorded == n.algo.as_deref().unwrap_or(current) && e.partial == n.partial {
+                return None;
+            }
+            // Files above --hash-max-size are not read, not even to compare them.
+            if n.is_unhashed() {
+                return None;
+            }
+            let algo = Algo::from_name(recorded)?;
+            Some((path, algo, Sampling::from_marker(e.partial.as_deref())?))
+        })
+        .collect();
+    if pending.is_empty() {
+        return 0;
+    }
+    // The key only applies if the old digests were computed with the same one.
+    let key = scan.key.as_ref().filter(|k| old_header.key_id == Some(hash_key_id(k)));
+
+    let unchanged: Vec<String> = pending
+        .par_iter()
+        .filter(|(path, algo, sampling)| {
+            let abs = disk_path(root, disk_paths, path);
+            let size = new[path.as_str()].size;
+            hash_file_sampled(&abs, size, *algo, key, *sampling, &scan.read)
+                .is_ok_and(|h| h == old[path.as_str()].hash_hex)
+        })
+        .map(|(path, _, _)| (*path).clone())
+        .collect();
+    let rehashed = pending.len();
+    for path in unchanged {
+        if let (Some(o), Some(n)) = (old.get_mut(&path), new.get(&path)) {
+            o.hash_hex = n.hash_hex.clone();
+            o.algo = n.algo.clone();
+            o.partial = n.partial.clone();
+        }
+    }
+    rehashed
+}
+
+/// With `--keep-going`, adds the error of `result` (failing to `what`, about `path`) to
+/// `failed` and returns `None` instead.
+pub(crate) fn keep_going<T>(
+    result: Result<T>,
+    what: impl std::fmt::Display,
+    path: &str,
+    opts: &SyncOptions,
+    failed: &Mutex<Vec<FileError>>,
+) -> Result<Option<T>> {
+    match result {
+        Err(error) if opts.keep_going => {
+            eprintln!("Warning: failed to {what}: {error:#}");
+            failed.lock().unwrap().push(FileError { path: path.to_string(), error });
+            Ok(None)
+        }
+        result => result.map(Some),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::default_buffer_size;
+    use crate::test_dir::TestDir;
+    use crate::timestamps::unix_now;
+
+    #[cfg(unix)]
+    #[test]
+    fn hash_cache_reuses_digests_of_unchanged_files_only() {
+        let dir = TestDir::new();
+        let file = dir.write("a", "one\n");
+        let scan = ScanOptions {
+            algo: Algo::Blake3,
+            also: Vec::new(),
+            owner: false,
+            xattrs: false,
+            meta_hash: false,
+            key: None,
+            sampling: Sampling::Full,
+            chunking: None,
+            hash_max_size: None,
+            read: ReadOptions {
+                mmap: false,
+                engine: IoEngine::Std,
+                buffer_size: default_buffer_size(IoEngine::Std),
+                drop_cache: false,
+                bwlimit: None,
+            },
+            keep_going: false,
+        };
+        let scan_with = |entries: &HashMap<String, Entry>, header: &StateHeader| {
+            let cache = HashCache::new(entries, header, dir.path(), None, PathForm::Nfc);
+            let (mut entries, _) = hash_entries(dir.path(), std::slice::from_ref(&file), &scan, cache.as_ref()).unwrap();
+            entries.remove(0)
+        };
+        let header = StateHeader {
+            algo: Some("blake3".to_string()),
+            root: Some(dir.path().to_string_lossy().into_owned()),
+            // As if scanned after the file was last written.
+            scanned_at: Some(unix_now() + 1),
+            ..Default::default()
+        };
+        let fresh = scan_with(&HashMap::new(), &header);
+
+        // A recorded digest that no longer matches the content shows when it is reused.
+        let mut recorded = fresh.clone();
+        recorded.hash_hex = "f".repeat(64);
+        let previous = HashMap::from([("a".to_string(), recorded)]);
+        assert_eq!(scan_with(&previous, &header).hash_hex, "f".repeat(64));
+
+        let other_root = StateHeader { root: Some("/elsewhere".to_string()), ..header.clone() };
+        assert_eq!(scan_with(&previous, &other_root).hash_hex, fresh.hash_hex);
+        let scanned_before = StateHeader { scanned_at: Some(1), ..header.clone() };
+        assert_eq!(scan_with(&previous, &scanned_before).hash_hex, fresh.hash_hex);
+
+        // Same size and modification time, but rewritten: the status change time tells.
+        let mtime = filetime::FileTime::from_last_modification_time(&fs::metadata(&file).unwrap());
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        fs::write(&file, "two\n").unwrap();
+        filetime::set_file_mtime(&file, mtime).unwrap();
+        let rewritten = scan_with(&previous, &header);
+        assert_ne!(rewritten.hash_hex, "f".repeat(64));
+        assert_ne!(rewritten.hash_hex, fresh.hash_hex);
+    }
+}
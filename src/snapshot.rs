@@ -0,0 +1,85 @@
+//! History stores of state snapshots (`--history`), read by `diff` and `prune`.
+
+use crate::state::{write_state_file, State, StateFormat};
+use crate::timestamps::{format_utc_compact, unix_now};
+use anyhow::{anyhow, Context, Result};
+use std::fs::{self};
+use std::path::{Path, PathBuf};
+
+/// Extension of snapshot files in a history store; their format is auto-detected like any state.
+const SNAPSHOT_EXT: &str = "state";
+
+pub(crate) fn snapshot_path(store: &Path, id: &str) -> PathBuf {
+    store.join(format!("{id}.{SNAPSHOT_EXT}"))
+}
+
+pub(crate) fn validate_snapshot_label(label: &str) -> Result<()> {
+    let valid = !label.is_empty()
+        && label.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'));
+    if !valid {
+        return Err(anyhow!(
+            "Invalid snapshot label {label:?}: use letters, digits, '.', '_' or '-'"
+        ));
+    }
+    Ok(())
+}
+
+/// Stores `state` as `<store>/<id>.state`, where the id is the UTC scan time
+/// (`YYYYMMDDTHHMMSSZ`) optionally followed by `-<label>`, so ids sort chronologically.
+pub(crate) fn write_snapshot(
+    store: &Path,
+    state: &State,
+    format: StateFormat,
+    label: Option<&str>,
+) -> Result<String> {
+    let mut id = format_utc_compact(state.header.scanned_at.unwrap_or_else(unix_now));
+    if let Some(label) = label {
+        id.push('-');
+        id.push_str(label);
+    }
+    let path = snapshot_path(store, &id);
+    if path.exists() {
+        return Err(anyhow!("Snapshot {id} already exists in {store:?}"));
+    }
+    write_state_file(&path, state, format, None)?;
+    Ok(id)
+}
+
+/// Snapshot ids of a history store, oldest first.
+pub(crate) fn list_snapshots(store: &Path) -> Result<Vec<String>> {
+    let mut ids = Vec::new();
+    for entry in fs::read_dir(store).with_context(|| format!("Failed to read history store: {store:?}"))? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == SNAPSHOT_EXT) {
+            if let Some(stem) = path.file_stem().and_then(|s| s.to_str()) {
+                ids.push(stem.to_string());
+            }
+        }
+    }
+    ids.sort();
+    Ok(ids)
+}
+
+/// Resolves `latest`, a full id, a label or a unique id prefix to a snapshot id.
+pub(crate) fn resolve_snapshot(store: &Path, spec: &str) -> Result<String> {
+    let ids = list_snapshots(store)?;
+    if spec == "latest" {
+        return ids.last().cloned().ok_or_else(|| anyhow!("History store {store:?} has no snapshots"));
+    }
+    if ids.iter().any(|id| id == spec) {
+        return Ok(spec.to_string());
+    }
+    let label_suffix = format!("-{spec}");
+    let matches: Vec<&String> = ids
+        .iter()
+        .filter(|id| id.starts_with(spec) || id.ends_with(&label_suffix))
+        .collect();
+    match matches.as_slice() {
+        [id] => Ok((*id).clone()),
+        [] => Err(anyhow!("No snapshot matching {spec:?} in {store:?}")),
+        _ => Err(anyhow!(
+            "Snapshot {spec:?} is ambiguous in {store:?}: {}",
+            matches.iter().map(|s| s.as_str()).collect::<Vec<_>>().join(", ")
+        )),
+    }
+}
@@ -0,0 +1,1412 @@
+//! State files: the entries, and the text, binary, JSON and SQLite formats they are stored in.
+
+use crate::copy::temp_path;
+use crate::encoding::{
+    decode_multihash, encode_multihash, escape_path, hex_decode, hex_encode, is_hex, unescape_path,
+};
+use crate::hash::{algo_name, infer_algo, Algo, UNHASHED};
+use crate::walk::ancestor_dirs;
+use anyhow::{anyhow, Context, Result};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub(crate) enum StateFormat {
+    #[default]
+    Text,
+    Binary,
+    Sqlite,
+    Json,
+}
+
+/// How digests are written in text-based state formats (always hex in memory).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub(crate) enum DigestEncoding {
+    /// Lowercase hex
+    #[default]
+    Hex,
+    /// Multihash, multibase base32 (`b…`), as used by IPFS
+    Multihash,
+}
+
+/// Metadata stored alongside the entries of a state file.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct StateHeader {
+    /// Format version the state was read with (1 for legacy headerless text states).
+    pub(crate) version: u32,
+    pub(crate) algo: Option<String>,
+    pub(crate) root: Option<String>,
+    /// Name of the host that ran the scan.
+    pub(crate) host: Option<String>,
+    pub(crate) scanned_at: Option<u64>,
+    /// Encoding of the digests in the file (`--digest-encoding`).
+    pub(crate) digests: DigestEncoding,
+    /// Fingerprint of the `--hash-key` used for the digests (see [`hash_key_id`](crate::hash::hash_key_id)).
+    pub(crate) key_id: Option<String>,
+    /// Average FastCDC chunk size of the entries' `chunks` (`--chunks`).
+    pub(crate) chunk_size: Option<u32>,
+    /// Merkle root of the entries (see [`tree_hashes`]).
+    pub(crate) tree: Option<String>,
+    /// Directories indexed below `root` when several were given, relative to it.
+    pub(crate) sources: Option<Vec<String>>,
+    /// Hex ed25519 signature over [`signed_bytes`] of the rest of the state.
+    pub(crate) signature: Option<String>,
+}
+
+impl StateHeader {
+    fn to_pairs(&self) -> Vec<(&'static str, String)> {
+        let mut pairs = Vec::new();
+        if let Some(ref algo) = self.algo {
+            pairs.push(("algo", algo.clone()));
+        }
+        if let Some(ref root) = self.root {
+            pairs.push(("root", root.clone()));
+        }
+        if let Some(ref host) = self.host {
+            pairs.push(("host", host.clone()));
+        }
+        if let Some(ts) = self.scanned_at {
+            pairs.push(("scanned", ts.to_string()));
+        }
+        if self.digests == DigestEncoding::Multihash {
+            pairs.push(("digests", "multihash".to_string()));
+        }
+        if let Some(ref id) = self.key_id {
+            pairs.push(("key_id", id.clone()));
+        }
+        if let Some(size) = self.chunk_size {
+            pairs.push(("chunking", format!("fastcdc:{size}")));
+        }
+        if let Some(ref tree) = self.tree {
+            pairs.push(("tree", tree.clone()));
+        }
+        if let Some(ref sources) = self.sources {
+            pairs.push(("sources", serde_json::to_string(sources).unwrap_or_default()));
+        }
+        if let Some(ref sig) = self.signature {
+            pairs.push(("signature", sig.clone()));
+        }
+        pairs
+    }
+
+    fn set(&mut self, key: &str, value: String) {
+        match key {
+            "algo" => self.algo = Some(value),
+            "root" => self.root = Some(value),
+            "host" => self.host = Some(value),
+            "scanned" => self.scanned_at = value.parse().ok(),
+            "digests" => self.digests = DigestEncoding::from_str(&value, true).unwrap_or_default(),
+            "key_id" => self.key_id = Some(value),
+            "tree" => self.tree = Some(value),
+            "sources" => self.sources = serde_json::from_str(&value).ok(),
+            "chunking" => self.chunk_size = value.strip_prefix("fastcdc:").and_then(|v| v.parse().ok()),
+            "signature" => self.signature = Some(value),
+            // Unknown keys come from newer versions; ignore them.
+            _ => {}
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct State {
+    pub(crate) header: StateHeader,
+    pub(crate) entries: HashMap<String, Entry>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum EntryKind {
+    #[default]
+    File,
+    /// Empty directory (`--empty-dirs`); size and hash are empty.
+    Dir,
+    /// Symbolic link or junction that was not followed; the hash is empty and `target` holds
+    /// where it points.
+    Link,
+    /// Socket, FIFO or device node (`--special-files record`); `special` holds which.
+    Special,
+}
+
+impl EntryKind {
+    pub(crate) fn is_file(&self) -> bool {
+        *self == EntryKind::File
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct Entry {
+    #[serde(rename = "path")]
+    pub(crate) rel_path: String,
+    #[serde(default)]
+    pub(crate) size: u64,
+    #[serde(default)]
+    pub(crate) tstamp: u64,
+    #[serde(rename = "hash")]
+    pub(crate) hash_hex: String,
+    #[serde(default, skip_serializing_if = "EntryKind::is_file")]
+    pub(crate) kind: EntryKind,
+    /// Modification time in nanoseconds since the Unix epoch (`tstamp` prefers creation time).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) mtime_ns: Option<u64>,
+    /// Status change time in nanoseconds since the Unix epoch (Unix only). Unlike the mtime,
+    /// it cannot be set back, so it catches edits that restore the mtime.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) ctime_ns: Option<u64>,
+    /// Device and inode number (Unix only), to catch files replaced by another one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) dev: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) ino: Option<u64>,
+    /// Number of hard links to the file, when it has more than one (Unix only).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) nlink: Option<u64>,
+    /// For a hard link to another indexed file (the first by path): that file's path. Its
+    /// content was only read once, for that file.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) hardlink: Option<String>,
+    /// Permission bits (`mode & 0o7777`); `None` on platforms without POSIX modes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) mode: Option<u32>,
+    /// Owner and group ids, only recorded with `--owner`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) uid: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) gid: Option<u32>,
+    /// Extended attributes (name -> hex value), only recorded with `--xattrs`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) xattrs: Option<BTreeMap<String, String>>,
+    /// Digest of the permission bits, owner, extended attributes and symlink target, only
+    /// recorded with `--meta-hash` (see [`metadata_hash`](crate::scan::metadata_hash)).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) meta: Option<String>,
+    /// Target of a link entry, as stored in the link.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) target: Option<String>,
+    /// Type of a special entry (see [`special_type`](crate::walk::special_type)).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) special: Option<String>,
+    /// Algorithm of `hash_hex`; entries written before it was recorded use the header's.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) algo: Option<String>,
+    /// [`Sampling::marker`](crate::hash::Sampling::marker) if `hash_hex` only covers part of the content (`--quick`, `--sample`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) partial: Option<String>,
+    /// Additional digests by algorithm name (`--algo a,b,...`), computed in the same read.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) hashes: Option<BTreeMap<String, String>>,
+    /// Content-defined chunks of the file, in order (`--chunks`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) chunks: Option<Vec<Chunk>>,
+    /// TLSH similarity digest of the content (`--similarity`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) tlsh: Option<String>,
+}
+
+/// A content-defined chunk of a file, hashed with the entry's algorithm (and key).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Chunk {
+    pub(crate) len: u64,
+    pub(crate) hash: String,
+}
+
+/// Keys of the optional per-entry fields, stored as `key=value` strings by the text,
+/// binary and SQLite backends. New fields must be added here and to the two methods below.
+const ENTRY_EXTRA_KEYS: &[&str] = &["kind", "algo", "hashes", "partial", "chunks", "mtime_ns", "ctime_ns", "dev", "ino", "nlink", "hardlink", "mode", "uid", "gid", "xattrs", "meta", "target", "special", "tlsh"];
+
+impl Entry {
+    /// Whether only the size and timestamps of the file were recorded, not a digest.
+    pub(crate) fn is_unhashed(&self) -> bool {
+        self.partial.as_deref() == Some(UNHASHED)
+    }
+
+    fn extras(&self) -> Vec<(&'static str, String)> {
+        let mut extras = Vec::new();
+        match self.kind {
+            EntryKind::File => {}
+            EntryKind::Dir => extras.push(("kind", "dir".to_string())),
+            EntryKind::Link => extras.push(("kind", "link".to_string())),
+            EntryKind::Special => extras.push(("kind", "special".to_string())),
+        }
+        if let Some(ref algo) = self.algo {
+            extras.push(("algo", algo.clone()));
+        }
+        if let Some(ref hashes) = self.hashes {
+            // `<algo>:<digest>` pairs, comma-separated.
+            let value: Vec<String> = hashes.iter().map(|(algo, hash)| format!("{algo}:{hash}")).collect();
+            extras.push(("hashes", value.join(",")));
+        }
+        if let Some(ref partial) = self.partial {
+            extras.push(("partial", partial.clone()));
+        }
+        if let Some(ref chunks) = self.chunks {
+            // `<len>:<hash>` pairs, comma-separated.
+            let value: Vec<String> = chunks.iter().map(|c| format!("{}:{}", c.len, c.hash)).collect();
+            extras.push(("chunks", value.join(",")));
+        }
+        if let Some(mtime_ns) = self.mtime_ns {
+            extras.push(("mtime_ns", mtime_ns.to_string()));
+        }
+        if let Some(ctime_ns) = self.ctime_ns {
+            extras.push(("ctime_ns", ctime_ns.to_string()));
+        }
+        if let Some(dev) = self.dev {
+            extras.push(("dev", dev.to_string()));
+        }
+        if let Some(ino) = self.ino {
+            extras.push(("ino", ino.to_string()));
+        }
+        if let Some(nlink) = self.nlink {
+            extras.push(("nlink", nlink.to_string()));
+        }
+        if let Some(ref hardlink) = self.hardlink {
+            extras.push(("hardlink", hardlink.clone()));
+        }
+        if let Some(mode) = self.mode {
+            extras.push(("mode", format!("{mode:o}")));
+        }
+        if let Some(uid) = self.uid {
+            extras.push(("uid", uid.to_string()));
+        }
+        if let Some(gid) = self.gid {
+            extras.push(("gid", gid.to_string()));
+        }
+        if let Some(ref xattrs) = self.xattrs {
+            extras.push(("xattrs", serde_json::to_string(xattrs).unwrap_or_default()));
+        }
+        if let Some(ref meta) = self.meta {
+            extras.push(("meta", meta.clone()));
+        }
+        if let Some(ref target) = self.target {
+            extras.push(("target", target.clone()));
+        }
+        if let Some(ref special) = self.special {
+            extras.push(("special", special.clone()));
+        }
+        if let Some(ref tlsh) = self.tlsh {
+            extras.push(("tlsh", tlsh.clone()));
+        }
+        extras
+    }
+
+    fn set_extra(&mut self, key: &str, value: &str) {
+        match key {
+            "kind" if value == "dir" => self.kind = EntryKind::Dir,
+            "kind" if value == "link" => self.kind = EntryKind::Link,
+            "kind" if value == "special" => self.kind = EntryKind::Special,
+            "algo" => self.algo = Some(value.to_string()),
+            "hashes" => {
+                self.hashes = value
+                    .split(',')
+                    .filter(|h| !h.is_empty())
+                    .map(|h| h.split_once(':').map(|(algo, hash)| (algo.to_string(), hash.to_string())))
+                    .collect();
+            }
+            "partial" => self.partial = Some(value.to_string()),
+            "chunks" => {
+                self.chunks = value
+                    .split(',')
+                    .filter(|c| !c.is_empty())
+                    .map(|c| {
+                        let (len, hash) = c.split_once(':')?;
+                        Some(Chunk {
+                            len: len.parse().ok()?,
+                            hash: hash.to_string(),
+                        })
+                    })
+                    .collect();
+            }
+            "mtime_ns" => self.mtime_ns = value.parse().ok(),
+            "ctime_ns" => self.ctime_ns = value.parse().ok(),
+            "dev" => self.dev = value.parse().ok(),
+            "ino" => self.ino = value.parse().ok(),
+            "nlink" => self.nlink = value.parse().ok(),
+            "hardlink" => self.hardlink = Some(value.to_string()),
+            "mode" => self.mode = u32::from_str_radix(value, 8).ok(),
+            "uid" => self.uid = value.parse().ok(),
+            "gid" => self.gid = value.parse().ok(),
+            "xattrs" => self.xattrs = serde_json::from_str(value).ok(),
+            "meta" => self.meta = Some(value.to_string()),
+            "target" => self.target = Some(value.to_string()),
+            "special" => self.special = Some(value.to_string()),
+            "tlsh" => self.tlsh = Some(value.to_string()),
+            // Unknown keys come from newer versions; ignore them.
+            _ => {}
+        }
+    }
+}
+
+/// Takes an exclusive advisory lock on `<state_file>.lock`, held until the returned file
+/// is dropped, so concurrent runs against the same state fail fast instead of racing.
+/// Nothing is locked for `-` (stdin/stdout).
+pub(crate) fn acquire_run_lock(state_file: &Path) -> Result<Option<File>> {
+    if is_stdio(state_file) {
+        return Ok(None);
+    }
+    let mut lock_path = state_file.as_os_str().to_owned();
+    lock_path.push(".lock");
+    let lock_path = PathBuf::from(lock_path);
+
+    if let Some(parent) = lock_path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create state file directory: {parent:?}"))?;
+    }
+    let file = File::options()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&lock_path)
+        .with_context(|| format!("Failed to open lock file: {lock_path:?}"))?;
+
+    match file.try_lock() {
+        Ok(()) => Ok(Some(file)),
+        Err(fs::TryLockError::WouldBlock) => Err(anyhow!(
+            "Another run is already using state file {state_file:?} (lock held on {lock_path:?})"
+        )),
+        Err(fs::TryLockError::Error(err)) => {
+            Err(err).with_context(|| format!("Failed to lock {lock_path:?}"))
+        }
+    }
+}
+
+/// Bytes covered by a state signature: the text (v2) serialization of the state without
+/// its signature, so signatures do not depend on the storage format.
+fn signed_bytes(state: &State) -> Result<Vec<u8>> {
+    let mut header = state.header.clone();
+    header.signature = None;
+    let ordered: BTreeMap<&String, &Entry> = state.entries.iter().collect();
+    let mut buf = Vec::new();
+    write_text_state(&mut buf, &header, &ordered)?;
+    Ok(buf)
+}
+
+pub(crate) fn sign_state(state: &mut State, key: &ed25519_dalek::SigningKey) -> Result<()> {
+    use ed25519_dalek::Signer;
+    let signature = key.sign(&signed_bytes(state)?);
+    state.header.signature = Some(hex_encode(&signature.to_bytes()));
+    Ok(())
+}
+
+pub(crate) fn verify_state(state: &State, key: &ed25519_dalek::VerifyingKey) -> Result<()> {
+    let sig_hex = state
+        .header
+        .signature
+        .as_deref()
+        .ok_or_else(|| anyhow!("state is not signed"))?;
+    let sig_bytes: [u8; 64] = hex_decode(sig_hex)
+        .and_then(|b| b.try_into().ok())
+        .ok_or_else(|| anyhow!("malformed signature"))?;
+    let signature = ed25519_dalek::Signature::from_bytes(&sig_bytes);
+    key.verify_strict(&signed_bytes(state)?, &signature)
+        .map_err(|_| anyhow!("signature does not match (state was modified or signed with another key)"))
+}
+
+pub(crate) fn load_signing_key(path: &Path) -> Result<ed25519_dalek::SigningKey> {
+    use ed25519_dalek::pkcs8::DecodePrivateKey;
+    let data = fs::read(path).with_context(|| format!("Failed to read signing key: {path:?}"))?;
+    if let Ok(pem) = std::str::from_utf8(&data) {
+        if let Ok(key) = ed25519_dalek::SigningKey::from_pkcs8_pem(pem) {
+            return Ok(key);
+        }
+    }
+    let seed = raw_key_bytes(&data).ok_or_else(|| anyhow!("Invalid ed25519 signing key: {path:?}"))?;
+    Ok(ed25519_dalek::SigningKey::from_bytes(&seed))
+}
+
+pub(crate) fn load_verifying_key(path: &Path) -> Result<ed25519_dalek::VerifyingKey> {
+    use ed25519_dalek::pkcs8::DecodePublicKey;
+    let data = fs::read(path).with_context(|| format!("Failed to read verify key: {path:?}"))?;
+    if let Ok(pem) = std::str::from_utf8(&data) {
+        if let Ok(key) = ed25519_dalek::VerifyingKey::from_public_key_pem(pem) {
+            return Ok(key);
+        }
+    }
+    let bytes = raw_key_bytes(&data).ok_or_else(|| anyhow!("Invalid ed25519 public key: {path:?}"))?;
+    ed25519_dalek::VerifyingKey::from_bytes(&bytes)
+        .with_context(|| format!("Invalid ed25519 public key: {path:?}"))
+}
+
+/// A 32-byte key stored either raw or as (lowercase or uppercase) hex text.
+fn raw_key_bytes(data: &[u8]) -> Option<[u8; 32]> {
+    if let Ok(raw) = <[u8; 32]>::try_from(data) {
+        return Some(raw);
+    }
+    let text = std::str::from_utf8(data).ok()?.trim().to_ascii_lowercase();
+    hex_decode(&text)?.try_into().ok()
+}
+
+/// The digest of `e` as written in text-based formats, per the header's digest encoding.
+/// Entries whose algorithm is unknown or has no multihash code are always written as hex.
+fn stored_digest<'a>(header: &StateHeader, e: &'a Entry) -> std::borrow::Cow<'a, str> {
+    if header.digests == DigestEncoding::Multihash {
+        let algo = e.algo.as_deref().or(header.algo.as_deref());
+        if let Some(algo) = algo.and_then(Algo::from_name) {
+            if let Some(encoded) = encode_multihash(algo, &e.hash_hex) {
+                return encoded.into();
+            }
+        }
+    }
+    e.hash_hex.as_str().into()
+}
+
+/// Magic prefix of binary state files, used for format auto-detection.
+const BINARY_STATE_MAGIC: &[u8; 8] = b"FHIBIN\0\0";
+
+const BINARY_STATE_VERSION: u32 = 3;
+
+const BINARY_HASH_HEX: u8 = 0;
+
+const BINARY_HASH_TEXT: u8 = 1;
+
+const SQLITE_MAGIC: &[u8; 16] = b"SQLite format 3\0";
+
+/// Current state format version, written by every backend.
+pub(crate) const STATE_VERSION: u32 = 2;
+
+/// First line of versioned text states, followed by the version number.
+const TEXT_STATE_MAGIC: &str = "#fast-hash-index-state v";
+
+/// Key of the (optional) header object on the first line of JSON states.
+const JSON_STATE_KEY: &str = "fast-hash-index-state";
+
+/// Whether `path` is `-`, i.e. stdin when reading and stdout when writing a state.
+pub(crate) fn is_stdio(path: &Path) -> bool {
+    path.as_os_str() == "-"
+}
+
+pub(crate) fn read_state_file(path: &Path) -> Result<(State, StateFormat)> {
+    let (mut state, format) = read_state_any(path)?;
+    for e in state.entries.values_mut() {
+        // Digests are hex in memory; multihash ones are recognized whatever the header says.
+        if !is_hex(&e.hash_hex) {
+            if let Some((algo, hex)) = decode_multihash(&e.hash_hex) {
+                e.hash_hex = hex;
+                e.algo.get_or_insert_with(|| algo_name(algo).to_string());
+            }
+        }
+    }
+    Ok((state, format))
+}
+
+fn read_state_any(path: &Path) -> Result<(State, StateFormat)> {
+    if is_stdio(path) {
+        return read_state_stream(io::stdin().lock()).context("Failed to read state from stdin");
+    }
+    if !path.exists() {
+        return Ok((State::default(), StateFormat::default()));
+    }
+    let file = File::open(path).with_context(|| format!("Failed to open previous state: {path:?}"))?;
+    let mut reader = BufReader::new(file);
+
+    if reader.fill_buf()?.starts_with(SQLITE_MAGIC) {
+        drop(reader);
+        let state = read_sqlite_state(path)
+            .with_context(|| format!("Failed to read SQLite state: {path:?}"))?;
+        return Ok((state, StateFormat::Sqlite));
+    }
+    read_state_stream(reader).with_context(|| format!("Failed to read state: {path:?}"))
+}
+
+/// Reads a state of any format except SQLite (which needs a file) from `reader`.
+fn read_state_stream(mut reader: impl BufRead) -> Result<(State, StateFormat)> {
+    let head = reader.fill_buf()?;
+    if head.starts_with(BINARY_STATE_MAGIC) {
+        let state = read_binary_state(reader).context("invalid binary state")?;
+        Ok((state, StateFormat::Binary))
+    } else if head.starts_with(SQLITE_MAGIC) {
+        Err(anyhow!("SQLite states can only be read from a file"))
+    } else if head.starts_with(b"{") {
+        let state = read_json_state(reader).context("invalid JSON state")?;
+        Ok((state, StateFormat::Json))
+    } else if head.starts_with(TEXT_STATE_MAGIC.as_bytes()) {
+        let state = read_text_state(reader)?;
+        Ok((state, StateFormat::Text))
+    } else {
+        let entries = read_legacy_text_state(reader);
+        let state = State {
+            header: StateHeader {
+                version: 1,
+                algo: infer_algo(&entries).map(|algo| algo_name(algo).to_string()),
+                ..Default::default()
+            },
+            entries,
+        };
+        Ok((state, StateFormat::Text))
+    }
+}
+
+/// Text layout (v2):
+///
+/// ```text
+/// #fast-hash-index-state v2
+/// #algo=blake3
+/// #root=/abs/path
+/// #scanned=1700000000
+/// <path>\t<size>\t<tstamp>\t<hash>[\t<key>=<value>...]
+/// ```
+///
+/// Header values and paths are escaped with [`escape_path`]; a path starting with `#` has
+/// it escaped as `\#`. Other lines starting with `#` are comments.
+fn read_text_state(reader: impl BufRead) -> Result<State> {
+    let mut lines = reader.lines();
+    let first = lines.next().transpose()?.unwrap_or_default();
+    let version: u32 = first
+        .strip_prefix(TEXT_STATE_MAGIC)
+        .and_then(|v| v.trim().parse().ok())
+        .ok_or_else(|| anyhow!("invalid state header: {first}"))?;
+    if version > STATE_VERSION {
+        return Err(anyhow!(
+            "state format v{version} is newer than supported (v{STATE_VERSION})"
+        ));
+    }
+
+    let mut state = State::default();
+    state.header.version = version;
+    for (idx, line_res) in lines.enumerate() {
+        let lineno = idx + 2;
+        let line = line_res?;
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(comment) = line.strip_prefix('#') {
+            if let Some((key, value)) = comment.split_once('=') {
+                state.header.set(key, unescape_path(value));
+            }
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() < 4 {
+            eprintln!("Warning: invalid format at line {lineno}: {line}");
+            continue;
+        }
+        let (Ok(size), Ok(tstamp)) = (fields[1].parse::<u64>(), fields[2].parse::<u64>()) else {
+            eprintln!("Warning: invalid format at line {lineno}: {line}");
+            continue;
+        };
+        let rel = unescape_path(fields[0]);
+        let mut entry = Entry {
+            rel_path: rel.clone(),
+            size,
+            tstamp,
+            hash_hex: fields[3].to_string(),
+            ..Default::default()
+        };
+        for extra in &fields[4..] {
+            if let Some((key, value)) = extra.split_once('=') {
+                entry.set_extra(key, &unescape_path(value));
+            }
+        }
+        state.entries.insert(rel, entry);
+    }
+    Ok(state)
+}
+
+/// NDJSON layout: an optional header object followed by one entry object per line.
+///
+/// ```text
+/// {"algo":"blake3","fast-hash-index-state":2,"root":"/abs/path","scanned":"1700000000"}
+/// {"path":"src/main.rs","size":1234,"tstamp":1700000000,"hash":"5f1c…"}
+/// ```
+///
+/// `size` and `tstamp` may be omitted by external producers (they default to 0).
+fn read_json_state(reader: impl BufRead) -> Result<State> {
+    let mut state = State::default();
+    state.header.version = STATE_VERSION;
+    for (idx, line_res) in reader.lines().enumerate() {
+        let lineno = idx + 1;
+        let line = line_res?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let value: serde_json::Value = serde_json::from_str(&line)
+            .with_context(|| format!("Invalid JSON at line {lineno}"))?;
+
+        if let Some(version) = value.get(JSON_STATE_KEY) {
+            let version = version
+                .as_u64()
+                .and_then(|v| u32::try_from(v).ok())
+                .ok_or_else(|| anyhow!("Invalid state version at line {lineno}"))?;
+            if version > STATE_VERSION {
+                return Err(anyhow!(
+                    "state format v{version} is newer than supported (v{STATE_VERSION})"
+                ));
+            }
+            state.header.version = version;
+            for (key, v) in value.as_object().into_iter().flatten() {
+                if key == JSON_STATE_KEY {
+                    continue;
+                }
+                let v = match v {
+                    serde_json::Value::String(s) => s.clone(),
+                    other => other.to_string(),
+                };
+                state.header.set(key, v);
+            }
+            continue;
+        }
+
+        let entry: Entry = serde_json::from_value(value)
+            .with_context(|| format!("Invalid entry at line {lineno}"))?;
+        state.entries.insert(entry.rel_path.clone(), entry);
+    }
+    Ok(state)
+}
+
+/// Legacy (v1) colon-delimited `path:size:tstamp:hash` lines, without a header.
+fn read_legacy_text_state(reader: impl BufRead) -> HashMap<String, Entry> {
+    let mut map = HashMap::new();
+    for (lineno, line_res) in reader.lines().enumerate() {
+        let line = match line_res {
+            Ok(s) => s,
+            Err(err) => {
+                eprintln!("Warning: invalid line {} (I/O): {err}", lineno + 1);
+                continue;
+            }
+        };
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let parts: Vec<&str> = line.splitn(4, ':').collect();
+        if parts.len() != 4 {
+            eprintln!("Warning: invalid format at line {}: {line}", lineno + 1);
+            continue;
+        }
+        let rel = parts[0].to_string();
+        let size = parts[1].parse::<u64>().unwrap_or(0);
+        let tstamp = parts[2].parse::<u64>().unwrap_or(0);
+        let hash_hex = parts[3].to_string();
+
+        map.insert(
+            rel.clone(),
+            Entry {
+                rel_path: rel,
+                size,
+                tstamp,
+                hash_hex,
+                ..Default::default()
+            },
+        );
+    }
+    map
+}
+
+/// Binary layout (all integers little-endian):
+///
+/// ```text
+/// magic[8] version:u32
+/// header_count:u32 header_count x { key_len:u32 key[key_len] value_len:u32 value[value_len] }   (v2+)
+/// count:u64
+/// count x {
+///     path_len:u32 path[path_len] size:u64 tstamp:u64 hash_kind:u8 hash_len:u16 hash[hash_len]
+///     extra_count:u16 extra_count x { key_len:u32 key[key_len] value_len:u32 value[value_len] }   (v3+)
+/// }
+/// checksum:u64   (xxh3-64 of everything before it)
+/// ```
+///
+/// `hash_kind` is `BINARY_HASH_HEX` when the digest is stored as raw bytes (hex-encoded
+/// in memory) or `BINARY_HASH_TEXT` when it is stored verbatim.
+fn read_binary_state(reader: impl Read) -> Result<State> {
+    let mut r = ChecksumReader::new(reader);
+
+    let mut magic = [0u8; 8];
+    r.read_exact(&mut magic)?;
+    if &magic != BINARY_STATE_MAGIC {
+        return Err(anyhow!("not a binary state file"));
+    }
+    let version = r.read_u32()?;
+    if version == 0 || version > BINARY_STATE_VERSION {
+        return Err(anyhow!("unsupported binary state version {version}"));
+    }
+
+    let mut header = StateHeader {
+        version,
+        ..Default::default()
+    };
+    if version >= 2 {
+        let pairs = r.read_u32()?;
+        for _ in 0..pairs {
+            let key = r.read_string()?;
+            let value = r.read_string()?;
+            header.set(&key, value);
+        }
+    }
+
+    let count = r.read_u64()?;
+    let mut map = HashMap::with_capacity(count.min(1 << 24) as usize);
+    for _ in 0..count {
+        let rel = r.read_string()?;
+        let size = r.read_u64()?;
+        let tstamp = r.read_u64()?;
+        let hash_kind = r.read_u8()?;
+        let hash_len = r.read_u16()? as usize;
+        let hash = r.read_vec(hash_len)?;
+        let hash_hex = match hash_kind {
+            BINARY_HASH_HEX => hex_encode(&hash),
+            BINARY_HASH_TEXT => String::from_utf8(hash).context("hash is not valid UTF-8")?,
+            other => return Err(anyhow!("unknown hash encoding {other} for {rel}")),
+        };
+
+        let mut entry = Entry {
+            rel_path: rel.clone(),
+            size,
+            tstamp,
+            hash_hex,
+            ..Default::default()
+        };
+        if version >= 3 {
+            let extras = r.read_u16()?;
+            for _ in 0..extras {
+                let key = r.read_string()?;
+                let value = r.read_string()?;
+                entry.set_extra(&key, &value);
+            }
+        }
+        map.insert(rel, entry);
+    }
+
+    let expected = r.digest();
+    let stored = r.read_u64()?;
+    if stored != expected {
+        return Err(anyhow!("checksum mismatch (file is corrupt or truncated)"));
+    }
+    Ok(State {
+        header,
+        entries: map,
+    })
+}
+
+/// `previous` is the content currently stored at `path`, when it was read from a SQLite
+/// state; it lets the SQLite backend update only the rows that changed.
+pub(crate) fn write_state_file(
+    path: &Path,
+    state: &State,
+    format: StateFormat,
+    previous: Option<&HashMap<String, Entry>>,
+) -> Result<()> {
+    if is_stdio(path) {
+        if format == StateFormat::Sqlite {
+            return Err(anyhow!("SQLite states can only be written to a file"));
+        }
+        let mut out = BufWriter::new(io::stdout().lock());
+        write_state_stream(&mut out, state, format)?;
+        out.flush()?;
+        return Ok(());
+    }
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create state file directory: {parent:?}"))?;
+    }
+    if format == StateFormat::Sqlite {
+        return write_sqlite_state(path, state, previous)
+            .with_context(|| format!("Failed to write SQLite state: {path:?}"));
+    }
+    // Written next to the state and renamed over it, so a crash or a full disk mid-write
+    // leaves the previous state intact rather than truncated. A symlinked state is replaced
+    // at its target.
+    let path = &fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let tmp = temp_path(path);
+    let written = File::create(&tmp)
+        .with_context(|| format!("Failed to create state file: {tmp:?}"))
+        .and_then(|file| {
+            let mut w = BufWriter::new(file);
+            write_state_stream(&mut w, state, format)?;
+            let file = w.into_inner().map_err(|e| e.into_error())?;
+            // Keep the mode of the state being replaced, as writing in place did.
+            if let Ok(md) = fs::metadata(path) {
+                fs::set_permissions(&tmp, md.permissions())?;
+            }
+            file.sync_all().context("Failed to flush the state file")
+        })
+        .and_then(|()| fs::rename(&tmp, path).with_context(|| format!("Failed to replace {path:?}")));
+    if let Err(err) = written {
+        let _ = fs::remove_file(&tmp);
+        return Err(err.context(format!("Failed to write state file: {path:?}")));
+    }
+    Ok(())
+}
+
+fn write_state_stream(w: &mut impl Write, state: &State, format: StateFormat) -> Result<()> {
+    let mut ordered: BTreeMap<&String, &Entry> = BTreeMap::new();
+    for (k, v) in &state.entries {
+        ordered.insert(k, v);
+    }
+
+    match format {
+        StateFormat::Text => write_text_state(w, &state.header, &ordered)?,
+        StateFormat::Binary => write_binary_state(w, &state.header, &ordered)?,
+        StateFormat::Json => write_json_state(w, &state.header, &ordered)?,
+        StateFormat::Sqlite => unreachable!("written by write_sqlite_state"),
+    }
+    Ok(())
+}
+
+fn write_text_state(
+    mut w: impl Write,
+    header: &StateHeader,
+    ordered: &BTreeMap<&String, &Entry>,
+) -> Result<()> {
+    writeln!(w, "{TEXT_STATE_MAGIC}{STATE_VERSION}")?;
+    for (key, value) in header.to_pairs() {
+        writeln!(w, "#{key}={}", escape_path(&value))?;
+    }
+    for e in ordered.values() {
+        let mut path = escape_path(&e.rel_path);
+        if path.starts_with('#') {
+            path.insert(0, '\\');
+        }
+        write!(w, "{path}\t{}\t{}\t{}", e.size, e.tstamp, stored_digest(header, e))?;
+        for (key, value) in e.extras() {
+            write!(w, "\t{key}={}", escape_path(&value))?;
+        }
+        writeln!(w)?;
+    }
+    Ok(())
+}
+
+fn write_json_state(
+    mut w: impl Write,
+    header: &StateHeader,
+    ordered: &BTreeMap<&String, &Entry>,
+) -> Result<()> {
+    let mut head = serde_json::Map::new();
+    head.insert(JSON_STATE_KEY.to_string(), STATE_VERSION.into());
+    for (key, value) in header.to_pairs() {
+        head.insert(key.to_string(), value.into());
+    }
+    serde_json::to_writer(&mut w, &head)?;
+    writeln!(w)?;
+
+    for e in ordered.values() {
+        if header.digests == DigestEncoding::Hex {
+            serde_json::to_writer(&mut w, e)?;
+        } else {
+            let mut encoded = (*e).clone();
+            encoded.hash_hex = stored_digest(header, e).into_owned();
+            serde_json::to_writer(&mut w, &encoded)?;
+        }
+        writeln!(w)?;
+    }
+    Ok(())
+}
+
+fn write_binary_state(
+    w: impl Write,
+    header: &StateHeader,
+    ordered: &BTreeMap<&String, &Entry>,
+) -> Result<()> {
+    let mut w = ChecksumWriter::new(w);
+    w.write_all(BINARY_STATE_MAGIC)?;
+    w.write_all(&BINARY_STATE_VERSION.to_le_bytes())?;
+
+    let pairs = header.to_pairs();
+    w.write_all(&(pairs.len() as u32).to_le_bytes())?;
+    for (key, value) in &pairs {
+        w.write_string(key)?;
+        w.write_string(value)?;
+    }
+
+    w.write_all(&(ordered.len() as u64).to_le_bytes())?;
+    for e in ordered.values() {
+        w.write_string(&e.rel_path)?;
+        w.write_all(&e.size.to_le_bytes())?;
+        w.write_all(&e.tstamp.to_le_bytes())?;
+
+        let (kind, hash) = match hex_decode(&e.hash_hex) {
+            Some(raw) => (BINARY_HASH_HEX, raw),
+            None => (BINARY_HASH_TEXT, e.hash_hex.as_bytes().to_vec()),
+        };
+        let hash_len = u16::try_from(hash.len())
+            .map_err(|_| anyhow!("hash too long for binary state: {}", e.rel_path))?;
+        w.write_all(&[kind])?;
+        w.write_all(&hash_len.to_le_bytes())?;
+        w.write_all(&hash)?;
+
+        let extras = e.extras();
+        w.write_all(&(extras.len() as u16).to_le_bytes())?;
+        for (key, value) in &extras {
+            w.write_string(key)?;
+            w.write_string(value)?;
+        }
+    }
+
+    let checksum = w.digest();
+    w.write_all(&checksum.to_le_bytes())?;
+    Ok(())
+}
+
+const SQLITE_SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS entries (
+        path   TEXT PRIMARY KEY NOT NULL,
+        size   INTEGER NOT NULL,
+        tstamp INTEGER NOT NULL,
+        hash   TEXT NOT NULL
+    ) WITHOUT ROWID;
+    CREATE INDEX IF NOT EXISTS entries_hash ON entries (hash);
+    CREATE TABLE IF NOT EXISTS meta (
+        key   TEXT PRIMARY KEY NOT NULL,
+        value TEXT NOT NULL
+    );
+";
+
+fn read_sqlite_state(path: &Path) -> Result<State> {
+    let conn = rusqlite::Connection::open_with_flags(path, rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+
+    // Databases written before the metadata table existed are version 1.
+    let mut header = StateHeader {
+        version: 1,
+        ..Default::default()
+    };
+    let has_meta: bool = conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'meta')",
+        [],
+        |row| row.get(0),
+    )?;
+    if has_meta {
+        let mut stmt = conn.prepare("SELECT key, value FROM meta")?;
+        let rows = stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?;
+        for row in rows {
+            let (key, value) = row?;
+            if key == "version" {
+                header.version = value.parse().unwrap_or(1);
+            } else {
+                header.set(&key, value);
+            }
+        }
+    }
+
+    // Older databases may lack the columns of newer optional fields.
+    let columns = sqlite_columns(&conn)?;
+    let extra_keys: Vec<&str> = ENTRY_EXTRA_KEYS
+        .iter()
+        .copied()
+        .filter(|k| columns.iter().any(|c| c == k))
+        .collect();
+    let mut sql = "SELECT path, size, tstamp, hash".to_string();
+    for key in &extra_keys {
+        sql.push_str(&format!(", \"{key}\""));
+    }
+    sql.push_str(" FROM entries");
+
+    let mut stmt = conn.prepare(&sql)?;
+    let rows = stmt.query_map([], |row| {
+        let mut e = Entry {
+            rel_path: row.get(0)?,
+            size: row.get::<_, i64>(1)? as u64,
+            tstamp: row.get::<_, i64>(2)? as u64,
+            hash_hex: row.get(3)?,
+            ..Default::default()
+        };
+        for (i, key) in extra_keys.iter().enumerate() {
+            if let Some(value) = row.get::<_, Option<String>>(4 + i)? {
+                e.set_extra(key, &value);
+            }
+        }
+        Ok(e)
+    })?;
+
+    let mut map = HashMap::new();
+    for row in rows {
+        let e = row?;
+        map.insert(e.rel_path.clone(), e);
+    }
+    Ok(State {
+        header,
+        entries: map,
+    })
+}
+
+fn write_sqlite_state(
+    path: &Path,
+    state: &State,
+    previous: Option<&HashMap<String, Entry>>,
+) -> Result<()> {
+    let map = &state.entries;
+    // Anything that is not the database we read from (e.g. a text state) is replaced.
+    if previous.is_none() && path.exists() {
+        fs::remove_file(path).with_context(|| format!("Failed to replace state file: {path:?}"))?;
+    }
+    let empty = HashMap::new();
+    let mut previous = previous.unwrap_or(&empty);
+
+    let mut conn = rusqlite::Connection::open(path)?;
+    conn.execute_batch(SQLITE_SCHEMA)?;
+    // Rows are only skipped when unchanged, so switching the digest encoding rewrites all of them.
+    use rusqlite::OptionalExtension;
+    let stored_encoding: Option<String> = conn
+        .query_row("SELECT value FROM meta WHERE key = 'digests'", [], |row| row.get(0))
+        .optional()?;
+    let multihash = state.header.digests == DigestEncoding::Multihash;
+    if stored_encoding.is_some() != multihash {
+        previous = &empty;
+    }
+    // Optional fields are nullable TEXT columns holding the same values as the text format.
+    let columns = sqlite_columns(&conn)?;
+    for key in ENTRY_EXTRA_KEYS {
+        if !columns.iter().any(|c| c == key) {
+            conn.execute(&format!("ALTER TABLE entries ADD COLUMN \"{key}\" TEXT"), [])?;
+        }
+    }
+
+    let tx = conn.transaction()?;
+    {
+        let mut sql = "INSERT OR REPLACE INTO entries (path, size, tstamp, hash".to_string();
+        for key in ENTRY_EXTRA_KEYS {
+            sql.push_str(&format!(", \"{key}\""));
+        }
+        sql.push_str(") VALUES (?1, ?2, ?3, ?4");
+        for i in 0..ENTRY_EXTRA_KEYS.len() {
+            sql.push_str(&format!(", ?{}", i + 5));
+        }
+        sql.push(')');
+        let mut upsert = tx.prepare(&sql)?;
+
+        for e in map.values() {
+            let unchanged = previous.get(&e.rel_path).is_some_and(|p| {
+                p.size == e.size
+                    && p.tstamp == e.tstamp
+                    && p.hash_hex == e.hash_hex
+                    && p.extras() == e.extras()
+            });
+            if unchanged {
+                continue;
+            }
+            let extras = e.extras();
+            let mut params: Vec<Box<dyn rusqlite::ToSql>> = vec![
+                Box::new(e.rel_path.clone()),
+                Box::new(e.size as i64),
+                Box::new(e.tstamp as i64),
+                Box::new(stored_digest(&state.header, e).into_owned()),
+            ];
+            for key in ENTRY_EXTRA_KEYS {
+                let value = extras.iter().find(|(k, _)| k == key).map(|(_, v)| v.clone());
+                params.push(Box::new(value));
+            }
+            upsert.execute(rusqlite::params_from_iter(params))?;
+        }
+
+        let mut delete = tx.prepare("DELETE FROM entries WHERE path = ?1")?;
+        for path in previous.keys().filter(|k| !map.contains_key(*k)) {
+            delete.execute([path])?;
+        }
+
+        tx.execute("DELETE FROM meta", [])?;
+        let mut meta = tx.prepare("INSERT INTO meta (key, value) VALUES (?1, ?2)")?;
+        meta.execute(["version", &STATE_VERSION.to_string()])?;
+        for (key, value) in state.header.to_pairs() {
+            meta.execute([key, &value])?;
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+fn sqlite_columns(conn: &rusqlite::Connection) -> Result<Vec<String>> {
+    let mut stmt = conn.prepare("SELECT name FROM pragma_table_info('entries')")?;
+    let names = stmt
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(names)
+}
+
+struct ChecksumWriter<W> {
+    inner: W,
+    state: xxhash_rust::xxh3::Xxh3,
+}
+
+impl<W: Write> ChecksumWriter<W> {
+    fn new(inner: W) -> Self {
+        Self {
+            inner,
+            state: xxhash_rust::xxh3::Xxh3::new(),
+        }
+    }
+
+    fn digest(&self) -> u64 {
+        self.state.digest()
+    }
+
+    fn write_string(&mut self, s: &str) -> Result<()> {
+        let len = u32::try_from(s.len()).map_err(|_| anyhow!("string too long: {s}"))?;
+        self.write_all(&len.to_le_bytes())?;
+        self.write_all(s.as_bytes())?;
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.state.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+struct ChecksumReader<R> {
+    inner: R,
+    state: xxhash_rust::xxh3::Xxh3,
+}
+
+impl<R: Read> ChecksumReader<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            state: xxhash_rust::xxh3::Xxh3::new(),
+        }
+    }
+
+    fn digest(&self) -> u64 {
+        self.state.digest()
+    }
+
+    /// Reads `len` bytes. The length comes from the (not yet verified) file, so the buffer
+    /// only grows with what is actually read: a corrupt length fails instead of allocating it.
+    fn read_vec(&mut self, len: usize) -> io::Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        self.by_ref().take(len as u64).read_to_end(&mut buf)?;
+        if buf.len() != len {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated binary state"));
+        }
+        Ok(buf)
+    }
+
+    fn read_string(&mut self) -> Result<String> {
+        let len = self.read_u32()? as usize;
+        String::from_utf8(self.read_vec(len)?).context("string is not valid UTF-8")
+    }
+
+    fn read_u8(&mut self) -> io::Result<u8> {
+        let mut b = [0u8; 1];
+        self.read_exact(&mut b)?;
+        Ok(b[0])
+    }
+
+    fn read_u16(&mut self) -> io::Result<u16> {
+        let mut b = [0u8; 2];
+        self.read_exact(&mut b)?;
+        Ok(u16::from_le_bytes(b))
+    }
+
+    fn read_u32(&mut self) -> io::Result<u32> {
+        let mut b = [0u8; 4];
+        self.read_exact(&mut b)?;
+        Ok(u32::from_le_bytes(b))
+    }
+
+    fn read_u64(&mut self) -> io::Result<u64> {
+        let mut b = [0u8; 8];
+        self.read_exact(&mut b)?;
+        Ok(u64::from_le_bytes(b))
+    }
+}
+
+impl<R: Read> Read for ChecksumReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.state.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// Parent directory of a unix-style relative path ("" for top-level files).
+pub(crate) fn parent_dir(rel: &str) -> &str {
+    rel.rfind('/').map(|i| &rel[..i]).unwrap_or("")
+}
+
+/// Last component of a relative path.
+fn base_name(rel: &str) -> &str {
+    rel.rsplit('/').next().unwrap_or(rel)
+}
+
+/// Merkle rollup hash of every directory (relative path, `""` for the root): BLAKE3 over the
+/// names and digests of its files, the targets of its links and the rollups of its
+/// subdirectories, sorted by name.
+/// Metadata is not covered, so two trees with the same content have the same root hash; files
+/// recorded without a digest (`--hash-max-size`) are covered by their size.
+pub(crate) fn tree_hashes(entries: &HashMap<String, Entry>) -> BTreeMap<String, String> {
+    // (name, kind, digest) of the children of each directory.
+    let mut children: HashMap<&str, Vec<(&str, u8, String)>> = HashMap::new();
+    children.insert("", Vec::new());
+    for e in entries.values() {
+        for dir in ancestor_dirs(&e.rel_path) {
+            children.entry(dir).or_default();
+        }
+        match e.kind {
+            EntryKind::Dir => {
+                children.entry(&e.rel_path).or_default();
+            }
+            EntryKind::Link => {
+                let item = (base_name(&e.rel_path), b'l', e.target.clone().unwrap_or_default());
+                children.entry(parent_dir(&e.rel_path)).or_default().push(item);
+            }
+            EntryKind::Special => {
+                let item = (base_name(&e.rel_path), b's', e.special.clone().unwrap_or_default());
+                children.entry(parent_dir(&e.rel_path)).or_default().push(item);
+            }
+            EntryKind::File => {
+                let digest = if e.is_unhashed() {
+                    format!("size:{}", e.size)
+                } else {
+                    e.hash_hex.clone()
+                };
+                let item = (base_name(&e.rel_path), b'f', digest);
+                children.entry(parent_dir(&e.rel_path)).or_default().push(item);
+            }
+        }
+    }
+
+    // Deepest first, so that subdirectories are done before their parent.
+    let mut dirs: Vec<&str> = children.keys().copied().collect();
+    let depth = |dir: &str| if dir.is_empty() { 0 } else { dir.matches('/').count() + 1 };
+    dirs.sort_by_key(|dir| std::cmp::Reverse(depth(dir)));
+
+    let mut hashes = BTreeMap::new();
+    for dir in dirs {
+        let mut items = children.remove(dir).unwrap_or_default();
+        items.sort();
+        let mut hasher = blake3::Hasher::new();
+        for (name, kind, digest) in &items {
+            hasher.update(&[*kind]);
+            hasher.update(&(name.len() as u64).to_le_bytes());
+            hasher.update(name.as_bytes());
+            hasher.update(&(digest.len() as u64).to_le_bytes());
+            hasher.update(digest.as_bytes());
+        }
+        let hash = hasher.finalize().to_hex().to_string();
+        if !dir.is_empty() {
+            if let Some(parent) = children.get_mut(parent_dir(dir)) {
+                parent.push((base_name(dir), b'd', hash.clone()));
+            }
+        }
+        hashes.insert(dir.to_string(), hash);
+    }
+    hashes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    fn sample_state() -> State {
+        let mut state = State::default();
+        state.header.algo = Some("blake3".to_string());
+        state.header.root = Some("/data/with\ttab".to_string());
+        state.header.scanned_at = Some(1_700_000_000);
+        for (i, path) in ["#notes.txt", "dir/\\#odd", "a\tb\nc\rd", "ünï/cødé.txt", "ctl\u{1}x"]
+            .into_iter()
+            .enumerate()
+        {
+            let mut e = Entry {
+                rel_path: path.to_string(),
+                size: 100 + i as u64,
+                tstamp: 1_600_000_000 + i as u64,
+                hash_hex: format!("{i:064x}"),
+                ..Default::default()
+            };
+            e.set_extra("mode", "420");
+            state.entries.insert(path.to_string(), e);
+        }
+        state
+    }
+
+    /// The entries as comparable strings (`Entry` has no `PartialEq`).
+    fn summary(state: &State) -> Vec<String> {
+        let mut entries: Vec<_> = state
+            .entries
+            .iter()
+            .map(|(k, e)| {
+                assert_eq!(*k, e.rel_path);
+                format!("{:?} {} {} {} {:?}", e.rel_path, e.size, e.tstamp, e.hash_hex, e.extras())
+            })
+            .collect();
+        entries.sort();
+        entries
+    }
+
+    fn write(state: &State, format: StateFormat) -> Vec<u8> {
+        let mut out = Vec::new();
+        write_state_stream(&mut out, state, format).unwrap();
+        out
+    }
+
+    fn round_trip(format: StateFormat) {
+        let state = sample_state();
+        let (read, detected) = read_state_stream(write(&state, format).as_slice()).unwrap();
+        assert!(detected == format);
+        assert_eq!(summary(&read), summary(&state));
+        assert_eq!(read.header.algo, state.header.algo);
+        assert_eq!(read.header.root, state.header.root);
+        assert_eq!(read.header.scanned_at, state.header.scanned_at);
+    }
+
+    #[test]
+    fn text_state_round_trip() {
+        round_trip(StateFormat::Text);
+    }
+
+    #[test]
+    fn text_state_escapes_paths_starting_with_hash() {
+        let text = String::from_utf8(write(&sample_state(), StateFormat::Text)).unwrap();
+        assert!(text.lines().any(|l| l.starts_with("\\#notes.txt\t")));
+        assert_eq!(text.lines().filter(|l| l.starts_with('#')).count(), 4);
+    }
+
+    #[test]
+    fn json_state_round_trip() {
+        round_trip(StateFormat::Json);
+    }
+
+    #[test]
+    fn binary_state_round_trip() {
+        round_trip(StateFormat::Binary);
+    }
+
+    #[test]
+    fn binary_state_rejects_truncated_input() {
+        let bytes = write(&sample_state(), StateFormat::Binary);
+        for len in [9, 20, bytes.len() / 2, bytes.len() - 1] {
+            assert!(read_state_stream(&bytes[..len]).is_err(), "truncated to {len} bytes");
+        }
+    }
+
+    #[test]
+    fn binary_state_rejects_corrupt_input() {
+        let bytes = write(&sample_state(), StateFormat::Binary);
+        let mut flipped = bytes.clone();
+        let at = flipped.len() / 2;
+        flipped[at] ^= 0x40;
+        assert!(read_state_stream(flipped.as_slice()).is_err());
+
+        // The length of the first header key, after the magic, version and pair count.
+        let mut huge = bytes;
+        huge[16..20].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(read_state_stream(huge.as_slice()).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn state_file_is_replaced_whole() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TestDir::new();
+        let path = dir.write("s.txt", "stale\n");
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+        write_state_file(&path, &sample_state(), StateFormat::Binary, None).unwrap();
+
+        let (read, format) = read_state_file(&path).unwrap();
+        assert!(format == StateFormat::Binary);
+        assert_eq!(summary(&read), summary(&sample_state()));
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        let names: Vec<_> = fs::read_dir(dir.path()).unwrap().map(|e| e.unwrap().file_name()).collect();
+        assert_eq!(names, ["s.txt"], "the temporary file is renamed over the state");
+    }
+}
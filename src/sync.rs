@@ -0,0 +1,1190 @@
+//! Syncing changes into a target directory (`--target`) and `apply`.
+
+use crate::copy::{
+    apply_permissions, copy_with_permissions, copy_xattrs_and_acls, is_real_dir,
+    replace_with_hard_link, replace_with_symlink, set_owner,
+};
+use crate::diff::Change;
+use crate::hash::{
+    algo_name, default_buffer_size, hash_file_sampled, Algo, IoEngine, RateLimit, ReadOptions,
+    Sampling,
+};
+use crate::patch::patch_path;
+use crate::scan::{file_id, file_owner, keep_going, FileError};
+use crate::state::{Entry, EntryKind};
+use crate::timestamps::{format_utc_compact, unix_now};
+use crate::walk::{
+    collect_files, find_on_disk, path_to_rel_unix, PathForm, SpecialFiles, WalkOptions,
+};
+use anyhow::{anyhow, Context, Result};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Write};
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Where [`apply_ops`] reads the files it copies.
+pub(crate) struct OpSource<'a> {
+    pub(crate) root: &'a Path,
+    /// Disk locations of paths recorded in another Unicode normalization.
+    pub(crate) disk_paths: &'a HashMap<String, PathBuf>,
+    /// Algorithm and key to check the digests of the operations with (`apply`); without,
+    /// they are trusted, as when syncing right after hashing.
+    pub(crate) verify: Option<(Algo, Option<[u8; 32]>)>,
+}
+
+impl OpSource<'_> {
+    pub(crate) fn path(&self, rel: &str) -> Result<PathBuf> {
+        match self.disk_paths.get(rel) {
+            Some(path) => Ok(path.clone()),
+            // Patches do not record how their (normalized) paths are spelled on disk.
+            None => patch_path(self.root, rel).map(|path| match fs::symlink_metadata(&path) {
+                Ok(_) => path,
+                Err(_) => find_on_disk(self.root, rel),
+            }),
+        }
+    }
+
+    /// Whether `path` has the content `hash` was computed from (true if it cannot be checked).
+    fn matches(&self, path: &Path, size: u64, hash: &str, partial: Option<&str>) -> Result<bool> {
+        let Some((algo, ref key)) = self.verify else {
+            return Ok(true);
+        };
+        content_matches(path, size, hash, partial, algo, key.as_ref(), false)
+    }
+}
+
+/// Whether the `size` bytes of `path` hash to `hash` with `algo`, sampled as the `partial`
+/// marker says (true if it cannot be checked). With `uncached`, the file is read from the
+/// device rather than from the page cache where possible.
+fn content_matches(
+    path: &Path,
+    size: u64,
+    hash: &str,
+    partial: Option<&str>,
+    algo: Algo,
+    key: Option<&[u8; 32]>,
+    uncached: bool,
+) -> Result<bool> {
+    let Some(sampling) = Sampling::from_marker(partial) else {
+        return Ok(true);
+    };
+    if hash.is_empty() {
+        return Ok(true);
+    }
+    let read = ReadOptions {
+        mmap: !uncached,
+        engine: IoEngine::Std,
+        buffer_size: default_buffer_size(IoEngine::Std),
+        drop_cache: uncached,
+        bwlimit: None,
+    };
+    Ok(hash_file_sampled(path, size, algo, key, sampling, &read)? == hash)
+}
+
+/// How operations are applied to a target (`--target`, `apply`).
+#[derive(Debug, Default)]
+pub(crate) struct SyncOptions {
+    /// Only print what would be done (`--dry-run`).
+    pub(crate) dry_run: bool,
+    /// Remove what was deleted in the source (`--delete`); otherwise it is kept and listed.
+    pub(crate) delete: bool,
+    /// Move deleted files into this directory instead of removing them (`--delete-to`).
+    pub(crate) trash: Option<PathBuf>,
+    /// Keep the previous versions of overwritten and deleted files (`--backup-suffix`).
+    pub(crate) backup: Option<Backup>,
+    /// Re-hash copies with this algorithm and key (`--verify-copies`).
+    pub(crate) verify_copies: Option<(Algo, Option<[u8; 32]>)>,
+    /// How many files to copy or delete at a time (`--copy-jobs`).
+    pub(crate) jobs: usize,
+    /// Keep directories emptied by deletions (`--keep-empty-dirs`).
+    pub(crate) keep_empty_dirs: bool,
+    /// Shared by all copying threads (`--copy-bwlimit`).
+    pub(crate) bwlimit: Option<RateLimit>,
+    pub(crate) preserve: Preserve,
+    /// Records what was applied, so the sync after an interruption skips it.
+    pub(crate) journal: Option<Journal>,
+    /// How many times to retry transient failures (`--copy-retries`), and the first delay.
+    pub(crate) retries: u32,
+    pub(crate) retry_delay: Duration,
+    /// List what cannot be applied at the end instead of stopping (`--keep-going`).
+    pub(crate) keep_going: bool,
+}
+
+/// The operations of a sync already applied to its target, one JSON line each after a
+/// header naming the target. It is removed once the sync has completed.
+#[derive(Debug)]
+pub(crate) struct Journal {
+    path: PathBuf,
+    done: HashSet<String>,
+    /// The journal was left by an interrupted sync to the same target.
+    resuming: bool,
+    out: Mutex<File>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct JournalHeader {
+    #[serde(rename = "fast-hash-index-journal")]
+    version: u32,
+    target: String,
+}
+
+impl Journal {
+    pub(crate) fn open(path: PathBuf, target: &Path) -> Result<Journal> {
+        let target = target.to_string_lossy().into_owned();
+        let mut done = HashSet::new();
+        let mut resuming = false;
+        if let Ok(file) = File::open(&path) {
+            let mut lines = BufReader::new(file).lines();
+            let header = lines.next().and_then(|l| l.ok()).and_then(|l| serde_json::from_str::<JournalHeader>(&l).ok());
+            if header.as_ref().is_some_and(|h| h.target == target) {
+                resuming = true;
+                // A line cut short by the interruption is not a completed operation.
+                done.extend(lines.map_while(|l| l.ok()).filter(|l| serde_json::from_str::<SyncOp>(l).is_ok()));
+            } else {
+                eprintln!("Notice: ignoring the sync journal {path:?} of another target");
+            }
+        }
+        let mut out = File::create(&path).with_context(|| format!("Failed to create sync journal: {path:?}"))?;
+        let mut content = serde_json::to_string(&JournalHeader { version: 1, target })?;
+        content.push('\n');
+        for line in &done {
+            content.push_str(line);
+            content.push('\n');
+        }
+        out.write_all(content.as_bytes()).with_context(|| format!("Failed to write sync journal: {path:?}"))?;
+        Ok(Journal { path, done, resuming, out: Mutex::new(out) })
+    }
+
+    fn is_done(&self, op: &SyncOp) -> bool {
+        serde_json::to_string(op).is_ok_and(|line| self.done.contains(&line))
+    }
+
+    fn record(&self, op: &SyncOp) -> Result<()> {
+        let mut line = serde_json::to_string(op)?;
+        line.push('\n');
+        let mut out = self.out.lock().unwrap();
+        out.write_all(line.as_bytes())
+            .with_context(|| format!("Failed to write sync journal: {:?}", self.path))
+    }
+
+    /// Called once the sync has completed.
+    pub(crate) fn finish(self) -> Result<()> {
+        drop(self.out);
+        fs::remove_file(&self.path).with_context(|| format!("Failed to remove sync journal: {:?}", self.path))
+    }
+}
+
+/// What copies to the target keep besides their content, permissions and timestamps.
+#[derive(Debug, Default)]
+pub(crate) struct Preserve {
+    /// Owner and group (`--preserve-owner`).
+    pub(crate) owner: bool,
+    /// Extended attributes other than ACLs (`--preserve-xattrs`).
+    pub(crate) xattrs: bool,
+    /// POSIX ACLs, or the DACL on Windows (`--preserve-acls`).
+    pub(crate) acls: bool,
+}
+
+impl Preserve {
+    pub(crate) fn new(owner: bool, xattrs: bool, acls: bool) -> Self {
+        if acls && !cfg!(any(target_os = "linux", windows)) {
+            eprintln!("Warning: --preserve-acls is not supported on this platform; ACLs are not copied");
+        }
+        Preserve { owner, xattrs, acls }
+    }
+}
+
+/// Where the previous versions of target files go: `<path><suffix>.<YYYYMMDDTHHMMSSZ>`, next
+/// to the file or in the same relative place under `dir`.
+#[derive(Debug)]
+pub(crate) struct Backup {
+    suffix: String,
+    dir: Option<PathBuf>,
+    /// How many versions of each file to keep (all if unset).
+    keep: Option<usize>,
+    stamp: String,
+}
+
+impl Backup {
+    /// Moves `dst`, the target file of `rel`, out of the way, then removes its versions beyond
+    /// `keep`. With `replacing`, `dst` is left in place (hard-linked to its backup, or copied)
+    /// for the new version to be renamed over it.
+    fn save(&self, rel: &str, dst: &Path, replacing: bool) -> Result<()> {
+        let location = match self.dir {
+            Some(ref dir) => patch_path(dir, rel)?,
+            None => dst.to_path_buf(),
+        };
+        let name = location.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+        let prefix = format!("{name}{}.", self.suffix);
+        let to = location.with_file_name(format!("{prefix}{}", self.stamp));
+        if let Some(parent) = to.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create backup directory: {parent:?}"))?;
+        }
+        if replacing {
+            fs::hard_link(dst, &to)
+                .or_else(|_| fs::copy(dst, &to).map(|_| ()))
+                .with_context(|| format!("Failed to back up {dst:?} to {to:?}"))?;
+        } else {
+            fs::rename(dst, &to).with_context(|| format!("Failed to move {dst:?} to {to:?}"))?;
+        }
+
+        let Some(keep) = self.keep else { return Ok(()) };
+        let parent = to.parent().unwrap_or(Path::new("."));
+        let mut versions: Vec<String> = fs::read_dir(parent)
+            .with_context(|| format!("Failed to read backup directory: {parent:?}"))?
+            .filter_map(|e| e.ok()?.file_name().into_string().ok())
+            .filter(|n| n.strip_prefix(&prefix).is_some_and(is_backup_stamp))
+            .collect();
+        versions.sort();
+        let excess = versions.len().saturating_sub(keep);
+        for old in &versions[..excess] {
+            let path = parent.join(old);
+            fs::remove_file(&path).with_context(|| format!("Failed to remove old backup: {path:?}"))?;
+        }
+        Ok(())
+    }
+
+    /// Whether `path`, in the target, is a previous version kept by [`Backup::save`].
+    fn is_version(&self, path: &Path) -> bool {
+        if let Some(ref dir) = self.dir {
+            return path.starts_with(dir);
+        }
+        let name = path.file_name().map(|n| n.to_string_lossy()).unwrap_or_default();
+        name.rsplit_once('.')
+            .is_some_and(|(file, stamp)| is_backup_stamp(stamp) && file.len() > self.suffix.len() && file.ends_with(&self.suffix))
+    }
+}
+
+/// Whether `s` is a `YYYYMMDDTHHMMSSZ` timestamp, as appended to backups.
+fn is_backup_stamp(s: &str) -> bool {
+    let b = s.as_bytes();
+    b.len() == 16
+        && b[8] == b'T'
+        && b[15] == b'Z'
+        && b.iter().enumerate().all(|(i, c)| i == 8 || i == 15 || c.is_ascii_digit())
+}
+
+/// The `--backup-*` options, if any was given.
+pub(crate) fn backup_options(target: &Path, suffix: Option<&str>, dir: Option<&Path>, keep: Option<usize>) -> Result<Option<Backup>> {
+    if suffix.is_none() && dir.is_none() {
+        if keep.is_some() {
+            return Err(anyhow!("--backup-keep requires --backup-suffix or --backup-dir"));
+        }
+        return Ok(None);
+    }
+    let suffix = suffix.unwrap_or_default();
+    if suffix.contains(['/', std::path::MAIN_SEPARATOR]) {
+        return Err(anyhow!("--backup-suffix cannot contain a path separator: {suffix:?}"));
+    }
+    Ok(Some(Backup {
+        suffix: suffix.to_string(),
+        dir: dir.map(|dir| target_subdir(target, dir, "--backup-dir")).transpose()?,
+        keep,
+        stamp: format_utc_compact(unix_now()),
+    }))
+}
+
+/// `dir` inside `target`, where `flag` keeps files.
+fn target_subdir(target: &Path, dir: &Path, flag: &str) -> Result<PathBuf> {
+    if dir.as_os_str().is_empty() || !dir.components().all(|c| matches!(c, Component::Normal(_))) {
+        return Err(anyhow!("{flag} must be a relative path inside the target: {dir:?}"));
+    }
+    Ok(target.join(dir))
+}
+
+/// Directory `--trash` moves deleted files into, inside the target.
+pub(crate) const TRASH_DIR: &str = ".fhi-trash";
+
+/// A new directory under `dir` (relative to `target`) for the files deleted by this run, named
+/// after the current time (`<dir>/20240610T020000Z`).
+pub(crate) fn quarantine_dir(target: &Path, dir: &Path) -> Result<PathBuf> {
+    let base = target_subdir(target, dir, "--delete-to")?.join(format_utc_compact(unix_now()));
+    let mut path = base.clone();
+    let mut n = 1;
+    while fs::symlink_metadata(&path).is_ok() {
+        n += 1;
+        let mut name = base.clone().into_os_string();
+        name.push(format!("-{n}"));
+        path = PathBuf::from(name);
+    }
+    Ok(path)
+}
+
+/// Applies `ops` to `target`. Returns how many were skipped because a file did not match its
+/// digest (only checked with [`OpSource::verify`]), and the operations that failed with
+/// `--keep-going`.
+///
+/// Directories are created first, then files are deleted and copied by `opts.jobs` threads
+/// (the operations on a path in order), then hard links are made to the copies and emptied
+/// directories are removed.
+pub(crate) fn apply_ops(ops: &[SyncOp], source: &OpSource, target: &Path, opts: &SyncOptions) -> Result<(usize, Vec<FileError>)> {
+    let ops: Vec<&SyncOp> = ops
+        .iter()
+        .filter(|op| {
+            if op.is_deletion() && !opts.delete {
+                eprintln!("Notice: keeping {} in the target (deleted in the source; use --delete to remove it)", op.path());
+                return false;
+            }
+            match op {
+                SyncOp::Chown { .. } => opts.preserve.owner,
+                SyncOp::Xattrs { .. } => opts.preserve.xattrs || opts.preserve.acls,
+                _ => true,
+            }
+        })
+        .collect();
+    let ops = match opts.journal {
+        Some(ref journal) if journal.resuming => {
+            let before = ops.len();
+            let ops: Vec<&SyncOp> = ops.into_iter().filter(|op| !journal.is_done(op)).collect();
+            eprintln!(
+                "Notice: resuming an interrupted sync; {} operations were already done",
+                before - ops.len()
+            );
+            ops
+        }
+        _ => ops,
+    };
+    if opts.dry_run {
+        for op in ops {
+            let replaces = !matches!(op, SyncOp::Chmod { .. }) && target.join(op.path()).is_file();
+            match (&opts.trash, &opts.backup) {
+                (Some(trash), _) if op.is_deletion() => eprintln!("Would {op} (moving it to {trash:?})"),
+                (_, Some(_)) if replaces => eprintln!("Would {op} (backing up the previous version)"),
+                _ => eprintln!("Would {op}"),
+            }
+        }
+        return Ok((0, Vec::new()));
+    }
+    if !target.exists() {
+        fs::create_dir_all(target)
+            .with_context(|| format!("Failed to create target directory: {target:?}"))?;
+    }
+
+    let mut mkdirs = Vec::new();
+    let mut deletes = Vec::new();
+    let mut by_path: BTreeMap<&str, Vec<&SyncOp>> = BTreeMap::new();
+    let mut links = Vec::new();
+    let mut rmdirs = Vec::new();
+    for &op in &ops {
+        match op {
+            SyncOp::Mkdir { .. } => mkdirs.push(op),
+            SyncOp::Delete { .. } => deletes.push(op),
+            SyncOp::Copy { .. }
+            | SyncOp::Chmod { .. }
+            | SyncOp::Chown { .. }
+            | SyncOp::Xattrs { .. }
+            | SyncOp::Symlink { .. } => {
+                by_path.entry(op.path()).or_default().push(op)
+            }
+            SyncOp::Link { .. } => links.push(op),
+            SyncOp::Rmdir { .. } => rmdirs.push(op),
+        }
+    }
+    // Children before their parents.
+    rmdirs.reverse();
+    let deleted: Vec<&str> = deletes.iter().chain(&rmdirs).map(|op| op.path()).collect();
+
+    let failed = Mutex::new(Vec::new());
+    let apply = |op: &SyncOp| -> Result<OpOutcome> {
+        let result = match op {
+            SyncOp::Copy { .. } | SyncOp::Delete { .. } => {
+                with_retries(op, opts, || apply_op(op, source, target, opts))
+            }
+            _ => apply_op(op, source, target, opts),
+        };
+        let outcome = keep_going(result, op, op.path(), opts, &failed)?.unwrap_or(OpOutcome::Failed);
+        if let (OpOutcome::Done, Some(journal)) = (outcome, &opts.journal) {
+            journal.record(op)?;
+        }
+        Ok(outcome)
+    };
+    let pool = copy_pool(opts)?;
+    let mut outcomes = Vec::with_capacity(ops.len());
+    for op in &mkdirs {
+        outcomes.push(apply(op)?);
+    }
+    let parallel: Vec<Vec<&SyncOp>> = deletes.into_iter().map(|op| vec![op]).collect();
+    for groups in [parallel, by_path.into_values().collect()] {
+        let done: Vec<Vec<OpOutcome>> = pool.install(|| {
+            groups
+                .par_iter()
+                .map(|group| group.iter().map(|op| apply(op)).collect())
+                .collect::<Result<_>>()
+        })?;
+        outcomes.extend(done.into_iter().flatten());
+    }
+    for op in links.iter().chain(&rmdirs) {
+        outcomes.push(apply(op)?);
+    }
+    if !opts.keep_empty_dirs {
+        remove_emptied_dirs(&deleted, source, target)?;
+    }
+    let mut dirs = BTreeSet::new();
+    for op in &ops {
+        if let SyncOp::Mkdir { path } = op {
+            dirs.insert(path.as_str());
+        }
+        add_parents(&mut dirs, op.path());
+    }
+    restore_dir_metadata(dirs, source, target, opts, &failed)?;
+
+    let count = |outcome| outcomes.iter().filter(|&&o| o == outcome).count();
+    let mismatched = count(OpOutcome::Mismatched);
+    if mismatched > 0 {
+        return Err(anyhow!("{mismatched} files copied to the target do not match the source"));
+    }
+    Ok((count(OpOutcome::Skipped), failed.into_inner().unwrap()))
+}
+
+/// Removes the directories of the target left empty by deleting `deleted`, up to (but not
+/// including) the target itself, unless the source still has them.
+fn remove_emptied_dirs(deleted: &[&str], source: &OpSource, target: &Path) -> Result<()> {
+    let mut dirs = BTreeSet::new();
+    for rel in deleted {
+        add_parents(&mut dirs, rel);
+    }
+    dirs.remove("");
+    // Deepest first, so parents are only checked once their children are gone.
+    let mut dirs: Vec<&str> = dirs.into_iter().collect();
+    dirs.sort_by_key(|rel| std::cmp::Reverse(rel.matches('/').count()));
+    for rel in dirs {
+        let dst = patch_path(target, rel)?;
+        if !is_real_dir(&dst) || source.path(rel).is_ok_and(|src| src.is_dir()) {
+            continue;
+        }
+        remove_dir_if_empty(&dst)?;
+    }
+    Ok(())
+}
+
+/// Adds the directories containing `rel` to `dirs`, up to the root (`""`).
+fn add_parents<'a>(dirs: &mut BTreeSet<&'a str>, mut rel: &'a str) {
+    while let Some((parent, _)) = rel.rsplit_once('/') {
+        if !dirs.insert(parent) {
+            return;
+        }
+        rel = parent;
+    }
+    dirs.insert("");
+}
+
+/// Gives `dirs` of the target (relative paths, `""` for the target itself) the permissions
+/// and timestamps of the source directories, once their entries are in place, as creating
+/// the entries changed their timestamps.
+fn restore_dir_metadata(
+    dirs: BTreeSet<&str>,
+    source: &OpSource,
+    target: &Path,
+    opts: &SyncOptions,
+    failed: &Mutex<Vec<FileError>>,
+) -> Result<()> {
+    let restore = |rel: &str| -> Result<()> {
+        let (src, dst) = if rel.is_empty() {
+            (source.root.to_path_buf(), target.to_path_buf())
+        } else {
+            (source.path(rel)?, patch_path(target, rel)?)
+        };
+        let Ok(src_md) = fs::metadata(&src) else { return Ok(()) };
+        if !src_md.is_dir() || !is_real_dir(&dst) {
+            return Ok(());
+        }
+        if opts.preserve.owner {
+            if let Some((uid, gid)) = file_owner(&src_md) {
+                set_owner(&dst, uid, gid)?;
+            }
+        }
+        apply_permissions(&src_md.permissions(), &dst)?;
+        let mtime = filetime::FileTime::from_last_modification_time(&src_md);
+        let atime = filetime::FileTime::from_last_access_time(&src_md);
+        filetime::set_file_times(&dst, atime, mtime)
+            .with_context(|| format!("Failed to apply timestamps to: {dst:?}"))
+    };
+    // Children before their parents (they sort after them).
+    for rel in dirs.into_iter().rev() {
+        let path = if rel.is_empty() { "." } else { rel };
+        keep_going(restore(rel), format_args!("restore the metadata of {path}"), path, opts, failed)?;
+    }
+    Ok(())
+}
+
+fn copy_pool(opts: &SyncOptions) -> Result<rayon::ThreadPool> {
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(opts.jobs)
+        .build()
+        .context("Failed to start the copy threads")
+}
+
+/// Fills `target`, a new snapshot, with the files that did not change since the previous one,
+/// `link_dest` (`--link-dest`): hard links to its files when they are still there, copies from
+/// the source otherwise. Files with a copy or chmod in the sync operations are left to them.
+/// Returns the files that could not be copied, with `--keep-going`.
+pub(crate) fn link_unchanged(
+    source: &OpSource,
+    link_dest: &Path,
+    target: &Path,
+    changes: &[Change],
+    new: &HashMap<String, Entry>,
+    opts: &SyncOptions,
+) -> Result<Vec<FileError>> {
+    let resuming = opts.journal.as_ref().is_some_and(|j| j.resuming);
+    if !resuming && fs::read_dir(target).is_ok_and(|mut dir| dir.next().is_some()) {
+        return Err(anyhow!("--link-dest needs a new, empty target directory: {target:?}"));
+    }
+    let changed: HashSet<&str> = changes.iter().map(|ch| ch.path()).collect();
+    let synced: HashSet<&str> = changes
+        .iter()
+        .filter(|ch| matches!(ch, Change::Added(_) | Change::Updated(_) | Change::SizeAnomaly(_) | Change::Permissions(_)))
+        .map(|ch| ch.path())
+        .collect();
+    let mut dirs: Vec<&str> = Vec::new();
+    let mut files: Vec<(&str, bool)> = Vec::new();
+    let mut symlinks: Vec<SyncOp> = Vec::new();
+    for (rel, e) in new {
+        match e.kind {
+            EntryKind::Dir => dirs.push(rel),
+            EntryKind::Link if !synced.contains(rel.as_str()) => {
+                if let Some(ref to) = e.target {
+                    symlinks.push(SyncOp::Symlink { path: rel.clone(), target: to.clone() });
+                }
+            }
+            EntryKind::File if !synced.contains(rel.as_str()) => {
+                // Only what is unchanged, and still the same size in the previous snapshot, is shared.
+                let previous = fs::metadata(link_dest.join(rel)).ok();
+                let link = !changed.contains(rel.as_str()) && previous.is_some_and(|m| m.is_file() && m.len() == e.size);
+                files.push((rel, link));
+            }
+            _ => {}
+        }
+    }
+    dirs.sort_unstable();
+    files.sort_unstable();
+    symlinks.sort_unstable_by(|a, b| a.path().cmp(b.path()));
+
+    if opts.dry_run {
+        let linked = files.iter().filter(|(_, link)| *link).count();
+        eprintln!("Would link {linked} unchanged files from {link_dest:?}");
+        for (rel, _) in files.iter().filter(|(_, link)| !link) {
+            eprintln!("Would copy {rel}");
+        }
+        for op in &symlinks {
+            eprintln!("Would {op}");
+        }
+        return Ok(Vec::new());
+    }
+    for &rel in &dirs {
+        let dst = patch_path(target, rel)?;
+        fs::create_dir_all(&dst).with_context(|| format!("Failed to create directory in target: {dst:?}"))?;
+    }
+    let link_or_copy = |rel: &str, link: bool| -> Result<()> {
+        let dst = patch_path(target, rel)?;
+        // Links and copies are made in place atomically, so what exists is complete.
+        if resuming && fs::symlink_metadata(&dst).is_ok() {
+            return Ok(());
+        }
+        create_parent(&dst)?;
+        if link {
+            let previous = patch_path(link_dest, rel)?;
+            match fs::hard_link(&previous, &dst) {
+                Ok(()) => return Ok(()),
+                Err(err) => eprintln!("Warning: copying {rel} instead of linking it to {previous:?}: {err}"),
+            }
+        }
+        let src = source.path(rel)?;
+        with_retries(format_args!("copy {rel}"), opts, || copy_with_permissions(&src, &dst, opts))
+    };
+    let failed = Mutex::new(Vec::new());
+    copy_pool(opts)?.install(|| {
+        files.par_iter().try_for_each(|&(rel, link)| {
+            keep_going(link_or_copy(rel, link), format_args!("copy {rel}"), rel, opts, &failed).map(drop)
+        })
+    })?;
+    for op in &symlinks {
+        keep_going(apply_op(op, source, target, opts), op, op.path(), opts, &failed)?;
+    }
+    let mut parents = BTreeSet::new();
+    for rel in dirs.iter().copied().chain(files.iter().map(|(rel, _)| *rel)).chain(symlinks.iter().map(|op| op.path())) {
+        add_parents(&mut parents, rel);
+    }
+    parents.extend(dirs);
+    restore_dir_metadata(parents, source, target, opts, &failed)?;
+    Ok(failed.into_inner().unwrap())
+}
+
+/// The deletions that remove from `target` what is not in `new` (`--mirror`): files, links and directories, walked as the source was (so excluded paths are
+/// left alone), below the target directory of each of `sources`. The trash (`--trash`,
+/// `--delete-to`) and the previous versions kept with this run's options are not touched.
+pub(crate) fn mirror_ops(
+    source: &OpSource,
+    target: &Path,
+    sources: &[PathBuf],
+    new: &HashMap<String, Entry>,
+    walk: &WalkOptions,
+    paths: PathForm,
+    opts: &SyncOptions,
+) -> Result<Vec<SyncOp>> {
+    let dirs: Vec<PathBuf> = sources
+        .iter()
+        .filter_map(|s| s.strip_prefix(source.root).ok())
+        .map(|rel| target.join(rel))
+        .filter(|dir| dir.is_dir())
+        .collect();
+    if dirs.is_empty() {
+        return Ok(Vec::new());
+    }
+    let walk = WalkOptions {
+        // Links in the target are entries to remove, not directories to enter.
+        follow_symlinks: false,
+        special_files: SpecialFiles::Skip,
+        ..walk.clone()
+    };
+    let collected = collect_files(target, &dirs, &walk)?;
+    // The trash of every run, not only this one's.
+    let trash = opts.trash.as_deref().and_then(Path::parent);
+    let kept = |path: &Path| {
+        path.starts_with(target.join(TRASH_DIR))
+            || trash.is_some_and(|trash| path.starts_with(trash))
+            || opts.backup.as_ref().is_some_and(|backup| backup.is_version(path))
+    };
+    let stale = |path: &Path| {
+        let rel = path_to_rel_unix(target, path);
+        (!new.contains_key(paths.apply(&rel).as_ref()) && !kept(path)).then_some(rel)
+    };
+    let mut stale_ops: Vec<SyncOp> = collected
+        .files
+        .iter()
+        .chain(&collected.links)
+        .filter_map(|path| stale(path))
+        .map(|path| SyncOp::Delete { path, hash: String::new() })
+        .collect();
+    let mut stale_dirs: Vec<String> = collected
+        .dirs
+        .iter()
+        .filter_map(|path| stale(path))
+        .filter(|rel| !source.path(rel).is_ok_and(|src| src.is_dir()))
+        .collect();
+    // Parents first, as the removals are applied in reverse.
+    stale_dirs.sort_unstable();
+    stale_ops.sort_unstable_by(|a, b| a.path().cmp(b.path()));
+    stale_ops.extend(stale_dirs.into_iter().map(|path| SyncOp::Rmdir { path }));
+    Ok(stale_ops)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum OpOutcome {
+    Done,
+    /// Not applied because a file did not match its digest.
+    Skipped,
+    /// Copied, but the copy does not match the source (`--verify-copies`).
+    Mismatched,
+    /// Failed, to be listed at the end of the run (`--keep-going`).
+    Failed,
+}
+
+/// Runs `f`, and again up to `--copy-retries` times while it fails with a transient error,
+/// waiting `--copy-retry-delay` (doubled after each attempt) in between.
+fn with_retries<T>(what: impl std::fmt::Display, opts: &SyncOptions, mut f: impl FnMut() -> Result<T>) -> Result<T> {
+    let mut delay = opts.retry_delay;
+    for attempt in 1.. {
+        match f() {
+            Err(err) if attempt <= opts.retries && is_transient(&err) => {
+                eprintln!(
+                    "Warning: failed to {what} ({err:#}); retrying in {delay:?} ({attempt} of {})",
+                    opts.retries
+                );
+                std::thread::sleep(delay);
+                delay = delay.saturating_mul(2);
+            }
+            result => return result,
+        }
+    }
+    unreachable!()
+}
+
+/// Whether an I/O error may go away by itself, as network filesystems (SMB, NFS) report
+/// dropped connections and timeouts.
+fn is_transient(err: &anyhow::Error) -> bool {
+    let Some(err) = err.chain().find_map(|e| e.downcast_ref::<io::Error>()) else {
+        return false;
+    };
+    #[cfg(unix)]
+    if let Some(code) = err.raw_os_error() {
+        if [libc::EIO, libc::ESTALE, libc::EAGAIN, libc::EBUSY, libc::ETIMEDOUT].contains(&code) {
+            return true;
+        }
+    }
+    matches!(
+        err.kind(),
+        io::ErrorKind::TimedOut
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::ResourceBusy
+            | io::ErrorKind::StaleNetworkFileHandle
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::NetworkDown
+            | io::ErrorKind::HostUnreachable
+            | io::ErrorKind::NetworkUnreachable
+    )
+}
+
+pub(crate) fn create_parent(dst: &Path) -> Result<()> {
+    if let Some(parent) = dst.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create parent directory in target: {parent:?}"))?;
+    }
+    Ok(())
+}
+
+fn apply_op(op: &SyncOp, source: &OpSource, target: &Path, opts: &SyncOptions) -> Result<OpOutcome> {
+    match op {
+        SyncOp::Copy { path, size, hash, partial } => {
+            let (src, dst) = (source.path(path)?, patch_path(target, path)?);
+            if source.verify.is_some() {
+                let current = fs::metadata(&src).map(|m| m.len()).ok();
+                if current != Some(*size) || !source.matches(&src, *size, hash, partial.as_deref())? {
+                    eprintln!("Warning: {path} changed in the source since the patch was made; not copied");
+                    return Ok(OpOutcome::Skipped);
+                }
+            }
+            if is_real_dir(&dst) {
+                remove_dir_if_empty(&dst)?;
+            }
+            if let (Some(backup), true) = (&opts.backup, dst.is_file()) {
+                backup.save(path, &dst, true)?;
+            }
+            create_parent(&dst)?;
+            copy_with_permissions(&src, &dst, opts)
+                .with_context(|| format!("Failed copying '{src:?}' -> '{dst:?}'"))?;
+            if let Some((algo, ref key)) = opts.verify_copies {
+                // Written pages are only dropped from the cache once they are on the device.
+                File::open(&dst)
+                    .and_then(|f| f.sync_all())
+                    .with_context(|| format!("Failed to flush copy: {dst:?}"))?;
+                if !content_matches(&dst, *size, hash, partial.as_deref(), algo, key.as_ref(), true)? {
+                    eprintln!("Warning: the copy of {path} in the target does not match the source");
+                    return Ok(OpOutcome::Mismatched);
+                }
+            }
+        }
+        SyncOp::Link { path, to } => {
+            let (original, dst) = (patch_path(target, to)?, patch_path(target, path)?);
+            create_parent(&dst)?;
+            if let (Some(backup), true) = (&opts.backup, dst.is_file()) {
+                if file_id(&dst).is_none() || file_id(&dst) != file_id(&original) {
+                    backup.save(path, &dst, true)?;
+                }
+            }
+            if original.is_file() {
+                match replace_with_hard_link(&original, &dst) {
+                    Ok(()) => return Ok(OpOutcome::Done),
+                    Err(err) => eprintln!("Warning: copying {path} instead of linking it to {to}: {err:#}"),
+                }
+            }
+            let src = source.path(path)?;
+            copy_with_permissions(&src, &dst, opts)
+                .with_context(|| format!("Failed copying '{src:?}' -> '{dst:?}'"))?;
+        }
+        SyncOp::Chown { path, uid, gid } => {
+            let (src, dst) = (source.path(path)?, patch_path(target, path)?);
+            if fs::symlink_metadata(&dst).is_err() {
+                create_parent(&dst)?;
+                copy_with_permissions(&src, &dst, opts)
+                    .with_context(|| format!("Failed copying '{src:?}' -> '{dst:?}'"))?;
+                return Ok(OpOutcome::Done);
+            }
+            set_owner(&dst, *uid, *gid)?;
+        }
+        SyncOp::Xattrs { path } => {
+            let (src, dst) = (source.path(path)?, patch_path(target, path)?);
+            if !dst.is_file() {
+                create_parent(&dst)?;
+                copy_with_permissions(&src, &dst, opts)
+                    .with_context(|| format!("Failed copying '{src:?}' -> '{dst:?}'"))?;
+                return Ok(OpOutcome::Done);
+            }
+            copy_xattrs_and_acls(&src, &dst, &opts.preserve)?;
+        }
+        SyncOp::Chmod { path, mode } => {
+            let (src, dst) = (source.path(path)?, patch_path(target, path)?);
+            if !dst.is_file() {
+                create_parent(&dst)?;
+                copy_with_permissions(&src, &dst, opts)
+                    .with_context(|| format!("Failed copying '{src:?}' -> '{dst:?}'"))?;
+                return Ok(OpOutcome::Done);
+            }
+            #[cfg(unix)]
+            fs::set_permissions(&dst, fs::Permissions::from_mode(*mode))
+                .with_context(|| format!("Failed to apply permissions (mode {mode:o}) to: {dst:?}"))?;
+            #[cfg(not(unix))]
+            {
+                let _ = mode;
+                let src_md = fs::metadata(&src)
+                    .with_context(|| format!("Failed to read source metadata: {src:?}"))?;
+                apply_permissions(&src_md.permissions(), &dst)?;
+            }
+        }
+        SyncOp::Delete { path, hash } => {
+            let dst = patch_path(target, path)?;
+            // A symlink has no content to check, and removing it leaves what it points to.
+            let symlink = fs::symlink_metadata(&dst).is_ok_and(|m| m.file_type().is_symlink());
+            if !symlink && !dst.is_file() {
+                return Ok(OpOutcome::Done);
+            }
+            let size = fs::metadata(&dst).map(|m| m.len()).unwrap_or(0);
+            if !symlink && !source.matches(&dst, size, hash, None)? {
+                eprintln!("Warning: {path} in the target is not the deleted file; not deleted");
+                return Ok(OpOutcome::Skipped);
+            }
+            if let Some(ref backup) = opts.backup {
+                backup.save(path, &dst, false)?;
+                return Ok(OpOutcome::Done);
+            }
+            match opts.trash {
+                Some(ref trash) => {
+                    let to = trash.join(path);
+                    create_parent(&to)?;
+                    fs::rename(&dst, &to).with_context(|| format!("Failed to move {dst:?} to {to:?}"))?;
+                }
+                None => {
+                    // Links to directories are directories on Windows.
+                    fs::remove_file(&dst)
+                        .or_else(|err| if symlink && cfg!(windows) { fs::remove_dir(&dst) } else { Err(err) })
+                        .with_context(|| format!("Failed to delete in target: {dst:?}"))?;
+                }
+            }
+        }
+        SyncOp::Mkdir { path } => {
+            let dst = patch_path(target, path)?;
+            if fs::symlink_metadata(&dst).is_ok_and(|m| !m.is_dir()) {
+                match opts.backup {
+                    Some(ref backup) => backup.save(path, &dst, false)?,
+                    None => fs::remove_file(&dst).with_context(|| format!("Failed to delete in target: {dst:?}"))?,
+                }
+            }
+            fs::create_dir_all(&dst)
+                .with_context(|| format!("Failed to create directory in target: {dst:?}"))?;
+        }
+        SyncOp::Rmdir { path } => {
+            let dst = patch_path(target, path)?;
+            if is_real_dir(&dst) {
+                remove_dir_if_empty(&dst)?;
+            }
+        }
+        SyncOp::Symlink { path, target: to } => {
+            let dst = patch_path(target, path)?;
+            if is_real_dir(&dst) {
+                remove_dir_if_empty(&dst)?;
+            }
+            if let (Some(backup), true) = (&opts.backup, dst.is_file()) {
+                backup.save(path, &dst, true)?;
+            }
+            create_parent(&dst)?;
+            // A link to a directory is a different kind of link on Windows.
+            let to_dir = fs::metadata(source.path(path)?).is_ok_and(|m| m.is_dir());
+            if let Err(err) = replace_with_symlink(Path::new(to), to_dir, &dst) {
+                eprintln!("Warning: not replicating link {path} to the target: {err:#}");
+            }
+        }
+    }
+    Ok(OpOutcome::Done)
+}
+
+/// What syncing a change to a copy of the indexed directory takes (`--target`), also the
+/// lines of a patch file after the header (`--emit-patch`). Digests are those of the source
+/// file (`copy`) or of the deleted one (`delete`), so `apply` can check that it copies and
+/// deletes what the scan saw; they are empty when unknown.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub(crate) enum SyncOp {
+    Copy {
+        path: String,
+        size: u64,
+        hash: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        partial: Option<String>,
+    },
+    /// A hard link to `to`, another path of the same sync or already in the target.
+    Link { path: String, to: String },
+    Chmod { path: String, mode: u32 },
+    /// Only applied with `--preserve-owner`.
+    Chown { path: String, uid: u32, gid: u32 },
+    /// Copy the extended attributes and ACLs of the source file; only applied with
+    /// `--preserve-xattrs` or `--preserve-acls`.
+    Xattrs { path: String },
+    Delete { path: String, hash: String },
+    Mkdir { path: String },
+    Rmdir { path: String },
+    /// A symbolic link with `target` as stored in the source link.
+    Symlink { path: String, target: String },
+}
+
+impl SyncOp {
+    pub(crate) fn path(&self) -> &str {
+        match self {
+            SyncOp::Copy { path, .. }
+            | SyncOp::Link { path, .. }
+            | SyncOp::Chmod { path, .. }
+            | SyncOp::Chown { path, .. }
+            | SyncOp::Xattrs { path }
+            | SyncOp::Symlink { path, .. }
+            | SyncOp::Delete { path, .. }
+            | SyncOp::Mkdir { path }
+            | SyncOp::Rmdir { path } => path,
+        }
+    }
+
+    pub(crate) fn is_deletion(&self) -> bool {
+        matches!(self, SyncOp::Delete { .. } | SyncOp::Rmdir { .. })
+    }
+}
+
+impl std::fmt::Display for SyncOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SyncOp::Copy { path, .. } => write!(f, "copy {path}"),
+            SyncOp::Link { path, to } => write!(f, "link {path} to {to}"),
+            SyncOp::Chmod { path, mode } => write!(f, "set mode {mode:o} on {path}"),
+            SyncOp::Chown { path, uid, gid } => write!(f, "set owner {uid}:{gid} on {path}"),
+            SyncOp::Xattrs { path } => write!(f, "copy extended attributes of {path}"),
+            SyncOp::Delete { path, .. } => write!(f, "delete {path}"),
+            SyncOp::Mkdir { path } => write!(f, "create directory {path}"),
+            SyncOp::Rmdir { path } => write!(f, "remove directory {path} (if empty)"),
+            SyncOp::Symlink { path, target } => write!(f, "create symlink {path} -> {target}"),
+        }
+    }
+}
+
+/// The operations that sync `changes` to a copy of the indexed directory, hashed with `algo`.
+pub(crate) fn sync_ops(changes: &[Change], old: &HashMap<String, Entry>, new: &HashMap<String, Entry>, algo: Algo) -> Vec<SyncOp> {
+    let mut ops = Vec::new();
+    for ch in changes {
+        let path = ch.path().to_string();
+        match ch {
+            Change::Added(rel) | Change::Updated(rel) | Change::SizeAnomaly(rel) => {
+                let Some(e) = new.get(rel) else { continue };
+                match e.kind {
+                    EntryKind::Dir => ops.push(SyncOp::Mkdir { path }),
+                    EntryKind::Link => match e.target {
+                        Some(ref to) => ops.push(SyncOp::Symlink { path, target: to.clone() }),
+                        None => eprintln!("Warning: not replicating link {rel} to the target"),
+                    },
+                    EntryKind::Special => eprintln!("Warning: not replicating special file {rel} to the target"),
+                    EntryKind::File => match e.hardlink {
+                        Some(ref first) => ops.push(SyncOp::Link { path, to: first.clone() }),
+                        None => ops.push(SyncOp::Copy {
+                            path,
+                            size: e.size,
+                            hash: if e.algo.as_deref() == Some(algo_name(algo)) { e.hash_hex.clone() } else { String::new() },
+                            partial: e.partial.clone(),
+                        }),
+                    },
+                }
+            }
+            Change::Permissions(rel) => {
+                if let Some(mode) = new.get(rel).and_then(|e| e.mode) {
+                    ops.push(SyncOp::Chmod { path, mode });
+                }
+            }
+            Change::Owner(rel) => {
+                if let Some((Some(uid), Some(gid))) = new.get(rel).map(|e| (e.uid, e.gid)) {
+                    ops.push(SyncOp::Chown { path, uid, gid });
+                }
+            }
+            // Also ACL changes, where extended attributes are not recorded.
+            Change::Xattrs(rel) | Change::Metadata(rel) => {
+                if new.get(rel).is_some_and(|e| e.kind.is_file()) {
+                    ops.push(SyncOp::Xattrs { path });
+                }
+            }
+            Change::Touched(_) => {}
+            Change::Deleted(rel) => {
+                let Some(e) = old.get(rel) else { continue };
+                match e.kind {
+                    EntryKind::Dir => ops.push(SyncOp::Rmdir { path }),
+                    EntryKind::Link => ops.push(SyncOp::Delete { path, hash: String::new() }),
+                    EntryKind::Special => {}
+                    EntryKind::File => {
+                        // Digests of another algorithm, or partial ones, cannot be checked.
+                        let comparable = e.partial.is_none() && e.algo.as_deref() == Some(algo_name(algo));
+                        let hash = if comparable { e.hash_hex.clone() } else { String::new() };
+                        ops.push(SyncOp::Delete { path, hash });
+                    }
+                }
+            }
+        }
+    }
+    // Links go last, once the files they link to are in place.
+    ops.sort_by_key(|op| matches!(op, SyncOp::Link { .. }));
+    ops
+}
+
+/// Applies case-only renames (`--case-insensitive-paths`) to the target before syncing, so a
+/// case-insensitive target keeps the new spelling instead of losing the file.
+pub(crate) fn rename_in_target(target: &Path, renames: &[(String, String)], opts: &SyncOptions) -> Result<()> {
+    for (from, to) in renames {
+        let (src, dst) = (target.join(from), target.join(to));
+        if fs::symlink_metadata(&src).is_err() {
+            continue;
+        }
+        if opts.dry_run {
+            eprintln!("Would rename {from} to {to}");
+            continue;
+        }
+        if let Some(parent) = dst.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create parent directory in target: {parent:?}"))?;
+        }
+        fs::rename(&src, &dst).with_context(|| format!("Failed to rename in target: {src:?} -> {dst:?}"))?;
+        // A directory renamed by case leaves its old spelling empty on case-sensitive targets.
+        for dir in src.ancestors().skip(1).take_while(|d| *d != target) {
+            if fs::remove_dir(dir).is_err() {
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Removes a directory recorded as empty; it is kept if it has gained content since.
+fn remove_dir_if_empty(dir: &Path) -> Result<()> {
+    let is_empty = fs::read_dir(dir)
+        .with_context(|| format!("Failed to read directory in target: {dir:?}"))?
+        .next()
+        .is_none();
+    if is_empty {
+        fs::remove_dir(dir).with_context(|| format!("Failed to delete in target: {dir:?}"))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+    use crate::walk::{build_globset, build_prune_set};
+
+    fn copy_op(path: &str) -> SyncOp {
+        SyncOp::Copy { path: path.to_string(), size: 2, hash: String::new(), partial: None }
+    }
+
+    fn journal_text(target: &Path, ops: &[&SyncOp]) -> String {
+        let header = JournalHeader { version: 1, target: target.to_string_lossy().into_owned() };
+        let mut text = serde_json::to_string(&header).unwrap() + "\n";
+        for op in ops {
+            text += &(serde_json::to_string(op).unwrap() + "\n");
+        }
+        text
+    }
+
+    #[test]
+    fn journal_ignores_a_truncated_last_line() {
+        let dir = TestDir::new();
+        let target = dir.path().join("target");
+        let line = serde_json::to_string(&copy_op("b")).unwrap();
+        let text = journal_text(&target, &[&copy_op("a")]) + &line[..line.len() / 2];
+        let path = dir.write("s.txt.sync-journal", &text);
+
+        let journal = Journal::open(path.clone(), &target).unwrap();
+        assert!(journal.resuming);
+        assert!(journal.is_done(&copy_op("a")));
+        assert!(!journal.is_done(&copy_op("b")));
+        drop(journal);
+        assert_eq!(fs::read_to_string(&path).unwrap(), journal_text(&target, &[&copy_op("a")]));
+    }
+
+    #[test]
+    fn journal_of_another_target_is_not_resumed() {
+        let dir = TestDir::new();
+        let path = dir.write("s.txt.sync-journal", &journal_text(&dir.path().join("other"), &[&copy_op("a")]));
+
+        let journal = Journal::open(path, &dir.path().join("target")).unwrap();
+        assert!(!journal.resuming);
+        assert!(!journal.is_done(&copy_op("a")));
+    }
+
+    #[test]
+    fn resumed_sync_skips_the_copies_already_done() {
+        let dir = TestDir::new();
+        dir.write("src/a", "a\n");
+        dir.write("src/b", "b\n");
+        let target = dir.path().join("target");
+        let path = dir.write("s.txt.sync-journal", &journal_text(&target, &[&copy_op("a")]));
+        let source = OpSource { root: &dir.path().join("src"), disk_paths: &HashMap::new(), verify: None };
+        let opts = SyncOptions { journal: Some(Journal::open(path.clone(), &target).unwrap()), ..Default::default() };
+
+        apply_ops(&[copy_op("a"), copy_op("b")], &source, &target, &opts).unwrap();
+        assert!(!target.join("a").exists(), "journaled copies are not redone");
+        assert_eq!(fs::read_to_string(target.join("b")).unwrap(), "b\n");
+        let journaled = fs::read_to_string(&path).unwrap();
+        assert_eq!(journaled.lines().count(), 3, "the new copy is journaled: {journaled}");
+    }
+
+    #[test]
+    fn deletions_are_only_applied_with_delete() {
+        let dir = TestDir::new();
+        let root = dir.path().join("src");
+        fs::create_dir(&root).unwrap();
+        let target = dir.path().join("target");
+        dir.write("target/gone", "old\n");
+        fs::create_dir(target.join("emptied")).unwrap();
+        let source = OpSource { root: &root, disk_paths: &HashMap::new(), verify: None };
+        let ops = [
+            SyncOp::Delete { path: "gone".to_string(), hash: String::new() },
+            SyncOp::Rmdir { path: "emptied".to_string() },
+        ];
+
+        apply_ops(&ops, &source, &target, &SyncOptions::default()).unwrap();
+        assert!(target.join("gone").is_file());
+        assert!(target.join("emptied").is_dir());
+
+        apply_ops(&ops, &source, &target, &SyncOptions { delete: true, ..Default::default() }).unwrap();
+        assert!(!target.join("gone").exists());
+        assert!(!target.join("emptied").exists());
+    }
+
+    #[test]
+    fn mirror_leaves_excluded_paths_trash_and_backups_alone() {
+        let dir = TestDir::new();
+        dir.write("src/a", "a\n");
+        let root = dir.path().join("src");
+        let target = dir.path().join("target");
+        for rel in ["a", "stale", "cache/z", ".fhi-trash/20250101T000000Z/x", "quarantine/20250101T000000Z/y", "a~.20250101T000000Z"] {
+            dir.write(&format!("target/{rel}"), "kept\n");
+        }
+        let source = OpSource { root: &root, disk_paths: &HashMap::new(), verify: None };
+        let new = HashMap::from([("a".to_string(), Entry { rel_path: "a".to_string(), ..Default::default() })]);
+        let excludes = ["cache".to_string()];
+        let walk = WalkOptions {
+            excludes: build_globset(&excludes, "exclude", false).unwrap(),
+            prune: build_prune_set(&excludes, false).unwrap(),
+            exclude_regex: regex::RegexSet::empty(),
+            includes: None,
+            include_regex: None,
+            follow_symlinks: false,
+            gitignore: false,
+            fhignore: false,
+            max_depth: None,
+            one_file_system: false,
+            hidden: true,
+            git_tracked: None,
+            cachedir_tag: false,
+            special_files: SpecialFiles::Skip,
+        };
+        let opts = SyncOptions {
+            trash: Some(quarantine_dir(&target, Path::new("quarantine")).unwrap()),
+            backup: backup_options(&target, Some("~"), None, None).unwrap(),
+            ..Default::default()
+        };
+
+        let ops = mirror_ops(&source, &target, std::slice::from_ref(&root), &new, &walk, PathForm::Nfc, &opts).unwrap();
+        let paths: Vec<String> = ops.iter().map(ToString::to_string).collect();
+        assert_eq!(paths, ["delete stale"]);
+    }
+}
@@ -0,0 +1,186 @@
+//! Parsing and formatting timestamps and durations.
+
+use anyhow::Result;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub(crate) fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Formats seconds since the Unix epoch as `YYYYMMDDTHHMMSSZ` (UTC).
+pub(crate) fn format_utc_compact(secs: u64) -> String {
+    let (year, month, day) = civil_from_days(secs / 86_400);
+    let rem = secs % 86_400;
+    format!(
+        "{year:04}{month:02}{day:02}T{:02}{:02}{:02}Z",
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+/// Formats seconds since the Unix epoch as `YYYY-MM-DD HH:MM:SS UTC`.
+pub(crate) fn format_utc(secs: u64) -> String {
+    let (year, month, day) = civil_from_days(secs / 86_400);
+    let rem = secs % 86_400;
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02} UTC",
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+/// Parses `--newer-than`/`--older-than`: a duration before now (`<number><s|m|h|d|w>`, e.g.
+/// `90m`, `7d`), an RFC 3339 timestamp (`2024-06-10T02:00:00Z`, `2024-06-10T04:00:00+02:00`)
+/// or a date (`2024-06-10`, midnight UTC).
+pub(crate) fn parse_time_spec(s: &str) -> Result<SystemTime, String> {
+    let s = s.trim();
+    if let Some(ago) = duration_with_unit(s) {
+        return Ok(SystemTime::now().checked_sub(ago?).unwrap_or(UNIX_EPOCH));
+    }
+    let secs = parse_rfc3339(s).ok_or_else(|| format!("invalid duration or timestamp: {s:?}"))?;
+    Ok(UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+/// Parses `--copy-retry-delay`: seconds, or a number with a unit (`500ms`, `2s`, `1m`, ...).
+pub(crate) fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    match s.parse::<u64>() {
+        Ok(secs) => Ok(Duration::from_secs(secs)),
+        Err(_) => duration_with_unit(s).unwrap_or_else(|| Err(format!("invalid duration: {s:?}"))),
+    }
+}
+
+/// A `<number><ms|s|m|h|d|w>` duration, or `None` if `s` is not of that form.
+fn duration_with_unit(s: &str) -> Option<Result<Duration, String>> {
+    let (n, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit())?);
+    if n.is_empty() || !unit.bytes().all(|b| b.is_ascii_alphabetic()) {
+        return None;
+    }
+    let n: u64 = n.parse().ok()?;
+    let secs = match unit {
+        "ms" => return Some(Ok(Duration::from_millis(n))),
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86_400,
+        "w" => 7 * 86_400,
+        _ => return Some(Err(format!("unknown duration unit {unit:?} (use ms, s, m, h, d or w)"))),
+    };
+    Some(Ok(Duration::from_secs(n.saturating_mul(secs))))
+}
+
+/// Seconds since the Unix epoch of an RFC 3339 timestamp (fractional seconds are dropped) or
+/// of a `YYYY-MM-DD` date at midnight UTC. Times before the epoch are rejected.
+fn parse_rfc3339(s: &str) -> Option<u64> {
+    let num = |part: &str| part.bytes().all(|b| b.is_ascii_digit()).then(|| part.parse::<i64>().ok()).flatten();
+    let (date, time) = match s.split_once(['T', 't', ' ']) {
+        Some((date, time)) => (date, Some(time)),
+        None => (s, None),
+    };
+    let mut ymd = date.split('-');
+    let (year, month, day) = (num(ymd.next()?)?, num(ymd.next()?)?, num(ymd.next()?)?);
+    if ymd.next().is_some() || !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    let mut secs = days_from_civil(year, month, day) * 86_400;
+    if let Some(time) = time {
+        let (clock, offset) = if let Some(clock) = time.strip_suffix(['Z', 'z']) {
+            (clock, 0)
+        } else {
+            let at = time.rfind(['+', '-'])?;
+            let (clock, offset) = time.split_at(at);
+            let (oh, om) = offset[1..].split_once(':')?;
+            let minutes = num(oh)? * 60 + num(om)?;
+            (clock, if offset.starts_with('-') { -minutes * 60 } else { minutes * 60 })
+        };
+        let clock = clock.split_once('.').map_or(clock, |(whole, _)| whole);
+        let mut hms = clock.split(':');
+        let (h, m, sec) = (num(hms.next()?)?, num(hms.next()?)?, num(hms.next()?)?);
+        if hms.next().is_some() || h > 23 || m > 59 || sec > 60 {
+            return None;
+        }
+        secs += h * 3600 + m * 60 + sec - offset;
+    }
+    u64::try_from(secs).ok()
+}
+
+/// Converts a Gregorian date to days since 1970-01-01 (negative before it).
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    // Howard Hinnant's `days_from_civil`, the inverse of [`civil_from_days`].
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+/// Converts days since 1970-01-01 to a (year, month, day) Gregorian date.
+pub(crate) fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    // Howard Hinnant's `civil_from_days`, restricted to dates after the epoch.
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z % 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    #[test]
+    fn rfc3339_parsing() {
+        assert_eq!(parse_rfc3339("1970-01-01T00:00:00Z"), Some(0));
+        assert_eq!(parse_rfc3339("2024-06-10"), Some(1_717_977_600));
+        assert_eq!(parse_rfc3339("2024-06-10T02:00:00Z"), Some(1_717_977_600 + 7200));
+        assert_eq!(parse_rfc3339("2024-06-10T04:00:00+02:00"), Some(1_717_977_600 + 7200));
+        assert_eq!(parse_rfc3339("2024-06-09t21:30:00.75-04:30"), Some(1_717_977_600 + 7200));
+        assert_eq!(parse_rfc3339("2024-02-29 12:00:00Z"), Some(1_709_208_000));
+        for invalid in ["", "2024-13-01", "2024-06-32", "2024-06-10T24:00:00Z", "2024-06-10T02:00Z",
+            "2024-06-10T02:00:00", "1969-12-31T23:59:59Z", "+2024-06-10"]
+        {
+            assert_eq!(parse_rfc3339(invalid), None, "{invalid:?}");
+        }
+    }
+
+    #[test]
+    fn duration_parsing() {
+        assert_eq!(parse_duration("3"), Ok(Duration::from_secs(3)));
+        assert_eq!(parse_duration("500ms"), Ok(Duration::from_millis(500)));
+        assert_eq!(parse_duration(" 2s "), Ok(Duration::from_secs(2)));
+        assert_eq!(parse_duration("1m"), Ok(Duration::from_secs(60)));
+        assert_eq!(parse_duration("2h"), Ok(Duration::from_secs(7200)));
+        assert_eq!(parse_duration("1w"), Ok(Duration::from_secs(604_800)));
+        for invalid in ["", "s", "5x", "5 s", "-1s", "1.5s"] {
+            assert!(parse_duration(invalid).is_err(), "{invalid:?}");
+        }
+    }
+
+    #[test]
+    fn time_spec_parsing() {
+        let week = Duration::from_secs(7 * 86_400);
+        let before = SystemTime::now();
+        let spec = parse_time_spec("7d").unwrap();
+        let after = SystemTime::now();
+        assert!(before - week <= spec && spec <= after - week);
+        assert_eq!(
+            parse_time_spec("2024-06-10T04:00:00+02:00"),
+            Ok(UNIX_EPOCH + Duration::from_secs(1_717_977_600 + 7200))
+        );
+        assert!(parse_time_spec("yesterday").is_err());
+        assert!(parse_time_spec("3y").is_err());
+    }
+}
@@ -0,0 +1,741 @@
+//! Walking the sources: excludes, includes, ignore files and the files that make up an index.
+
+use crate::scan::{file_id, file_mode};
+use crate::state::{is_stdio, Entry, EntryKind};
+use anyhow::{anyhow, Context, Result};
+use clap::ValueEnum;
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use ignore::WalkBuilder;
+use regex::{RegexSet, RegexSetBuilder};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use unicode_normalization::{is_nfc, is_nfd, UnicodeNormalization};
+
+/// Compiles `--exclude`/`--include` patterns (`what` names them in errors). A bare name also
+/// matches everything below a directory of that name.
+pub(crate) fn build_globset(patterns: &[String], what: &str, ignore_case: bool) -> Result<GlobSet> {
+    compile_globs(expand_patterns(patterns), what, ignore_case)
+}
+
+/// Directories that need not be entered because everything below them is excluded: those
+/// matching an exclude pattern, or its prefix if it ends in `/**`.
+pub(crate) fn build_prune_set(patterns: &[String], ignore_case: bool) -> Result<GlobSet> {
+    let expanded = expand_patterns(patterns);
+    let prefixes: Vec<String> = expanded
+        .iter()
+        .filter_map(|pat| pat.strip_suffix("/**"))
+        .filter(|prefix| !prefix.is_empty() && *prefix != "**")
+        .map(str::to_string)
+        .collect();
+    compile_globs(expanded.into_iter().chain(prefixes), "exclude", ignore_case)
+}
+
+/// Patterns of an `--exclude-from` file: one per line, surrounding whitespace ignored, blank
+/// lines and lines starting with `#` skipped.
+pub(crate) fn read_pattern_file(path: &Path) -> Result<Vec<String>> {
+    let text = fs::read_to_string(path).with_context(|| format!("Failed to read pattern file: {path:?}"))?;
+    Ok(text
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect())
+}
+
+pub(crate) fn build_regex_set(patterns: &[String], ignore_case: bool) -> Result<RegexSet, regex::Error> {
+    RegexSetBuilder::new(patterns).case_insensitive(ignore_case).build()
+}
+
+fn expand_patterns(patterns: &[String]) -> Vec<String> {
+    let mut expanded: Vec<String> = Vec::new();
+    for pat in patterns {
+        expanded.push(pat.clone());
+        let looks_like_dir = !pat.contains('*') && !pat.ends_with('/') && !pat.ends_with('\\');
+        if looks_like_dir {
+            expanded.push(format!("{}/**", pat));
+            expanded.push(format!("**/{}/**", pat.trim_start_matches("./")));
+        }
+    }
+    expanded
+}
+
+fn compile_globs(patterns: impl IntoIterator<Item = String>, what: &str, ignore_case: bool) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pat in patterns {
+        let glob = GlobBuilder::new(&pat)
+            .case_insensitive(ignore_case)
+            .literal_separator(true)
+            .build()
+            .with_context(|| format!("Invalid {what} pattern: {pat}"))?;
+        builder.add(glob);
+    }
+
+    Ok(builder.build()?)
+}
+
+/// Which entries of the tree are indexed.
+#[derive(Clone)]
+pub(crate) struct WalkOptions {
+    pub(crate) excludes: GlobSet,
+    /// Directories that are not entered at all (see [`build_prune_set`]).
+    pub(crate) prune: GlobSet,
+    /// Excluded files and directories (`--exclude-regex`), searched anywhere in the path.
+    pub(crate) exclude_regex: RegexSet,
+    /// With `--include`, only matching files are indexed, whether excluded or not. Directories
+    /// are still entered unless excluded, since files below them may match.
+    pub(crate) includes: Option<GlobSet>,
+    /// Like `includes` (`--include-regex`); a file matching either is indexed.
+    pub(crate) include_regex: Option<RegexSet>,
+    pub(crate) follow_symlinks: bool,
+    /// Honor `.gitignore` files and `.git/info/exclude` inside git repositories, as git does
+    /// (`--respect-gitignore`). The user's global excludes file is not read, so the result does
+    /// not depend on who runs the scan.
+    pub(crate) gitignore: bool,
+    /// Honor [`FHIGNORE`] files anywhere in the tree (unless `--no-fhignore`).
+    pub(crate) fhignore: bool,
+    /// Deepest level walked (`--max-depth`); files directly in the root are at depth 1.
+    pub(crate) max_depth: Option<usize>,
+    /// Skip directories whose device differs from the root's (`--one-file-system`).
+    pub(crate) one_file_system: bool,
+    /// Index dotfiles and enter dot-directories (`--no-hidden` disables it).
+    pub(crate) hidden: bool,
+    /// Only index these files and only enter their directories (`--git-tracked`).
+    pub(crate) git_tracked: Option<Arc<GitTracked>>,
+    /// Skip cache directories tagged with [`CACHEDIR_TAG`] (unless `--no-cachedir-tag`).
+    pub(crate) cachedir_tag: bool,
+    pub(crate) special_files: SpecialFiles,
+}
+
+/// Policy for files that are neither regular files, directories nor links (`--special-files`).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub(crate) enum SpecialFiles {
+    /// Skip them with a warning
+    #[default]
+    Warn,
+    /// Skip them silently
+    Skip,
+    /// Record them as special entries (their type, not their contents)
+    Record,
+}
+
+/// Type of a special file: `fifo`, `socket`, `block` or `char` (device); `None` for regular
+/// files, directories and links.
+#[cfg(unix)]
+pub(crate) fn special_type(ft: &fs::FileType) -> Option<&'static str> {
+    use std::os::unix::fs::FileTypeExt;
+    if ft.is_fifo() {
+        Some("fifo")
+    } else if ft.is_socket() {
+        Some("socket")
+    } else if ft.is_block_device() {
+        Some("block")
+    } else if ft.is_char_device() {
+        Some("char")
+    } else {
+        None
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn special_type(_ft: &fs::FileType) -> Option<&'static str> {
+    None
+}
+
+/// Files tracked by git below the root and their ancestor directories, relative to the root.
+pub(crate) struct GitTracked {
+    files: HashSet<String>,
+    dirs: HashSet<String>,
+}
+
+/// Runs `git ls-files` in `root`, which lists the files in the index below it (relative to it,
+/// with `/` separators), whether they are modified or not.
+pub(crate) fn git_tracked_files(root: &Path) -> Result<GitTracked> {
+    let output = std::process::Command::new("git")
+        .arg("-C")
+        .arg(root)
+        .args(["ls-files", "-z"])
+        .output()
+        .context("Failed to run `git ls-files` (is git installed?)")?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "`git ls-files` failed in {root:?}: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    let files: HashSet<String> = output
+        .stdout
+        .split(|&b| b == 0)
+        .filter(|path| !path.is_empty())
+        .map(|path| String::from_utf8_lossy(path).into_owned())
+        .collect();
+    let dirs = files.iter().flat_map(|f| ancestor_dirs(f)).map(str::to_string).collect();
+    Ok(GitTracked { files, dirs })
+}
+
+/// Name of the per-directory ignore files (gitignore syntax) picked up by every scan.
+const FHIGNORE: &str = ".fhignore";
+
+/// File marking a cache directory, per the Cache Directory Tagging Specification
+/// (<https://bford.info/cachedir/>), if it starts with [`CACHEDIR_SIGNATURE`].
+const CACHEDIR_TAG: &str = "CACHEDIR.TAG";
+
+const CACHEDIR_SIGNATURE: &[u8] = b"Signature: 8a477f597d28d172789f06886806bc55";
+
+/// Whether `dir` contains a valid [`CACHEDIR_TAG`].
+fn is_cache_dir(dir: &Path) -> bool {
+    let mut head = [0u8; CACHEDIR_SIGNATURE.len()];
+    File::open(dir.join(CACHEDIR_TAG)).and_then(|mut f| f.read_exact(&mut head)).is_ok() && head == CACHEDIR_SIGNATURE
+}
+
+impl WalkOptions {
+    fn indexes_file(&self, rel: &str) -> bool {
+        if self.git_tracked.as_ref().is_some_and(|git| !git.files.contains(rel)) {
+            return false;
+        }
+        if self.includes.is_none() && self.include_regex.is_none() {
+            return !self.excludes.is_match(rel) && !self.exclude_regex.is_match(rel);
+        }
+        self.includes.as_ref().is_some_and(|g| g.is_match(rel))
+            || self.include_regex.as_ref().is_some_and(|r| r.is_match(rel))
+    }
+}
+
+/// Modification time range of the files hashed by this run (`--newer-than`, `--older-than`).
+pub(crate) struct TimeWindow {
+    pub(crate) newer_than: Option<SystemTime>,
+    pub(crate) older_than: Option<SystemTime>,
+}
+
+impl TimeWindow {
+    fn contains(&self, mtime: SystemTime) -> bool {
+        self.newer_than.is_none_or(|t| mtime > t) && self.older_than.is_none_or(|t| mtime < t)
+    }
+
+    /// Splits `files` into those to hash and the previous entries of the others, which are
+    /// kept as they were so they are not reported as deleted. Files outside the window that
+    /// are not in the previous state are left out; files whose time cannot be read are hashed
+    /// (and fail there).
+    pub(crate) fn split(
+        &self,
+        root: &Path,
+        files: &[PathBuf],
+        old: &HashMap<String, Entry>,
+        paths: PathForm,
+    ) -> (Vec<PathBuf>, Vec<Entry>) {
+        if self.newer_than.is_none() && self.older_than.is_none() {
+            return (files.to_vec(), Vec::new());
+        }
+        let mut to_hash = Vec::new();
+        let mut carried = Vec::new();
+        for path in files {
+            match fs::metadata(path).and_then(|m| m.modified()) {
+                Ok(mtime) if !self.contains(mtime) => {
+                    if let Some(e) = old.get(paths.apply(&path_to_rel_unix(root, path)).as_ref()) {
+                        carried.push(e.clone());
+                    }
+                }
+                _ => to_hash.push(path.clone()),
+            }
+        }
+        (to_hash, carried)
+    }
+}
+
+/// Decides which directories are entered with `--follow-symlinks`, so link farms are neither
+/// walked in circles nor indexed several times: links to directories inside the indexed
+/// directories are not followed (their contents are indexed under their real path), and any
+/// other directory is only entered once, through the first path that reaches it.
+struct SymlinkGuard {
+    root: PathBuf,
+    sources: Vec<PathBuf>,
+    /// Device and inode of the directories entered so far, with their path below the root.
+    visited: Mutex<HashMap<(u64, u64), String>>,
+}
+
+impl SymlinkGuard {
+    fn new(root: &Path, sources: &[PathBuf]) -> Self {
+        SymlinkGuard {
+            root: root.to_path_buf(),
+            sources: sources.to_vec(),
+            visited: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether to enter the directory `path` (`rel` below the root; `is_link` if it is a symlink).
+    fn admit(&self, rel: &str, path: &Path, is_link: bool) -> bool {
+        let target = if is_link {
+            let Ok(target) = fs::canonicalize(path) else {
+                return true;
+            };
+            if self.sources.iter().any(|source| target.starts_with(source)) {
+                let real = path_to_rel_unix(&self.root, &target);
+                let real = if real.is_empty() { "." } else { real.as_str() };
+                eprintln!("Warning: not following symlink {rel} -> {target:?}: its contents are indexed as {real}");
+                return false;
+            }
+            target
+        } else {
+            path.to_path_buf()
+        };
+        let Some(id) = file_id(&target) else {
+            return true;
+        };
+        let mut visited = self.visited.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(first) = visited.get(&id) {
+            eprintln!("Warning: not entering {rel} (resolves to {target:?}): already indexed as {first}");
+            return false;
+        }
+        visited.insert(id, rel.to_string());
+        true
+    }
+}
+
+/// The link and ancestor of a symlink loop reported by the walker.
+fn symlink_loop(err: &ignore::Error) -> Option<(&Path, &Path)> {
+    match err {
+        ignore::Error::Loop { ancestor, child } => Some((child, ancestor)),
+        ignore::Error::WithPath { err, .. } | ignore::Error::WithDepth { err, .. } => symlink_loop(err),
+        _ => None,
+    }
+}
+
+/// The directory entries are recorded relative to: the only one of `sources` (canonical paths),
+/// or the deepest common parent of several, which must not contain each other.
+pub(crate) fn common_root(sources: &[PathBuf]) -> Result<PathBuf> {
+    for (i, a) in sources.iter().enumerate() {
+        if let Some(b) = sources[i + 1..].iter().find(|b| a.starts_with(b) || b.starts_with(a)) {
+            return Err(anyhow!("Directories {a:?} and {b:?} cannot contain each other."));
+        }
+    }
+    let mut root = sources[0].clone();
+    while !sources.iter().all(|s| s.starts_with(&root)) {
+        if !root.pop() {
+            return Err(anyhow!("The directories to index have no common parent (they are on different drives)."));
+        }
+    }
+    Ok(root)
+}
+
+/// Files to hash and (non-excluded) directories found under the root, root excluded.
+pub(crate) struct Collected {
+    pub(crate) files: Vec<PathBuf>,
+    pub(crate) dirs: Vec<PathBuf>,
+    /// Symbolic links and junctions that were not followed.
+    pub(crate) links: Vec<PathBuf>,
+    /// Special files to record (`--special-files record`).
+    pub(crate) specials: Vec<PathBuf>,
+    /// Directories at `--max-depth` that have contents, which were not walked.
+    unwalked: Vec<PathBuf>,
+}
+
+/// Walks each of `sources` (directories below `root`, or `root` itself) and collects what
+/// `walk` selects, with paths relative to `root`.
+pub(crate) fn collect_files(root: &Path, sources: &[PathBuf], walk: &WalkOptions) -> Result<Collected> {
+    let (prune, prune_regex) = (walk.prune.clone(), walk.exclude_regex.clone());
+    let git_tracked = walk.git_tracked.clone();
+    let cachedir_tag = walk.cachedir_tag;
+    let links = walk.follow_symlinks.then(|| SymlinkGuard::new(root, sources));
+    let mut builder = WalkBuilder::new(&sources[0]);
+    for source in &sources[1..] {
+        builder.add(source);
+    }
+    let prune_root = root.to_path_buf();
+    if walk.fhignore {
+        builder.add_custom_ignore_filename(FHIGNORE);
+    }
+    builder
+        .standard_filters(false)
+        .git_ignore(walk.gitignore)
+        .git_exclude(walk.gitignore)
+        .parents(walk.gitignore)
+        .follow_links(walk.follow_symlinks)
+        .max_depth(walk.max_depth)
+        .same_file_system(walk.one_file_system)
+        .hidden(!walk.hidden)
+        .filter_entry(move |e| {
+            if e.depth() == 0 || !e.file_type().is_some_and(|ft| ft.is_dir()) {
+                return true;
+            }
+            let rel = path_to_rel_unix(&prune_root, e.path());
+            // Directories without tracked files (such as `.git` and build output) are skipped.
+            git_tracked.as_ref().is_none_or(|git| git.dirs.contains(&rel))
+                && !prune.is_match(&rel)
+                && !prune_regex.is_match(&rel)
+                && (!cachedir_tag || !is_cache_dir(e.path()))
+                && links.as_ref().is_none_or(|links| links.admit(&rel, e.path(), e.path_is_symlink()))
+        });
+    if walk.follow_symlinks {
+        // Which of several links to the same directory is followed must not vary between runs.
+        builder.sort_by_file_name(|a, b| a.cmp(b));
+    }
+    let walker = builder.build();
+    let mut files = Vec::new();
+    let mut dirs = Vec::new();
+    let mut links = Vec::new();
+    let mut specials = Vec::new();
+    let mut unwalked = Vec::new();
+
+    for entry_res in walker {
+        let entry = match entry_res {
+            Ok(e) => e,
+            Err(err) => {
+                match symlink_loop(&err) {
+                    Some((child, ancestor)) => eprintln!(
+                        "Warning: not following symlink {} -> {:?}: it points to one of its parent directories",
+                        path_to_rel_unix(root, child),
+                        fs::canonicalize(child).unwrap_or_else(|_| ancestor.to_path_buf())
+                    ),
+                    None => eprintln!("Warning: failed to read an entry: {err}"),
+                }
+                continue;
+            }
+        };
+
+        let Some(ft) = entry.file_type() else {
+            continue;
+        };
+        let rel = path_to_rel_unix(root, entry.path());
+
+        if ft.is_dir() {
+            if Some(entry.depth()) == walk.max_depth && fs::read_dir(entry.path()).is_ok_and(|mut d| d.next().is_some()) {
+                unwalked.push(entry.path().to_path_buf());
+            }
+            if entry.depth() > 0 {
+                dirs.push(entry.into_path());
+            }
+            continue;
+        }
+
+        // Only links that are not followed are seen as such; on Windows, junctions and other
+        // name surrogate reparse points are reported as symlinks too.
+        if ft.is_symlink() {
+            if walk.indexes_file(&rel) {
+                links.push(entry.into_path());
+            }
+            continue;
+        }
+
+        if !ft.is_file() {
+            if let Some(kind) = special_type(&ft) {
+                if walk.indexes_file(&rel) {
+                    match walk.special_files {
+                        SpecialFiles::Warn => eprintln!("Warning: skipping special file {rel} ({kind})"),
+                        SpecialFiles::Skip => {}
+                        SpecialFiles::Record => specials.push(entry.into_path()),
+                    }
+                }
+            }
+            continue;
+        }
+
+        if !walk.indexes_file(&rel) {
+            continue;
+        }
+
+        files.push(entry.into_path());
+    }
+
+    Ok(Collected { files, dirs, links, specials, unwalked })
+}
+
+/// Reads the `--files-from` list: one path per line, relative to `root` (or absolute), which
+/// must be inside one of `sources`. Returns the relative paths, without duplicates.
+pub(crate) fn read_file_list(path: &Path, root: &Path, sources: &[PathBuf]) -> Result<Vec<String>> {
+    let text = if is_stdio(path) {
+        io::read_to_string(io::stdin().lock()).context("Failed to read the file list from stdin")?
+    } else {
+        fs::read_to_string(path).with_context(|| format!("Failed to read file list: {path:?}"))?
+    };
+    let mut listed = BTreeSet::new();
+    for line in text.lines() {
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        if line.is_empty() {
+            continue;
+        }
+        let given = Path::new(line);
+        let rel = if given.is_absolute() {
+            given.strip_prefix(root).ok()
+        } else {
+            Some(given)
+        };
+        let mut parts = Vec::new();
+        for component in rel.into_iter().flat_map(Path::components) {
+            match component {
+                Component::Normal(part) => parts.push(part.to_string_lossy()),
+                Component::CurDir => {}
+                _ => return Err(anyhow!("Listed path {line:?} must not contain `..`.")),
+            }
+        }
+        if rel.is_none() || !sources.iter().any(|s| root.join(parts.join("/")).starts_with(s)) {
+            return Err(anyhow!("Listed path {line:?} is not inside the indexed directories."));
+        }
+        if !parts.is_empty() {
+            listed.insert(parts.join("/"));
+        }
+    }
+    Ok(listed.into_iter().collect())
+}
+
+/// The `--files-from` counterpart of [`collect_files`]: the listed paths that are files `walk`
+/// selects, and the previous entries of everything that was not listed, which are kept as
+/// they were. Listed paths that no longer exist are left out (so they are reported deleted).
+pub(crate) fn listed_files(
+    root: &Path,
+    listed: &[String],
+    old: &HashMap<String, Entry>,
+    walk: &WalkOptions,
+    paths: PathForm,
+) -> (Collected, Vec<Entry>) {
+    let mut files = Vec::new();
+    let mut links = Vec::new();
+    let mut specials = Vec::new();
+    let mut replaced: HashSet<String> = HashSet::new();
+    let mut parents: HashSet<String> = HashSet::new();
+    for rel in listed {
+        let path = root.join(rel);
+        let recorded = paths.apply(rel).into_owned();
+        let meta = if walk.follow_symlinks {
+            fs::metadata(&path)
+        } else {
+            fs::symlink_metadata(&path)
+        };
+        match meta {
+            Ok(m) if m.is_file() => {
+                replaced.insert(recorded.clone());
+                if walk.indexes_file(rel) {
+                    parents.extend(ancestor_dirs(&recorded).map(str::to_string));
+                    files.push(path);
+                }
+            }
+            Ok(m) if m.file_type().is_symlink() => {
+                replaced.insert(recorded.clone());
+                if walk.indexes_file(rel) {
+                    parents.extend(ancestor_dirs(&recorded).map(str::to_string));
+                    links.push(path);
+                }
+            }
+            Ok(m) if special_type(&m.file_type()).is_some() && walk.special_files == SpecialFiles::Record => {
+                replaced.insert(recorded.clone());
+                if walk.indexes_file(rel) {
+                    parents.extend(ancestor_dirs(&recorded).map(str::to_string));
+                    specials.push(path);
+                }
+            }
+            Ok(_) => eprintln!("Warning: skipping listed path {rel}: not a regular file"),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                replaced.insert(recorded.clone());
+            }
+            Err(err) => eprintln!("Warning: skipping listed path {rel}: {err}"),
+        }
+    }
+    // An empty directory entry is dropped once a listed file is inside it.
+    let unlisted = old
+        .values()
+        .filter(|e| !replaced.contains(e.rel_path.as_str()) && !parents.contains(e.rel_path.as_str()))
+        .cloned()
+        .collect();
+    let collected = Collected {
+        files,
+        dirs: Vec::new(),
+        links,
+        specials,
+        unwalked: Vec::new(),
+    };
+    (collected, unlisted)
+}
+
+/// Entries for the collected directories that have nothing indexed below them.
+pub(crate) fn empty_dir_entries(root: &Path, collected: &Collected) -> Vec<Entry> {
+    let dir_rels: Vec<String> = collected
+        .dirs
+        .iter()
+        .map(|d| path_to_rel_unix(root, d))
+        .collect();
+    let file_rels: Vec<String> = collected
+        .files
+        .iter()
+        .chain(&collected.links)
+        .chain(&collected.specials)
+        .map(|f| path_to_rel_unix(root, f))
+        .collect();
+
+    let unwalked_rels: Vec<String> = collected
+        .unwalked
+        .iter()
+        .map(|d| path_to_rel_unix(root, d))
+        .collect();
+
+    let mut non_empty: HashSet<&str> = unwalked_rels.iter().map(String::as_str).collect();
+    for rel in file_rels.iter().chain(dir_rels.iter()) {
+        non_empty.extend(ancestor_dirs(rel));
+    }
+
+    dir_rels
+        .iter()
+        .filter(|rel| !non_empty.contains(rel.as_str()))
+        .map(|rel| Entry {
+            rel_path: rel.clone(),
+            kind: EntryKind::Dir,
+            ..Default::default()
+        })
+        .collect()
+}
+
+/// Link entries for the collected links, with their target as stored in the link.
+pub(crate) fn link_entries(root: &Path, links: &[PathBuf]) -> Vec<Entry> {
+    links
+        .iter()
+        .filter_map(|path| {
+            let rel = path_to_rel_unix(root, path);
+            match fs::read_link(path) {
+                Ok(target) => Some(Entry {
+                    rel_path: rel,
+                    kind: EntryKind::Link,
+                    target: Some(target.to_string_lossy().into_owned()),
+                    ..Default::default()
+                }),
+                Err(err) => {
+                    eprintln!("Warning: failed to read link {rel}: {err}");
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+/// Unicode normalization form of the recorded paths (`--normalize-paths`).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub(crate) enum PathForm {
+    /// Composed characters, as most systems write them
+    #[default]
+    Nfc,
+    /// Decomposed characters, as macOS (HFS+) writes them
+    Nfd,
+    /// Record paths as they are on disk
+    None,
+}
+
+impl PathForm {
+    pub(crate) fn apply<'a>(self, rel: &'a str) -> std::borrow::Cow<'a, str> {
+        match self {
+            PathForm::Nfc if !is_nfc(rel) => rel.nfc().collect::<String>().into(),
+            PathForm::Nfd if !is_nfd(rel) => rel.nfd().collect::<String>().into(),
+            _ => rel.into(),
+        }
+    }
+
+    /// Re-keys entries recorded with another (or no) normalization.
+    pub(crate) fn apply_to_map(self, entries: HashMap<String, Entry>) -> HashMap<String, Entry> {
+        if self == PathForm::None || entries.keys().all(|rel| self.apply(rel) == rel.as_str()) {
+            return entries;
+        }
+        let (map, _) = normalize_entries(Path::new(""), entries.into_values().collect(), self);
+        map
+    }
+}
+
+/// Keys `entries` by their path normalized to `form`. Also returns the files on disk of the
+/// entries whose recorded path differs from their name on disk. Of several names with the same
+/// normalized form, only the first (in name order) is kept.
+pub(crate) fn normalize_entries(
+    root: &Path,
+    mut entries: Vec<Entry>,
+    form: PathForm,
+) -> (HashMap<String, Entry>, HashMap<String, PathBuf>) {
+    entries.sort_by(|a, b| a.rel_path.cmp(&b.rel_path));
+    let mut map = HashMap::with_capacity(entries.len());
+    let mut disk_paths = HashMap::new();
+    for mut e in entries {
+        let rel = form.apply(&e.rel_path).into_owned();
+        if let Some(first) = map.get(&rel).map(|f: &Entry| f.rel_path.clone()) {
+            eprintln!("Warning: skipping {}: it only differs from {first} in its Unicode normalization", e.rel_path);
+            continue;
+        }
+        if rel != e.rel_path {
+            disk_paths.insert(rel.clone(), root.join(&e.rel_path));
+        }
+        if let Some(ref mut first) = e.hardlink {
+            *first = form.apply(first).into_owned();
+        }
+        e.rel_path = rel.clone();
+        map.insert(rel, e);
+    }
+    (map, disk_paths)
+}
+
+/// `rel`, a path of the state below `root`, as it is named on disk: where a directory has no
+/// entry of that exact name, its entry whose name is the same once normalized (NFC) is used,
+/// as paths are recorded normalized (`--normalize-paths`).
+pub(crate) fn find_on_disk(root: &Path, rel: &str) -> PathBuf {
+    let mut path = root.to_path_buf();
+    for part in rel.split('/') {
+        let exact = path.join(part);
+        if fs::symlink_metadata(&exact).is_ok() {
+            path = exact;
+            continue;
+        }
+        let wanted: String = part.nfc().collect();
+        let found = fs::read_dir(&path).ok().and_then(|dir| {
+            dir.filter_map(|e| e.ok())
+                .map(|e| e.file_name())
+                .find(|name| name.to_str().is_some_and(|name| name.nfc().eq(wanted.chars())))
+        });
+        path = match found {
+            Some(name) => path.join(name),
+            None => exact,
+        };
+    }
+    path
+}
+
+/// Special entries for the collected special files: their type and permission bits.
+pub(crate) fn special_entries(root: &Path, specials: &[PathBuf]) -> Vec<Entry> {
+    specials
+        .iter()
+        .filter_map(|path| {
+            let rel = path_to_rel_unix(root, path);
+            match fs::symlink_metadata(path) {
+                Ok(meta) => Some(Entry {
+                    rel_path: rel,
+                    kind: EntryKind::Special,
+                    special: special_type(&meta.file_type()).map(str::to_string),
+                    mode: file_mode(&meta),
+                    ..Default::default()
+                }),
+                Err(err) => {
+                    eprintln!("Warning: failed to read metadata for {rel}: {err}");
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+/// The file on disk of the entry recorded as `rel` (see [`normalize_entries`]).
+pub(crate) fn disk_path(root: &Path, disk_paths: &HashMap<String, PathBuf>, rel: &str) -> PathBuf {
+    disk_paths.get(rel).cloned().unwrap_or_else(|| root.join(rel))
+}
+
+pub(crate) fn path_to_rel_unix(root: &Path, path: &Path) -> String {
+    let rel = path.strip_prefix(root).unwrap_or(path).to_string_lossy();
+    // `\` is a valid file name character on Unix, only a separator on Windows.
+    if cfg!(windows) {
+        rel.replace('\\', "/")
+    } else {
+        rel.into_owned()
+    }
+}
+
+/// All ancestor directories of a relative path, innermost first, excluding the root.
+pub(crate) fn ancestor_dirs(rel: &str) -> impl Iterator<Item = &str> {
+    let mut cur = rel;
+    std::iter::from_fn(move || {
+        let i = cur.rfind('/')?;
+        cur = &cur[..i];
+        Some(cur)
+    })
+}